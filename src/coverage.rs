@@ -0,0 +1,387 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use arrow::{array::Array, util::display::array_value_to_string};
+use influxdb_iox_client::connection::Connection;
+use structopt::StructOpt;
+
+use crate::{
+    error::StringifyError,
+    predicate::measurement,
+    query::{Query, QueryOutput, StorageRpc},
+    query_log::QueryLog,
+    sql::{table_name, Token},
+    time::{format_timestamp, parse_timestamp},
+    time_shift::{sql_time_range, TimeShift},
+    util::Connector,
+};
+
+pub type Result<T, E = String> = std::result::Result<T, E>;
+
+/// Check whether the target database has data where each logged query
+/// looks for it, warning about queries that will trivially return
+/// nothing, and suggest a `--time-shift` that covers the most queries.
+///
+/// The table (measurement) and time range of each query are compared
+/// with `min(time)` and `max(time)` of that table in the database.
+#[derive(Debug, StructOpt)]
+pub struct Coverage {
+    /// The database name to check
+    db: String,
+
+    /// The filename of the query log
+    filename: String,
+
+    #[structopt(flatten)]
+    time_shift: TimeShift,
+}
+
+impl Coverage {
+    pub async fn execute(&self, connector: &Connector) -> Result<()> {
+        let log = QueryLog::new_from_file(Path::new(&self.filename)).await?;
+        println!("Loaded query log with {} entries", log.queries.len());
+
+        let queries = log.queries.into_iter().map(|r| r.into_inner()).collect();
+        let queries: Vec<_> = self
+            .time_shift
+            .apply(queries)?
+            .into_iter()
+            .enumerate()
+            .map(|(i, query)| (i, self.db.clone(), query))
+            .collect();
+
+        let connection = connector.connect().await?;
+        check_coverage(&queries, &connection).await
+    }
+}
+
+/// The data a query selects: a table and a `[start, end)` time range
+#[derive(Debug)]
+struct Target {
+    table: String,
+    start: i64,
+    end: i64,
+}
+
+impl Target {
+    fn try_new(query: &Query) -> Option<Self> {
+        let (table, start, end) = match query {
+            Query::Sql(sql) => {
+                let (start, end) = sql_time_range(sql);
+                (table_name(sql)?, start, end)
+            }
+            Query::StorageRpc(StorageRpc::ReadFilter(request)) => {
+                let table = measurement(request.predicate.as_ref()?)?.to_string();
+                let range = request.range.as_ref();
+                (table, range.map(|r| r.start), range.map(|r| r.end))
+            }
+        };
+
+        Some(Self {
+            table,
+            start: start.unwrap_or(i64::MIN),
+            end: end.unwrap_or(i64::MAX),
+        })
+    }
+
+    /// Does this target select any time in `[min, max]` once shifted
+    /// by `shift` nanoseconds?
+    fn overlaps(&self, (min, max): (i64, i64), shift: i64) -> bool {
+        let start = if self.start == i64::MIN {
+            i64::MIN
+        } else {
+            self.start.saturating_add(shift)
+        };
+        let end = if self.end == i64::MAX {
+            i64::MAX
+        } else {
+            self.end.saturating_add(shift)
+        };
+        start <= max && end > min
+    }
+
+    fn describe_range(&self) -> String {
+        let bound = |t: i64, unbounded: i64| {
+            if t == unbounded {
+                "unbounded".to_string()
+            } else {
+                format_timestamp(t)
+            }
+        };
+        format!(
+            "[{}, {})",
+            bound(self.start, i64::MIN),
+            bound(self.end, i64::MAX)
+        )
+    }
+}
+
+/// The extent of each table of each database, or why it is not known
+type Extents = BTreeMap<(String, String), Result<(i64, i64)>>;
+
+/// Check that the data each of `queries` (with its position in the log)
+/// selects exists in the database it is paired with, printing a warning for each query that does not,
+/// and a suggested time shift
+pub async fn check_coverage(
+    queries: &[(usize, String, Query)],
+    connection: &Connection,
+) -> Result<()> {
+    let mut extents = Extents::new();
+    let mut targets = vec![];
+    let mut num_covered = 0;
+
+    for (i, database_name, query) in queries {
+        let target = match Target::try_new(query) {
+            Some(target) => target,
+            None => {
+                println!("query {}: can not determine the table it selects from", i);
+                continue;
+            }
+        };
+
+        let key = (database_name.clone(), target.table.clone());
+        if let Entry::Vacant(entry) = extents.entry(key.clone()) {
+            entry.insert(table_extent(&target.table, database_name, connection).await);
+        }
+
+        match &extents[&key] {
+            Ok(extent) if target.overlaps(*extent, 0) => {
+                num_covered += 1;
+                println!("query {}: ok", i);
+            }
+            Ok((min, max)) => println!(
+                "query {}: WARNING no data in {} for {}, which has data in [{}, {}]",
+                i,
+                target.table,
+                target.describe_range(),
+                format_timestamp(*min),
+                format_timestamp(*max)
+            ),
+            Err(e) => println!("query {}: WARNING no data in {}: {}", i, target.table, e),
+        }
+        targets.push((database_name.as_str(), target));
+    }
+
+    let databases: BTreeSet<_> = queries
+        .iter()
+        .map(|(_, database, _)| database.as_str())
+        .collect();
+    println!(
+        "{} of {} queries select data that exists in {}",
+        num_covered,
+        queries.len(),
+        databases.into_iter().collect::<Vec<_>>().join(", ")
+    );
+
+    if let Some((shift, covered)) = suggest_shift(&targets, &extents) {
+        if covered > num_covered {
+            println!(
+                "Suggest --time-shift {} ({}ns), which would cover {} queries",
+                describe_nanos(shift),
+                shift,
+                covered
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Return the `min(time)` and `max(time)` of `table`
+async fn table_extent(
+    table: &str,
+    database_name: &str,
+    connection: &Connection,
+) -> Result<(i64, i64)> {
+    let table = Token::QuotedIdent(table.to_string());
+    let query = Query::Sql(format!("SELECT min(time), max(time) FROM {}", table));
+    let (_, output) = query
+        .replay_with_output(database_name, connection.clone())
+        .await?;
+
+    let batch = match &output {
+        QueryOutput::Sql(batches) => batches.iter().find(|b| b.num_rows() > 0),
+        QueryOutput::StorageRpc(_) => None,
+    }
+    .ok_or("table is empty")?;
+
+    let column = |i: usize| -> Result<i64> {
+        let column = batch.column(i);
+        if column.is_null(0) {
+            return Err("table is empty".to_string());
+        }
+        parse_timestamp(&array_value_to_string(column, 0).context("Formatting time")?)
+    };
+    Ok((column(0)?, column(1)?))
+}
+
+/// Return the time shift that makes the most `targets` overlap the
+/// data in their table, and how many it covers. Candidate shifts align
+/// the start or end of a target with the start or end of its table
+fn suggest_shift(targets: &[(&str, Target)], extents: &Extents) -> Option<(i64, usize)> {
+    let extent = |(database, target): &(&str, Target)| {
+        extents
+            .get(&(database.to_string(), target.table.clone()))
+            .and_then(|e| e.as_ref().ok())
+    };
+
+    let mut candidates = vec![];
+    for database_target in targets {
+        let target = &database_target.1;
+        if let Some((min, max)) = extent(database_target) {
+            if target.start != i64::MIN {
+                candidates.push(min.saturating_sub(target.start));
+            }
+            if target.end != i64::MAX {
+                candidates.push(max.saturating_add(1).saturating_sub(target.end));
+            }
+        }
+    }
+
+    candidates
+        .into_iter()
+        .map(|shift| {
+            let covered = targets
+                .iter()
+                .filter(|t| matches!(extent(t), Some(e) if t.1.overlaps(*e, shift)))
+                .count();
+            (shift, covered)
+        })
+        // most covered, then the smallest shift
+        .max_by_key(|(shift, covered)| (*covered, std::cmp::Reverse(shift.unsigned_abs())))
+}
+
+/// Describe `nanos` in the largest whole unit understood by
+/// `--time-shift`
+fn describe_nanos(nanos: i64) -> String {
+    const UNITS: &[(&str, i64)] = &[
+        ("d", 24 * 3_600_000_000_000),
+        ("h", 3_600_000_000_000),
+        ("m", 60_000_000_000),
+        ("s", 1_000_000_000),
+        ("ms", 1_000_000),
+        ("us", 1_000),
+    ];
+    UNITS
+        .iter()
+        .find(|(_, unit)| nanos % unit == 0)
+        .map(|(name, unit)| format!("{}{}", nanos / unit, name))
+        .unwrap_or_else(|| nanos.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use generated_types::influxdata::platform::storage::{
+        node::{Comparison, Value},
+        Predicate, ReadFilterRequest, TimestampRange,
+    };
+
+    use super::*;
+    use crate::predicate::{tag_comparison, TAG_KEY_MEASUREMENT};
+
+    const HOUR: i64 = 3_600_000_000_000;
+
+    fn target(table: &str, start: i64, end: i64) -> Target {
+        Target {
+            table: table.to_string(),
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn targets_of_queries() {
+        let sql = Query::Sql("SELECT * FROM cpu WHERE time >= 10 AND time < 20".to_string());
+        let target = Target::try_new(&sql).unwrap();
+        assert_eq!(
+            (target.table.as_str(), target.start, target.end),
+            ("cpu", 10, 20)
+        );
+
+        let unbounded = Target::try_new(&Query::Sql("SELECT * FROM mem".to_string())).unwrap();
+        assert_eq!((unbounded.start, unbounded.end), (i64::MIN, i64::MAX));
+        assert_eq!(unbounded.describe_range(), "[unbounded, unbounded)");
+
+        let request = ReadFilterRequest {
+            range: Some(TimestampRange {
+                start: 0,
+                end: HOUR,
+            }),
+            predicate: Some(Predicate {
+                root: Some(tag_comparison(
+                    TAG_KEY_MEASUREMENT,
+                    Comparison::Equal,
+                    Value::StringValue("disk".to_string()),
+                )),
+            }),
+            ..Default::default()
+        };
+        let target = Target::try_new(&Query::StorageRpc(StorageRpc::ReadFilter(request))).unwrap();
+        assert_eq!(
+            (target.table.as_str(), target.start, target.end),
+            ("disk", 0, HOUR)
+        );
+        assert_eq!(
+            target.describe_range(),
+            "[1970-01-01T00:00:00Z, 1970-01-01T01:00:00Z)"
+        );
+
+        let no_measurement = ReadFilterRequest::default();
+        assert!(
+            Target::try_new(&Query::StorageRpc(StorageRpc::ReadFilter(no_measurement))).is_none()
+        );
+    }
+
+    #[test]
+    fn overlapping_extents() {
+        let cpu = target("cpu", 10, 20);
+        assert!(cpu.overlaps((0, 10), 0));
+        assert!(cpu.overlaps((19, 30), 0));
+        assert!(!cpu.overlaps((20, 30), 0));
+        assert!(!cpu.overlaps((0, 9), 0));
+        assert!(cpu.overlaps((20, 30), 5));
+        assert!(cpu.overlaps((0, 9), -5));
+
+        let unbounded = target("cpu", i64::MIN, i64::MAX);
+        assert!(unbounded.overlaps((0, 1), i64::MAX));
+    }
+
+    #[test]
+    fn suggested_shift_covers_most_queries() {
+        let day = 24 * HOUR;
+        let mut extents = Extents::new();
+        let key = |table: &str| ("db".to_string(), table.to_string());
+        extents.insert(key("cpu"), Ok((30 * day, 31 * day)));
+        extents.insert(key("mem"), Err("table is empty".to_string()));
+        // the same table in another database has no bearing
+        extents.insert(("other".to_string(), "cpu".to_string()), Ok((0, 1)));
+
+        // the smallest shift that moves the end of the query into the data
+        let after = ("db", target("cpu", 31 * day, 31 * day + HOUR));
+        assert_eq!(suggest_shift(&[after], &extents), Some((-HOUR + 1, 1)));
+
+        // captured a day after the data: a shift covers both cpu queries
+        let targets = vec![
+            ("db", target("cpu", 31 * day, 31 * day + HOUR)),
+            ("db", target("cpu", 32 * day - HOUR, 32 * day)),
+            ("db", target("mem", 0, HOUR)),
+        ];
+        let (shift, covered) = suggest_shift(&targets, &extents).unwrap();
+        assert_eq!(covered, 2);
+        assert!(targets[..2]
+            .iter()
+            .all(|(_, t)| t.overlaps((30 * day, 31 * day), shift)));
+
+        assert!(suggest_shift(&[("db", target("mem", 0, HOUR))], &extents).is_none());
+        assert!(suggest_shift(&[("none", target("cpu", 0, HOUR))], &extents).is_none());
+    }
+
+    #[test]
+    fn describe_nanos_in_largest_unit() {
+        assert_eq!(describe_nanos(-30 * 24 * HOUR), "-30d");
+        assert_eq!(describe_nanos(90 * 60_000_000_000), "90m");
+        assert_eq!(describe_nanos(1_500), "1500");
+        assert_eq!(describe_nanos(2_000), "2us");
+    }
+}
@@ -0,0 +1,156 @@
+use std::path::Path;
+
+use structopt::StructOpt;
+
+use crate::{
+    query_log::QueryLog,
+    util::Connector,
+    verify::{compare, row_order, RowOrder, Table},
+};
+
+pub type Result<T, E = String> = std::result::Result<T, E>;
+
+/// Run each logged query several times and report any query whose
+/// results differ between runs.
+///
+/// Results are normalized before comparison, so SQL queries without an
+/// `ORDER BY` may return their rows in any order.
+#[derive(Debug, StructOpt)]
+pub struct Determinism {
+    /// The database name to run the queries against
+    db: String,
+
+    /// The filename of the query log
+    filename: String,
+
+    /// The number of times to run each query
+    #[structopt(long, default_value = "5")]
+    runs: usize,
+}
+
+impl Determinism {
+    pub async fn execute(&self, connector: &Connector) -> Result<()> {
+        if self.runs < 2 {
+            return Err("--runs must be at least 2".to_string());
+        }
+
+        let log = QueryLog::new_from_file(Path::new(&self.filename)).await?;
+        println!("Loaded query log with {} entries", log.queries.len());
+
+        let connection = connector.connect().await?;
+        let mut num_nondeterministic = 0;
+        for (i, query) in log.queries.into_iter().map(|r| r.into_inner()).enumerate() {
+            let order = row_order(&query);
+
+            let mut tables = vec![];
+            for _ in 0..self.runs {
+                let (_, output) = query
+                    .clone()
+                    .replay_with_output(&self.db, connection.clone())
+                    .await?;
+                tables.push(Table::try_new(&output)?);
+            }
+
+            let hashes: Vec<u64> = tables.iter().map(|t| t.content_hash(&order)).collect();
+            let differing = differing_runs(&tables, &order);
+            if differing.is_empty() {
+                println!("query {}: deterministic {:016x}", i, hashes[0]);
+                continue;
+            }
+
+            num_nondeterministic += 1;
+            let hashes: Vec<_> = hashes.iter().map(|h| format!("{:016x}", h)).collect();
+            println!(
+                "query {}: NONDETERMINISTIC [{}] {}",
+                i,
+                hashes.join(", "),
+                query
+            );
+
+            // show how each differing run compares to the first
+            for run in differing {
+                println!("  run {} vs run 0:", run);
+                for difference in compare(&tables[0], &tables[run], &order, 0.0) {
+                    println!("    {}", difference);
+                }
+            }
+        }
+
+        if num_nondeterministic > 0 {
+            return Err(format!(
+                "{} queries returned different results across {} runs",
+                num_nondeterministic, self.runs
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The runs whose results differ from those of the first run
+fn differing_runs(tables: &[Table], order: &RowOrder) -> Vec<usize> {
+    let first = match tables.first() {
+        Some(first) => first.content_hash(order),
+        None => return vec![],
+    };
+    tables
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter(|(_, table)| table.content_hash(order) != first)
+        .map(|(run, _)| run)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::Cell;
+
+    fn table(rows: &[&[&str]]) -> Table {
+        Table {
+            columns: vec!["time".to_string(), "host".to_string()],
+            rows: rows
+                .iter()
+                .map(|row| row.iter().map(|v| Cell::Other(v.to_string())).collect())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn identical_runs_are_deterministic() {
+        let run = table(&[&["1", "a"], &["2", "b"]]);
+        let runs = vec![run.clone(), run.clone(), run];
+        assert!(differing_runs(&runs, &RowOrder::Ordered).is_empty());
+        assert!(differing_runs(&[], &RowOrder::Ordered).is_empty());
+    }
+
+    #[test]
+    fn row_order_only_matters_when_the_query_sorts() {
+        let runs = vec![
+            table(&[&["1", "a"], &["2", "b"]]),
+            table(&[&["2", "b"], &["1", "a"]]),
+            table(&[&["1", "a"], &["2", "b"]]),
+        ];
+        assert!(differing_runs(&runs, &RowOrder::Unordered).is_empty());
+        assert_eq!(differing_runs(&runs, &RowOrder::Ordered), vec![1]);
+
+        // ties in the sort columns may come back in any order
+        let by_time = RowOrder::SortedBy(vec!["time".to_string()]);
+        let ties = vec![
+            table(&[&["1", "a"], &["1", "b"]]),
+            table(&[&["1", "b"], &["1", "a"]]),
+        ];
+        assert!(differing_runs(&ties, &by_time).is_empty());
+    }
+
+    #[test]
+    fn different_values_are_nondeterministic() {
+        let runs = vec![
+            table(&[&["1", "a"]]),
+            table(&[&["1", "a"]]),
+            table(&[&["1", "c"]]),
+            table(&[]),
+        ];
+        assert_eq!(differing_runs(&runs, &RowOrder::Unordered), vec![2, 3]);
+    }
+}
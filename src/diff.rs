@@ -0,0 +1,382 @@
+use std::{path::Path, str::FromStr};
+
+use influxdb_iox_client::connection::Connection;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use structopt::StructOpt;
+
+use crate::{
+    query::{Query, QueryExecutionSummary, QueryExecutionSummaryBuilder, QueryOutput},
+    query_log::QueryLog,
+    replay::{measure, Iterations},
+    stats::{bootstrap_interval, mann_whitney_p_value, mean, mean_confidence_interval},
+    time_shift::TimeShift,
+    util::Connector,
+    verify::{row_order, Table},
+};
+
+pub type Result<T, E = String> = std::result::Result<T, E>;
+
+/// Replay each query against two servers (or two databases) and compare
+/// their results and latency.
+///
+/// The first side ("a") is the database `db` on the server given by
+/// `--host`. The second side ("b") defaults to the same server and
+/// database, and is changed with `--other-host` and / or `--other-db`.
+#[derive(Debug, StructOpt)]
+pub struct Diff {
+    /// The database name to replay the queries against
+    db: String,
+
+    /// The filename of the query log to replay
+    filename: String,
+
+    /// gRPC address of the second IOx server
+    #[structopt(long)]
+    other_host: Option<String>,
+
+    /// The database name to replay the queries against on the second side
+    #[structopt(long)]
+    other_db: Option<String>,
+
+    /// How to order the executions of the two sides of each query:
+    /// `sequential` runs all of a then all of b, `abab` alternates
+    /// between a and b, and `random` alternates in a random order for
+    /// each iteration. Interleaving reduces the effect of drift (caches,
+    /// background compactions, etc) on the comparison
+    #[structopt(long, default_value = "sequential")]
+    schedule: Schedule,
+
+    /// Seed for the random number generator used by `--schedule random`
+    /// and for bootstrap resampling
+    #[structopt(long, default_value = "0")]
+    seed: u64,
+
+    /// Latency changes are only reported as `faster` or `slower` when
+    /// the Mann-Whitney U test p-value is below this level
+    #[structopt(long, default_value = "0.05")]
+    significance: f64,
+
+    #[structopt(flatten)]
+    iterations: Iterations,
+
+    #[structopt(flatten)]
+    time_shift: TimeShift,
+}
+
+/// How to order the executions of the two sides being compared
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Schedule {
+    Sequential,
+    Alternate,
+    Random,
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sequential" => Ok(Self::Sequential),
+            "abab" => Ok(Self::Alternate),
+            "random" => Ok(Self::Random),
+            _ => Err(format!(
+                "Unknown schedule '{}', expected 'sequential', 'abab' or 'random'",
+                s
+            )),
+        }
+    }
+}
+
+impl Schedule {
+    /// Should side a run first in the next pair of executions of an
+    /// interleaved schedule?
+    fn a_first(self, rng: &mut impl Rng) -> bool {
+        self != Self::Random || rng.gen()
+    }
+}
+
+/// The results of running a query on one side of the comparison
+#[derive(Debug)]
+struct Side {
+    summary: QueryExecutionSummary,
+    /// The output of the first execution
+    output: QueryOutput,
+}
+
+impl Diff {
+    pub async fn execute(&self, connector: &Connector) -> Result<()> {
+        let other_db = self.other_db.as_ref().unwrap_or(&self.db);
+        let log = QueryLog::new_from_file(Path::new(&self.filename)).await?;
+        println!("Loaded query log with {} entries", log.queries.len());
+
+        let connection = connector.connect().await?;
+        let other_connection = match &self.other_host {
+            Some(other_host) => Connector::new(other_host).connect().await?,
+            None => connection.clone(),
+        };
+
+        let mut rng = StdRng::seed_from_u64(self.seed);
+
+        println!(
+            "query,fingerprint,a_rows,b_rows,a_frames,b_frames,a_hash,b_hash,a_mean_ms,b_mean_ms,ratio,diff_mean_ms,diff_ci_low_ms,diff_ci_high_ms,status,ratio_ci_low,ratio_ci_high,p_value,a_outliers,b_outliers,latency"
+        );
+        let mut num_mismatches = 0;
+        let (mut num_faster, mut num_slower) = (0, 0);
+        let mut ratios = vec![];
+        let queries = log.queries.into_iter().map(|r| r.into_inner()).collect();
+        for (i, query) in self.time_shift.apply(queries)?.into_iter().enumerate() {
+            let (a, b) = match self.schedule {
+                Schedule::Sequential => (
+                    measure_side(&query, &self.db, &connection, &self.iterations).await?,
+                    measure_side(&query, other_db, &other_connection, &self.iterations).await?,
+                ),
+                Schedule::Alternate | Schedule::Random => {
+                    measure_interleaved(
+                        &query,
+                        (&self.db, &connection),
+                        (other_db, &other_connection),
+                        &self.iterations,
+                        || self.schedule.a_first(&mut rng),
+                    )
+                    .await?
+                }
+            };
+
+            let order = row_order(&query);
+            let a_hash = Table::try_new(&a.output)?.content_hash(&order);
+            let b_hash = Table::try_new(&b.output)?.content_hash(&order);
+
+            let matches = a.output.num_rows() == b.output.num_rows()
+                && a.output.num_frames() == b.output.num_frames()
+                && a_hash == b_hash;
+            if !matches {
+                num_mismatches += 1;
+            }
+
+            let (a_mean, b_mean) = (a.summary.mean_duration(), b.summary.mean_duration());
+            let ratio = b_mean.as_secs_f64() / a_mean.as_secs_f64();
+            ratios.push(ratio);
+
+            let (a_samples, b_samples) = (a.summary.samples_ms(), b.summary.samples_ms());
+            let (ratio_ci_low, ratio_ci_high) = format_interval(bootstrap_interval(
+                &a_samples,
+                &b_samples,
+                |a, b| mean(b) / mean(a),
+                &mut rng,
+            ));
+
+            // the difference b - a in mean latency. Interleaved executions
+            // run in pairs under the same conditions, so the interval comes
+            // from the paired differences. Sequential executions are not
+            // paired, so it comes from resampling each side independently
+            let (diff_mean, diff_interval) = match self.schedule {
+                Schedule::Sequential => (
+                    mean(&b_samples) - mean(&a_samples),
+                    bootstrap_interval(&a_samples, &b_samples, |a, b| mean(b) - mean(a), &mut rng),
+                ),
+                Schedule::Alternate | Schedule::Random => {
+                    let differences: Vec<f64> = a_samples
+                        .iter()
+                        .zip(&b_samples)
+                        .map(|(a, b)| b - a)
+                        .collect();
+                    (mean(&differences), mean_confidence_interval(&differences))
+                }
+            };
+            let (diff_ci_low, diff_ci_high) = format_interval(diff_interval);
+
+            // only report changes that are unlikely to be noise
+            let p_value = mann_whitney_p_value(&a_samples, &b_samples).unwrap_or(1.0);
+            let latency = if p_value >= self.significance {
+                "unchanged"
+            } else if b_mean > a_mean {
+                num_slower += 1;
+                "slower"
+            } else {
+                num_faster += 1;
+                "faster"
+            };
+
+            println!(
+                "{},{},{},{},{},{},{:016x},{:016x},{:.3},{:.3},{:.3},{:.3},{},{},{},{},{},{:.4},{},{},{}",
+                i,
+                query.fingerprint(),
+                a.output.num_rows(),
+                b.output.num_rows(),
+                a.output.num_frames(),
+                b.output.num_frames(),
+                a_hash,
+                b_hash,
+                a_mean.as_secs_f64() * 1000.0,
+                b_mean.as_secs_f64() * 1000.0,
+                ratio,
+                diff_mean,
+                diff_ci_low,
+                diff_ci_high,
+                if matches { "MATCH" } else { "MISMATCH" },
+                ratio_ci_low,
+                ratio_ci_high,
+                p_value,
+                a.summary.num_outliers(),
+                b.summary.num_outliers(),
+                latency
+            );
+        }
+
+        if let Some((geometric_mean, n)) = geometric_mean(&ratios) {
+            println!(
+                "Geometric mean latency ratio (b / a) over {} queries: {:.3}",
+                n, geometric_mean
+            );
+        }
+
+        println!(
+            "Significantly faster (p < {}): {}, significantly slower: {}",
+            self.significance, num_faster, num_slower
+        );
+
+        if num_mismatches > 0 {
+            return Err(format!(
+                "{} queries returned different results",
+                num_mismatches
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Run `query` repeatedly against one side
+async fn measure_side(
+    query: &Query,
+    database_name: &str,
+    connection: &Connection,
+    iterations: &Iterations,
+) -> Result<Side> {
+    let (summary, output) = measure(query, database_name, connection, iterations, |_| {}).await?;
+    Ok(Side { summary, output })
+}
+
+/// Run `query` against sides `a` and `b` in pairs of executions until
+/// `iterations` says to stop both. `a_first` is called before each pair
+/// to decide which side runs first.
+async fn measure_interleaved(
+    query: &Query,
+    a: (&str, &Connection),
+    b: (&str, &Connection),
+    iterations: &Iterations,
+    mut a_first: impl FnMut() -> bool,
+) -> Result<(Side, Side)> {
+    let mut summaries = (
+        QueryExecutionSummaryBuilder::new(),
+        QueryExecutionSummaryBuilder::new(),
+    );
+    let mut outputs = (None, None);
+
+    while outputs.0.is_none()
+        || iterations.should_continue(&summaries.0)
+        || iterations.should_continue(&summaries.1)
+    {
+        let a_first = a_first();
+        for run_a in [a_first, !a_first] {
+            let (database_name, connection) = if run_a { a } else { b };
+            let (execution, output) = query
+                .clone()
+                .replay_with_output(database_name, connection.clone())
+                .await?;
+
+            let (summary, first_output) = if run_a {
+                (&mut summaries.0, &mut outputs.0)
+            } else {
+                (&mut summaries.1, &mut outputs.1)
+            };
+            if first_output.is_none() {
+                *first_output = Some(output);
+            }
+            *summary = std::mem::take(summary).add(execution);
+        }
+    }
+
+    let side = |summary: QueryExecutionSummaryBuilder, output: Option<QueryOutput>| Side {
+        summary: summary.build(),
+        output: output.expect("query ran at least once"),
+    };
+    Ok((side(summaries.0, outputs.0), side(summaries.1, outputs.1)))
+}
+
+/// Format a confidence interval as two CSV fields, empty if there is
+/// no interval
+fn format_interval(interval: Option<(f64, f64)>) -> (String, String) {
+    interval
+        .map(|(low, high)| (format!("{:.3}", low), format!("{:.3}", high)))
+        .unwrap_or_default()
+}
+
+/// Return the geometric mean of the finite, positive `ratios` (the
+/// appropriate average for ratios) and how many there are, or `None` if
+/// there are none
+fn geometric_mean(ratios: &[f64]) -> Option<(f64, usize)> {
+    let logs: Vec<f64> = ratios
+        .iter()
+        .filter(|r| r.is_finite() && **r > 0.0)
+        .map(|r| r.ln())
+        .collect();
+    if logs.is_empty() {
+        return None;
+    }
+    Some((
+        (logs.iter().sum::<f64>() / logs.len() as f64).exp(),
+        logs.len(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn geometric_mean_of_ratios() {
+        let (mean, n) = geometric_mean(&[2.0, 0.5]).unwrap();
+        assert!((mean - 1.0).abs() < 1e-9);
+        assert_eq!(n, 2);
+
+        let (mean, n) = geometric_mean(&[1.0, 4.0, f64::INFINITY, f64::NAN, 0.0]).unwrap();
+        assert!((mean - 2.0).abs() < 1e-9);
+        assert_eq!(n, 2);
+
+        assert_eq!(geometric_mean(&[]), None);
+        assert_eq!(geometric_mean(&[f64::NAN]), None);
+    }
+
+    #[test]
+    fn schedules() {
+        assert_eq!("sequential".parse(), Ok(Schedule::Sequential));
+        assert_eq!("abab".parse(), Ok(Schedule::Alternate));
+        assert_eq!("random".parse(), Ok(Schedule::Random));
+        assert!("ba".parse::<Schedule>().is_err());
+
+        let diff = Diff::from_iter(["diff", "mydb", "log.json"]);
+        assert_eq!(diff.schedule, Schedule::Sequential);
+        let diff = Diff::from_iter(["diff", "mydb", "log.json", "--schedule", "random"]);
+        assert_eq!(diff.schedule, Schedule::Random);
+    }
+
+    #[test]
+    fn interleaved_order() {
+        let order = |schedule: Schedule, seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..100)
+                .map(|_| schedule.a_first(&mut rng))
+                .collect::<Vec<_>>()
+        };
+        assert!(order(Schedule::Alternate, 0)
+            .into_iter()
+            .all(|a_first| a_first));
+
+        // random orders are mixed, and reproducible from the seed
+        let random = order(Schedule::Random, 0);
+        let num_a_first = random.iter().filter(|a_first| **a_first).count();
+        assert!((20..80).contains(&num_a_first), "{}", num_a_first);
+        assert_eq!(random, order(Schedule::Random, 0));
+        assert_ne!(random, order(Schedule::Random, 1));
+    }
+}
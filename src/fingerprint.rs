@@ -0,0 +1,283 @@
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    predicate,
+    query::{
+        Query, QueryExecution, QueryExecutionSummary, QueryExecutionSummaryBuilder, StorageRpc,
+    },
+    sql::{is_keyword, tokenize, Token},
+};
+
+/// The "shape" of a `Query`: the query with literal values and time
+/// ranges removed, so that queries which differ only in their
+/// constants have the same fingerprint.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Fingerprint {
+    text: String,
+}
+
+impl Display for Fingerprint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.id())
+    }
+}
+
+impl Fingerprint {
+    pub fn new(query: &Query) -> Self {
+        let text = match query {
+            Query::Sql(sql) => format!("Sql({})", normalize_sql(sql)),
+            Query::StorageRpc(storagerpc) => format!(
+                "{}({})",
+                storagerpc.name(),
+                match storagerpc {
+                    StorageRpc::ReadFilter(request) => request
+                        .predicate
+                        .as_ref()
+                        .map(predicate::shape)
+                        .unwrap_or_else(|| "<empty>".to_string()),
+                }
+            ),
+        };
+        Self { text }
+    }
+
+    /// A short identifier for this fingerprint: the first 64 bits of
+    /// the SHA-256 of its text, so it is stable across builds and
+    /// toolchains (it is used in the names of saved result files)
+    pub fn id(&self) -> String {
+        Sha256::digest(self.text.as_bytes())[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// The normalized query text
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+/// Normalize `sql`, replacing all literals with `?`, collapsing lists
+/// of literals (e.g. `IN (1, 2, 3)`) into a single `?`, lower casing
+/// keywords and collapsing whitespace.
+fn normalize_sql(sql: &str) -> String {
+    let mut normalized: Vec<String> = vec![];
+
+    for token in tokenize(sql) {
+        let token = match token {
+            Token::Whitespace(_) => continue,
+            Token::StringLiteral(_) | Token::Number(_) => "?".to_string(),
+            Token::Word(w) if is_keyword(&w) => w.to_lowercase(),
+            token => token.to_string(),
+        };
+
+        // collapse `?, ?` into `?`
+        let n = normalized.len();
+        if token == "?" && n >= 2 && normalized[n - 1] == "," && normalized[n - 2] == "?" {
+            normalized.pop();
+            continue;
+        }
+        normalized.push(token);
+    }
+
+    normalized.join(" ")
+}
+
+/// Aggregated executions of all queries with the same `Fingerprint`
+#[derive(Debug, Default)]
+struct Shape {
+    /// The number of queries with this fingerprint, counting each
+    /// repetition of the same query
+    num_queries: usize,
+    executions: QueryExecutionSummaryBuilder,
+}
+
+/// Summarizes `QueryExecution`s per `Fingerprint`
+#[derive(Debug, Default)]
+pub struct FingerprintSummaries {
+    shapes: HashMap<Fingerprint, Shape>,
+}
+
+impl FingerprintSummaries {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Record that a query with `fingerprint` is being run
+    pub fn add_query(&mut self, fingerprint: &Fingerprint) {
+        self.shapes
+            .entry(fingerprint.clone())
+            .or_default()
+            .num_queries += 1;
+    }
+
+    /// Add an execution of a query with `fingerprint`
+    pub fn add_execution(&mut self, fingerprint: &Fingerprint, execution: QueryExecution) {
+        let shape = self.shapes.entry(fingerprint.clone()).or_default();
+        shape.executions = std::mem::take(&mut shape.executions).add(execution);
+    }
+
+    /// Print the `top` fingerprints with the longest mean duration
+    pub fn print_top(self, top: usize) {
+        let mut shapes: Vec<_> = self
+            .shapes
+            .into_iter()
+            .filter(|(_, shape)| shape.executions.count() > 0)
+            .map(|(fingerprint, shape)| (fingerprint, shape.num_queries, shape.executions.build()))
+            .collect();
+        shapes.sort_by_key(|(_, _, summary)| std::cmp::Reverse(summary.mean_duration()));
+
+        println!(
+            "Top {} of {} query fingerprints by mean duration",
+            top.min(shapes.len()),
+            shapes.len()
+        );
+        println!(
+            "fingerprint,queries,mean_duration_ms,{},shape",
+            QueryExecutionSummary::header()
+        );
+        for (fingerprint, num_queries, summary) in shapes.into_iter().take(top) {
+            println!(
+                "{},{},{},{},{}",
+                fingerprint,
+                num_queries,
+                summary.mean_duration().as_millis(),
+                summary,
+                fingerprint.text()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use generated_types::influxdata::platform::storage::{
+        node::{Comparison, Logical, Type, Value},
+        Node, Predicate, ReadFilterRequest, TimestampRange,
+    };
+
+    use super::*;
+
+    fn sql(sql: &str) -> Fingerprint {
+        Fingerprint::new(&Query::Sql(sql.to_string()))
+    }
+
+    fn node(node_type: Type, value: Value, children: Vec<Node>) -> Node {
+        Node {
+            node_type: node_type as i32,
+            children,
+            value: Some(value),
+        }
+    }
+
+    /// A read_filter of `[start, end)` with the predicate
+    /// `host = <host> AND usage > <usage>`
+    fn read_filter(start: i64, end: i64, host: &str, usage: f64) -> Fingerprint {
+        let comparison = |key: &str, comparison: Comparison, value: Value| {
+            node(
+                Type::ComparisonExpression,
+                Value::Comparison(comparison as i32),
+                vec![
+                    node(Type::TagRef, Value::TagRefValue(key.into()), vec![]),
+                    node(Type::Literal, value, vec![]),
+                ],
+            )
+        };
+        let root = node(
+            Type::LogicalExpression,
+            Value::Logical(Logical::And as i32),
+            vec![
+                comparison("host", Comparison::Equal, Value::StringValue(host.into())),
+                comparison("usage", Comparison::Gt, Value::FloatValue(usage)),
+            ],
+        );
+        Fingerprint::new(&Query::StorageRpc(StorageRpc::ReadFilter(
+            ReadFilterRequest {
+                range: Some(TimestampRange { start, end }),
+                predicate: Some(Predicate { root: Some(root) }),
+                ..Default::default()
+            },
+        )))
+    }
+
+    #[test]
+    fn normalizes_sql() {
+        assert_eq!(
+            normalize_sql("SELECT  host,\n usage FROM cpu WHERE host = 'a' AND usage > 1.5"),
+            "select host , usage from cpu where host = ? and usage > ?"
+        );
+        assert_eq!(
+            normalize_sql("select * from cpu where host in ('a', 'b', 'c') limit 10"),
+            "select * from cpu where host in ( ? ) limit ?"
+        );
+        // identifiers keep their case, quoted or not
+        assert_eq!(
+            normalize_sql("SELECT \"Host\" FROM Cpu"),
+            "select \"Host\" from Cpu"
+        );
+    }
+
+    #[test]
+    fn storage_rpc_predicate_shape() {
+        assert_eq!(
+            read_filter(0, 100, "a", 1.5).text(),
+            "ReadFilter(host = ? AND usage > ?)"
+        );
+        assert_eq!(
+            Fingerprint::new(&Query::StorageRpc(StorageRpc::ReadFilter(
+                ReadFilterRequest::default()
+            )))
+            .text(),
+            "ReadFilter(<empty>)"
+        );
+    }
+
+    #[test]
+    fn ids_are_stable() {
+        let fingerprint = sql("SELECT 1");
+        // the id names saved result files, so must not change
+        assert_eq!(fingerprint.text(), "Sql(select ?)");
+        assert_eq!(fingerprint.id(), "d02081c8f85d5776");
+        assert_eq!(fingerprint.to_string(), fingerprint.id());
+    }
+
+    #[test]
+    fn same_fingerprint_across_literals_and_times() {
+        assert_eq!(
+            sql("SELECT * FROM cpu WHERE time > '2021-01-01' AND host = 'a'"),
+            sql("select *  from cpu where time > '2022-06-30' and host = 'b'")
+        );
+        assert_eq!(
+            sql("SELECT * FROM cpu WHERE host IN ('a')"),
+            sql("SELECT * FROM cpu WHERE host IN ('a', 'b', 'c')")
+        );
+        assert_eq!(
+            read_filter(0, 100, "a", 1.5),
+            read_filter(200, 300, "b", 99.0)
+        );
+    }
+
+    #[test]
+    fn different_fingerprints_for_different_shapes() {
+        assert_ne!(
+            sql("SELECT * FROM cpu WHERE host = 'a'"),
+            sql("SELECT * FROM mem WHERE host = 'a'")
+        );
+        assert_ne!(
+            sql("SELECT * FROM cpu WHERE host = 'a'"),
+            sql("SELECT * FROM cpu WHERE host != 'a'")
+        );
+        assert_ne!(sql("SELECT * FROM cpu").id(), sql("SELECT * FROM mem").id());
+        assert_ne!(
+            read_filter(0, 100, "a", 1.5),
+            Fingerprint::new(&Query::StorageRpc(StorageRpc::ReadFilter(
+                ReadFilterRequest::default()
+            )))
+        );
+    }
+}
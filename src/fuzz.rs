@@ -0,0 +1,593 @@
+use std::{
+    fmt::{Display, Formatter},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use generated_types::influxdata::platform::storage::{
+    node::{Comparison, Type, Value},
+    Node, Predicate, ReadFilterRequest, TimestampRange,
+};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use structopt::StructOpt;
+
+use crate::{
+    error::StringifyError,
+    predicate::{and, tag_comparison},
+    query::{Query, StorageRpc},
+    query_log::{QueryLog, QueryRow},
+    sql::{split_clauses, tokenize, Token},
+    util::Connector,
+};
+
+pub type Result<T, E = String> = std::result::Result<T, E>;
+
+/// Comparisons a mutated predicate may use
+const COMPARISONS: &[Comparison] = &[
+    Comparison::Equal,
+    Comparison::NotEqual,
+    Comparison::StartsWith,
+    Comparison::Regex,
+    Comparison::NotRegex,
+    Comparison::Lt,
+    Comparison::Lte,
+    Comparison::Gt,
+    Comparison::Gte,
+];
+
+/// Regexes that are invalid, empty or expensive to evaluate
+const REGEXES: &[&str] = &["", "(", "[", "\\", ".*.*.*.*.*", "^$", "(a|aa)*b", "\u{ff}"];
+
+/// Timestamps at and around the limits of the representable range
+const TIMESTAMPS: &[i64] = &[i64::MIN, i64::MIN + 1, -1, 0, 1, i64::MAX - 1, i64::MAX];
+
+/// SQL comparison operators a mutated query may use
+const SQL_OPERATORS: &[&str] = &["=", "!=", "<>", "<", "<=", ">", ">="];
+
+/// SQL numbers at the limits of their types
+const SQL_NUMBERS: &[&str] = &[
+    "0",
+    "-1",
+    "9223372036854775807",
+    "9223372036854775808",
+    "18446744073709551616",
+    "1e308",
+    "0.0000000000000001",
+];
+
+/// SQL strings that are empty or are timestamps at the limits of the
+/// representable range
+const SQL_STRINGS: &[&str] = &[
+    "",
+    "1677-09-21T00:12:43.145224192Z",
+    "2262-04-11T23:47:16.854775807Z",
+    "1970-01-01T00:00:00Z",
+    "not a timestamp",
+];
+
+/// A tag that should not exist in any database
+const UNKNOWN_TAG: &[u8] = b"query_log_replay_fuzz_unknown_tag";
+
+/// Text in an error that indicates the connection to the server was lost
+const CONNECTION_ERRORS: &[&str] = &[
+    "transport error",
+    "status: Unavailable",
+    "broken pipe",
+    "connection reset",
+    "h2 protocol error",
+    "error reading a body",
+];
+
+/// Text in an error that indicates a bug in the server rather than an
+/// invalid request
+const INTERNAL_ERRORS: &[&str] = &["status: Internal", "status: Unknown", "panic"];
+
+/// Replay randomly mutated versions of logged queries, looking for
+/// internal errors, crashes and pathologically slow queries (including
+/// those that do not finish at all).
+///
+/// Each query found is written to `output_dir` as a query log with a
+/// single entry, which can be replayed to reproduce the problem.
+#[derive(Debug, StructOpt)]
+pub struct Fuzz {
+    /// The database name to run the queries against
+    db: String,
+
+    /// The filename of the query log to take queries from
+    filename: String,
+
+    /// The directory in which to write reproducers
+    output_dir: String,
+
+    /// The number of mutations to run for each logged query
+    #[structopt(long, default_value = "10")]
+    mutations: usize,
+
+    /// Mutated queries that take longer than this many seconds are
+    /// reported as slow
+    #[structopt(long, default_value = "10")]
+    slow_secs: u64,
+
+    /// Mutated queries that have not finished after this many seconds
+    /// are abandoned and reported as timed out
+    #[structopt(long, default_value = "60")]
+    timeout_secs: u64,
+
+    /// Seed for the random number generator, to reproduce a fuzzing run
+    #[structopt(long, default_value = "0")]
+    seed: u64,
+}
+
+/// A problem found by running a mutated query
+#[derive(Debug)]
+enum Finding {
+    InternalError(String),
+    ConnectionDropped(String),
+    Slow(Duration),
+    Timeout(Duration),
+}
+
+impl Finding {
+    /// Classify the result of running a query, returning `None` if it
+    /// succeeded quickly or was rejected as invalid
+    fn classify<T>(result: &Result<T>, elapsed: Duration, slow: Duration) -> Option<Self> {
+        match result {
+            Ok(_) if elapsed > slow => Some(Self::Slow(elapsed)),
+            Ok(_) => None,
+            Err(e) if CONNECTION_ERRORS.iter().any(|m| e.contains(m)) => {
+                Some(Self::ConnectionDropped(e.clone()))
+            }
+            Err(e) if INTERNAL_ERRORS.iter().any(|m| e.contains(m)) => {
+                Some(Self::InternalError(e.clone()))
+            }
+            Err(_) => None,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::InternalError(_) => "internal_error",
+            Self::ConnectionDropped(_) => "connection_dropped",
+            Self::Slow(_) => "slow",
+            Self::Timeout(_) => "timeout",
+        }
+    }
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InternalError(e) => write!(f, "internal error: {}", e),
+            Self::ConnectionDropped(e) => write!(f, "connection dropped: {}", e),
+            Self::Slow(elapsed) => write!(f, "slow: took {:?}", elapsed),
+            Self::Timeout(timeout) => write!(f, "timeout: not finished after {:?}", timeout),
+        }
+    }
+}
+
+impl Fuzz {
+    pub async fn execute(&self, connector: &Connector) -> Result<()> {
+        let log = QueryLog::new_from_file(Path::new(&self.filename)).await?;
+        println!("Loaded query log with {} entries", log.queries.len());
+
+        let output_dir = Path::new(&self.output_dir);
+        std::fs::create_dir_all(output_dir)
+            .context(&format!("Creating {}", output_dir.display()))?;
+
+        let slow = Duration::from_secs(self.slow_secs);
+        let timeout = Duration::from_secs(self.timeout_secs);
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let mut connection = connector.connect().await?;
+        let mut num_mutations = 0;
+        let mut num_findings = 0;
+        for (i, row) in log.queries.iter().enumerate() {
+            for _ in 0..self.mutations {
+                let (mutation, query) = match mutate(row.query(), &mut rng) {
+                    Some(mutated) => mutated,
+                    None => continue,
+                };
+
+                num_mutations += 1;
+                let start = Instant::now();
+                let result = tokio::time::timeout(
+                    timeout,
+                    query
+                        .clone()
+                        .replay_with_output(&self.db, connection.clone()),
+                )
+                .await;
+                let finding = match result {
+                    Ok(result) => Finding::classify(&result, start.elapsed(), slow),
+                    Err(_) => Some(Finding::Timeout(timeout)),
+                };
+                let finding = match finding {
+                    Some(finding) => finding,
+                    None => continue,
+                };
+
+                println!("query {} ({}): {}", i, mutation, finding);
+                let path = output_dir.join(format!("{:04}_{}.json", num_findings, finding.kind()));
+                let reproducer = QueryLog {
+                    queries: vec![QueryRow::new(row.issue_time(), query)],
+                };
+                reproducer.write_to_file(&path)?;
+                println!("  reproducer written to {}", path.display());
+                num_findings += 1;
+
+                // an abandoned request may still be running on the old
+                // connection, so don't queue the next one behind it
+                if matches!(finding, Finding::ConnectionDropped(_) | Finding::Timeout(_)) {
+                    connection = connector.reconnect().await?;
+                }
+            }
+        }
+
+        println!(
+            "Ran {} mutations of {} queries, found {} problems",
+            num_mutations,
+            log.queries.len(),
+            num_findings
+        );
+        Ok(())
+    }
+}
+
+/// Return a randomly mutated copy of `query` and a description of the
+/// mutation, or `None` if the chosen mutation does not apply to it
+fn mutate(query: &Query, rng: &mut impl Rng) -> Option<(&'static str, Query)> {
+    match query {
+        Query::Sql(sql) => {
+            let (mutation, sql) = mutate_sql(sql, rng)?;
+            Some((mutation, Query::Sql(sql)))
+        }
+        Query::StorageRpc(StorageRpc::ReadFilter(request)) => {
+            let (mutation, request) = mutate_read_filter(request, rng)?;
+            Some((mutation, Query::StorageRpc(StorageRpc::ReadFilter(request))))
+        }
+    }
+}
+
+fn mutate_read_filter(
+    request: &ReadFilterRequest,
+    rng: &mut impl Rng,
+) -> Option<(&'static str, ReadFilterRequest)> {
+    let mut request = request.clone();
+    let mutation = match rng.gen_range(0..6) {
+        0 => {
+            let comparison = random_comparison(&mut request, rng)?;
+            comparison.value = Some(Value::Comparison(
+                *COMPARISONS.choose(rng).expect("not empty") as i32,
+            ));
+            "change operator"
+        }
+        1 => {
+            let comparison = random_comparison(&mut request, rng)?;
+            let regex = REGEXES.choose(rng).expect("not empty").to_string();
+            let op = *[Comparison::Regex, Comparison::NotRegex]
+                .choose(rng)
+                .expect("not empty");
+            comparison.value = Some(Value::Comparison(op as i32));
+            comparison.children.truncate(1);
+            comparison.children.push(Node {
+                node_type: Type::Literal as i32,
+                children: vec![],
+                value: Some(Value::RegexValue(regex)),
+            });
+            "replace regex"
+        }
+        2 => {
+            let range = request.range.as_mut()?;
+            range.end = range.start;
+            "empty time range"
+        }
+        3 => {
+            let range = request.range.as_mut()?;
+            std::mem::swap(&mut range.start, &mut range.end);
+            "inverted time range"
+        }
+        4 => {
+            request.range = Some(TimestampRange {
+                start: *TIMESTAMPS.choose(rng).expect("not empty"),
+                end: *TIMESTAMPS.choose(rng).expect("not empty"),
+            });
+            "extreme timestamps"
+        }
+        _ => {
+            let root = request.predicate.take().and_then(|p| p.root);
+            let comparison = *COMPARISONS.choose(rng).expect("not empty");
+            let unknown = tag_comparison(
+                UNKNOWN_TAG,
+                comparison,
+                Value::StringValue("value".to_string()),
+            );
+            let root = and(root.into_iter().chain(std::iter::once(unknown)).collect());
+            request.predicate = Some(Predicate { root });
+            "unknown tag"
+        }
+    };
+    Some((mutation, request))
+}
+
+/// Return a randomly chosen comparison in the predicate of `request`
+fn random_comparison<'a>(
+    request: &'a mut ReadFilterRequest,
+    rng: &mut impl Rng,
+) -> Option<&'a mut Node> {
+    let root = request.predicate.as_mut()?.root.as_mut()?;
+    let count = count_comparisons(root);
+    if count == 0 {
+        return None;
+    }
+    nth_comparison(root, &mut rng.gen_range(0..count))
+}
+
+fn count_comparisons(node: &Node) -> usize {
+    if node.node_type == Type::ComparisonExpression as i32 {
+        1
+    } else {
+        node.children.iter().map(count_comparisons).sum()
+    }
+}
+
+/// Return the `n`th comparison in `node`, counting depth first
+fn nth_comparison<'a>(node: &'a mut Node, n: &mut usize) -> Option<&'a mut Node> {
+    if node.node_type == Type::ComparisonExpression as i32 {
+        if *n == 0 {
+            return Some(node);
+        }
+        *n -= 1;
+        return None;
+    }
+    node.children
+        .iter_mut()
+        .find_map(|child| nth_comparison(child, n))
+}
+
+fn mutate_sql(sql: &str, rng: &mut impl Rng) -> Option<(&'static str, String)> {
+    if rng.gen_range(0..4) == 0 {
+        let mut clauses = split_clauses(sql);
+        if clauses.len() < 2 {
+            return None;
+        }
+        clauses.shuffle(rng);
+        return Some(("shuffle clauses", clauses.join(" ")));
+    }
+
+    let mut tokens = tokenize(sql);
+    let (mutation, candidates): (_, Vec<usize>) = match rng.gen_range(0..3) {
+        0 => (
+            "change operator",
+            positions(
+                &tokens,
+                |t| matches!(t, Token::Punct(p) if SQL_OPERATORS.contains(&p.as_str())),
+            ),
+        ),
+        1 => (
+            "extreme number",
+            positions(&tokens, |t| matches!(t, Token::Number(_))),
+        ),
+        _ => (
+            "extreme string",
+            positions(&tokens, |t| matches!(t, Token::StringLiteral(_))),
+        ),
+    };
+
+    let position = *candidates.choose(rng)?;
+    tokens[position] = match &tokens[position] {
+        Token::Punct(_) => Token::Punct(SQL_OPERATORS.choose(rng)?.to_string()),
+        Token::Number(_) => Token::Number(SQL_NUMBERS.choose(rng)?.to_string()),
+        _ => Token::StringLiteral(SQL_STRINGS.choose(rng)?.to_string()),
+    };
+
+    let sql = tokens.iter().map(|t| t.to_string()).collect();
+    Some((mutation, sql))
+}
+
+/// Return the positions of the tokens matching `predicate`
+fn positions(tokens: &[Token], predicate: impl Fn(&Token) -> bool) -> Vec<usize> {
+    tokens
+        .iter()
+        .enumerate()
+        .filter(|(_, t)| predicate(t))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::predicate::{NodeDisplay, TAG_KEY_MEASUREMENT};
+
+    fn request() -> ReadFilterRequest {
+        let string = |s: &str| Value::StringValue(s.to_string());
+        ReadFilterRequest {
+            range: Some(TimestampRange {
+                start: 100,
+                end: 200,
+            }),
+            predicate: Some(Predicate {
+                root: and(vec![
+                    tag_comparison(TAG_KEY_MEASUREMENT, Comparison::Equal, string("cpu")),
+                    tag_comparison(b"host", Comparison::Equal, string("a")),
+                ]),
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn range(request: &ReadFilterRequest) -> (i64, i64) {
+        let range = request.range.as_ref().unwrap();
+        (range.start, range.end)
+    }
+
+    /// The comparison operators and literals of the comparisons in `node`
+    fn comparisons(node: &Node) -> Vec<(i32, Option<Value>)> {
+        let count = count_comparisons(node);
+        let mut node = node.clone();
+        (0..count)
+            .map(|i| {
+                let comparison = nth_comparison(&mut node, &mut i.clone()).unwrap();
+                let op = match comparison.value {
+                    Some(Value::Comparison(op)) => op,
+                    _ => panic!("not a comparison: {:?}", comparison),
+                };
+                (op, comparison.children.get(1).and_then(|c| c.value.clone()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn nth_comparison_counts_depth_first() {
+        let mut root = request().predicate.unwrap().root.unwrap();
+        assert_eq!(count_comparisons(&root), 2);
+        let host = nth_comparison(&mut root, &mut 1).unwrap();
+        assert_eq!(NodeDisplay::new(host).to_string(), "host = 'a'");
+        assert!(nth_comparison(&mut root, &mut 2).is_none());
+    }
+
+    #[test]
+    fn read_filter_mutations() {
+        let original = request();
+        let original_root = original.predicate.as_ref().unwrap().root.as_ref().unwrap();
+        let original_comparisons = comparisons(original_root);
+
+        let mut seen = BTreeSet::new();
+        for seed in 0..200 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let (mutation, mutated) = mutate_read_filter(&original, &mut rng).unwrap();
+            seen.insert(mutation);
+
+            let root = mutated.predicate.as_ref().unwrap().root.as_ref().unwrap();
+            let mutated_comparisons = comparisons(root);
+            let changed: Vec<_> = original_comparisons
+                .iter()
+                .zip(&mutated_comparisons)
+                .filter(|(a, b)| a != b)
+                .map(|(_, b)| b.clone())
+                .collect();
+
+            match mutation {
+                "change operator" => {
+                    assert_eq!(range(&mutated), (100, 200));
+                    assert_eq!(mutated_comparisons.len(), 2);
+                    assert!(changed.len() <= 1);
+                    for (op, value) in &changed {
+                        assert!(COMPARISONS.iter().any(|c| *c as i32 == *op));
+                        assert!(matches!(value, Some(Value::StringValue(_))));
+                    }
+                }
+                "replace regex" => {
+                    assert_eq!(changed.len(), 1);
+                    let (op, value) = &changed[0];
+                    assert!(*op == Comparison::Regex as i32 || *op == Comparison::NotRegex as i32);
+                    match value {
+                        Some(Value::RegexValue(regex)) => {
+                            assert!(REGEXES.contains(&regex.as_str()))
+                        }
+                        other => panic!("expected a regex, got {:?}", other),
+                    }
+                }
+                "empty time range" => assert_eq!(range(&mutated), (100, 100)),
+                "inverted time range" => assert_eq!(range(&mutated), (200, 100)),
+                "extreme timestamps" => {
+                    let (start, end) = range(&mutated);
+                    assert!(TIMESTAMPS.contains(&start) && TIMESTAMPS.contains(&end));
+                    assert!(changed.is_empty());
+                }
+                "unknown tag" => {
+                    assert_eq!(mutated_comparisons.len(), 3);
+                    assert!(changed.is_empty());
+                    assert!(NodeDisplay::new(root).to_string().starts_with(
+                        "_measurement = 'cpu' AND host = 'a' AND \
+                                      query_log_replay_fuzz_unknown_tag "
+                    ));
+                }
+                other => panic!("unexpected mutation {}", other),
+            }
+        }
+        assert_eq!(seen.len(), 6, "{:?}", seen);
+    }
+
+    #[test]
+    fn read_filter_mutations_need_something_to_mutate() {
+        for seed in 0..50 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            match mutate_read_filter(&ReadFilterRequest::default(), &mut rng) {
+                None => {}
+                Some(("extreme timestamps", _)) => {}
+                Some(("unknown tag", mutated)) => {
+                    let root = mutated.predicate.unwrap().root.unwrap();
+                    assert_eq!(count_comparisons(&root), 1);
+                }
+                Some((mutation, _)) => panic!("{} applied to an empty request", mutation),
+            }
+        }
+    }
+
+    #[test]
+    fn sql_mutations() {
+        let sql = "SELECT host FROM cpu WHERE usage > 10 AND host = 'a'";
+        let with_operators = |first: &str, second: &str| {
+            format!(
+                "SELECT host FROM cpu WHERE usage {} 10 AND host {} 'a'",
+                first, second
+            )
+        };
+
+        let mut seen = BTreeSet::new();
+        for seed in 0..200 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let (mutation, mutated) = mutate_sql(sql, &mut rng).unwrap();
+            seen.insert(mutation);
+
+            match mutation {
+                "shuffle clauses" => {
+                    let mut expected = split_clauses(sql);
+                    let mut actual = split_clauses(&mutated);
+                    expected.sort();
+                    actual.sort();
+                    assert_eq!(actual, expected);
+                }
+                "change operator" => assert!(
+                    SQL_OPERATORS
+                        .iter()
+                        .any(|op| mutated == with_operators(op, "=")
+                            || mutated == with_operators(">", op)),
+                    "{}",
+                    mutated
+                ),
+                "extreme number" => assert!(
+                    SQL_NUMBERS
+                        .iter()
+                        .any(|n| mutated == sql.replace(" 10 ", &format!(" {} ", n))),
+                    "{}",
+                    mutated
+                ),
+                "extreme string" => assert!(
+                    SQL_STRINGS
+                        .iter()
+                        .any(|s| mutated == sql.replace("'a'", &format!("'{}'", s))),
+                    "{}",
+                    mutated
+                ),
+                other => panic!("unexpected mutation {}", other),
+            }
+        }
+        assert_eq!(seen.len(), 4, "{:?}", seen);
+    }
+
+    #[test]
+    fn sql_mutations_need_something_to_mutate() {
+        for seed in 0..50 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            assert!(mutate_sql("SELECT *", &mut rng).is_none());
+
+            let mut rng = StdRng::seed_from_u64(seed);
+            if let Some((mutation, _)) = mutate_sql("SELECT * FROM cpu", &mut rng) {
+                assert_eq!(mutation, "shuffle clauses");
+            }
+        }
+    }
+}
@@ -1,5 +1,3 @@
-pub use std::time::Duration;
-
 use structopt::StructOpt;
 
 use influxdb_iox_client::{connection::Connection, management::generated_types::ChunkStorage};
@@ -0,0 +1,273 @@
+use std::{path::Path, str::FromStr};
+
+use rand::{rngs::StdRng, SeedableRng};
+use structopt::StructOpt;
+
+use crate::{
+    error::StringifyError,
+    query::Query,
+    query_log::{QueryLog, QueryRow},
+    rewrite::RewriteRules,
+    time::{format_timestamp, parse_timestamp},
+};
+
+pub type Result<T, E = String> = std::result::Result<T, E>;
+
+/// Sample, split and merge saved query logs
+#[derive(Debug, StructOpt)]
+pub struct Log {
+    #[structopt(subcommand)]
+    command: LogCommand,
+}
+
+#[derive(Debug, StructOpt)]
+enum LogCommand {
+    Sample(Sample),
+    /// Write the earliest issued queries of a log to a new log
+    Head(HeadTail),
+    /// Write the most recently issued queries of a log to a new log
+    Tail(HeadTail),
+    Split(Split),
+    Merge(Merge),
+    Rewrite(Rewrite),
+}
+
+impl Log {
+    pub async fn execute(&self) -> Result<()> {
+        match &self.command {
+            LogCommand::Sample(s) => s.execute().await,
+            LogCommand::Head(h) => {
+                let log = QueryLog::new_from_file(Path::new(&h.input)).await?;
+                log.head(h.count)?.write_to_file(Path::new(&h.output))
+            }
+            LogCommand::Tail(t) => {
+                let log = QueryLog::new_from_file(Path::new(&t.input)).await?;
+                log.tail(t.count)?.write_to_file(Path::new(&t.output))
+            }
+            LogCommand::Split(s) => s.execute().await,
+            LogCommand::Merge(m) => m.execute().await,
+            LogCommand::Rewrite(r) => r.execute().await,
+        }
+    }
+}
+
+/// How to group queries for stratified sampling
+#[derive(Debug, Clone, Copy)]
+enum Stratum {
+    QueryType,
+    Fingerprint,
+}
+
+impl FromStr for Stratum {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "query-type" => Ok(Self::QueryType),
+            "fingerprint" => Ok(Self::Fingerprint),
+            _ => Err(format!(
+                "Unknown stratum '{}', expected 'query-type' or 'fingerprint'",
+                s
+            )),
+        }
+    }
+}
+
+impl Stratum {
+    fn key(&self, query: &Query) -> String {
+        match self {
+            Self::QueryType => query.query_type().to_string(),
+            Self::Fingerprint => query.fingerprint().text().to_string(),
+        }
+    }
+}
+
+/// Write a random sample of the queries in a log to a new log
+#[derive(Debug, StructOpt)]
+struct Sample {
+    /// The query log to sample
+    input: String,
+
+    /// The filename to write the sampled queries to
+    output: String,
+
+    /// The number of queries to sample
+    #[structopt(short = "n", long)]
+    count: Option<usize>,
+
+    /// The fraction of queries to sample, such as 0.1
+    #[structopt(long)]
+    fraction: Option<f64>,
+
+    /// Sample proportionally from each group of queries rather than
+    /// uniformly. One of `query-type` or `fingerprint`
+    #[structopt(long)]
+    stratify_by: Option<Stratum>,
+
+    /// Seed for the random number generator, so samples are reproducible
+    #[structopt(long, default_value = "0")]
+    seed: u64,
+}
+
+impl Sample {
+    async fn execute(&self) -> Result<()> {
+        let log = QueryLog::new_from_file(Path::new(&self.input)).await?;
+
+        let count = match (self.count, self.fraction) {
+            (Some(count), None) => count,
+            (None, Some(fraction)) => (log.queries.len() as f64 * fraction).round() as usize,
+            _ => return Err("Exactly one of --count or --fraction must be specified".into()),
+        };
+
+        println!(
+            "Sampling {} of {} queries with seed {}",
+            count,
+            log.queries.len(),
+            self.seed
+        );
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let sample = match self.stratify_by {
+            Some(stratum) => log.stratified_sample(count, |row| stratum.key(row.query()), &mut rng),
+            None => log.sample(count, &mut rng),
+        };
+
+        sample.write_to_file(Path::new(&self.output))
+    }
+}
+
+#[derive(Debug, StructOpt)]
+struct HeadTail {
+    /// The query log to read
+    input: String,
+
+    /// The filename to write the selected queries to
+    output: String,
+
+    /// The number of queries to keep
+    #[structopt(short = "n", long, default_value = "10")]
+    count: usize,
+}
+
+/// Split a query log into several logs by time window or database
+#[derive(Debug, StructOpt)]
+struct Split {
+    /// The query log to split
+    input: String,
+
+    /// The directory in which to write the split logs
+    output_dir: String,
+
+    /// Split into windows of this many seconds, by issue time
+    #[structopt(long)]
+    window_secs: Option<u64>,
+
+    /// Split by the database targeted by each Storage RPC request.
+    /// SQL queries do not record their database and are written to
+    /// `sql.json`
+    #[structopt(long)]
+    by_database: bool,
+}
+
+impl Split {
+    async fn execute(&self) -> Result<()> {
+        let log = QueryLog::new_from_file(Path::new(&self.input)).await?;
+
+        let logs = match (self.window_secs, self.by_database) {
+            (Some(window_secs), false) => {
+                let window_nanos = (window_secs as i64).saturating_mul(1_000_000_000).max(1);
+                log.split_by(|row| {
+                    let issue_time = parse_timestamp(row.issue_time())?;
+                    let window_start = issue_time.div_euclid(window_nanos) * window_nanos;
+                    // no `:` or `-`, so the names are valid file names everywhere
+                    Ok(format_timestamp(window_start).replace([':', '-'], ""))
+                })?
+            }
+            (None, true) => log.split_by(database_key)?,
+            _ => {
+                return Err(
+                    "Exactly one of --window-secs or --by-database must be specified".into(),
+                )
+            }
+        };
+
+        let output_dir = Path::new(&self.output_dir);
+        std::fs::create_dir_all(output_dir)
+            .context(&format!("Creating directory {:?}", output_dir))?;
+
+        println!("Splitting into {} logs", logs.len());
+        for (key, log) in logs {
+            log.write_to_file(&output_dir.join(format!("{}.json", key)))?;
+        }
+        Ok(())
+    }
+}
+
+/// Return the name of the database a query was sent to, if known
+fn database_key(row: &QueryRow) -> Result<String> {
+    match row.query() {
+        Query::Sql(_) => Ok("sql".to_string()),
+        Query::StorageRpc(storagerpc) => storagerpc.database_name(),
+    }
+}
+
+/// Merge several query logs into a single log, ordered by issue time
+#[derive(Debug, StructOpt)]
+struct Merge {
+    /// The filename to write the merged log to
+    output: String,
+
+    /// The query logs to merge
+    #[structopt(required = true)]
+    inputs: Vec<String>,
+}
+
+impl Merge {
+    async fn execute(&self) -> Result<()> {
+        let mut logs = vec![];
+        for input in &self.inputs {
+            logs.push(QueryLog::new_from_file(Path::new(input)).await?);
+        }
+
+        QueryLog::merge(logs)?.write_to_file(Path::new(&self.output))
+    }
+}
+
+/// Rewrite the SQL queries of a log with rules from a JSON file, such as
+/// renaming tables or replacing `now()` (see `RewriteRules`)
+#[derive(Debug, StructOpt)]
+struct Rewrite {
+    /// The query log to rewrite
+    input: String,
+
+    /// The filename to write the rewritten log to
+    output: String,
+
+    /// The JSON file of rewrite rules
+    rules: String,
+}
+
+impl Rewrite {
+    async fn execute(&self) -> Result<()> {
+        let rules = RewriteRules::new_from_file(Path::new(&self.rules))?;
+        let log = QueryLog::new_from_file(Path::new(&self.input)).await?;
+
+        let num_queries = log.queries.len();
+        let queries: Vec<_> = log
+            .queries
+            .into_iter()
+            .filter_map(|row| {
+                let issue_time = row.issue_time().to_string();
+                rules
+                    .rewrite(row.into_inner())
+                    .map(|query| QueryRow::new(issue_time, query))
+            })
+            .collect();
+        println!(
+            "Rewrote {} queries, skipping {}",
+            queries.len(),
+            num_queries - queries.len()
+        );
+
+        QueryLog { queries }.write_to_file(Path::new(&self.output))
+    }
+}
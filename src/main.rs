@@ -1,14 +1,38 @@
 use std::process::exit;
 
+use crate::util::Connector;
 use structopt::StructOpt;
 mod compact;
+mod coverage;
+mod determinism;
+mod diff;
 pub mod error;
+mod fingerprint;
+mod fuzz;
 mod load;
+mod log_ops;
+mod metamorphic;
+mod minimize;
+pub(crate) mod predicate;
 pub(crate) mod query;
 pub(crate) mod query_log;
+mod read_source;
+mod reduce_query;
 mod replay;
+mod results;
+mod rewrite;
+mod run;
 mod save;
+mod schema;
+mod scrub;
+mod show;
+pub(crate) mod sql;
+mod stats;
+pub(crate) mod time;
+mod time_shift;
+mod to_sql;
 mod util;
+mod verify;
 
 #[derive(Debug, StructOpt)]
 #[structopt(
@@ -33,6 +57,70 @@ Examples:
     # replay the queries in queries.json back against my_db
     query_log_replay --host http://localhost:8082 replay my_db queries.json
 
+    # benchmark a single hand-written query without a log file
+    query_log_replay run my_db --sql 'select count(*) from cpu' --target-cv 0.05
+    query_log_replay run my_db --read-filter request.json
+
+    # check queries.json parses and print what would be sent, without a server
+    query_log_replay replay my_db queries.json --dry-run
+
+    # run each query until the relative standard error of its mean latency is
+    # below 1%, for at most 30s
+    query_log_replay replay my_db queries.json --target-relative-error 0.01 --max-duration-secs 30
+
+    # replay Storage RPC requests into a database not named <org_id>_<bucket_id>
+    query_log_replay replay bench_copy queries.json --org-id 26f7e5a4b7be365b --bucket-id 917b97a92e883afc
+
+    # replay a log with many tenants, each into its own database
+    query_log_replay replay my_db queries.json --tenant-map tenants.json
+
+    # rewrite SQL queries (rename tables, fix now(), add LIMIT, ...) before replaying
+    query_log_replay replay my_db queries.json --rewrite-rules rules.json
+    query_log_replay log rewrite queries.json rewritten.json rules.json
+
+    # replay queries captured 30 days ago against data loaded since
+    query_log_replay replay my_db queries.json --time-shift 30d
+
+    # check my_db has data where the queries look, and suggest a time shift
+    query_log_replay coverage my_db queries.json
+
+    # check the tables and columns the queries reference exist in my_db
+    query_log_replay schema-check my_db queries.json
+
+    # save the results of each query, then check a later replay returns the same
+    query_log_replay replay my_db queries.json --save-results results/
+    query_log_replay replay my_db queries.json --verify results/
+
+    # compare results and latency of a release candidate against production
+    query_log_replay --host http://prod:8082 diff my_db queries.json --other-host http://rc:8082
+
+    # compare two databases on one server, alternating executions in random order
+    query_log_replay diff my_db queries.json --other-db my_db_copy --schedule random
+
+    # find queries that return different results when run repeatedly
+    query_log_replay determinism my_db queries.json --runs 10
+
+    # check storage rpc results against requests derived from them
+    query_log_replay metamorphic my_db queries.json
+
+    # replay mutated queries, writing any that fail or are slow to found/
+    query_log_replay fuzz my_db queries.json found/ --mutations 100
+
+    # find the fewest queries of queries.json that still cause a server error
+    query_log_replay minimize my_db queries.json minimal.json --error
+
+    # shrink query 7 of minimal.json while it still takes over 2 seconds
+    query_log_replay reduce-query my_db minimal.json 7 --slower-than-ms 2000
+
+    # print queries 3 through 5 of queries.json in full
+    query_log_replay show queries.json 3-5
+
+    # anonymize queries.json so it can be shared
+    query_log_replay scrub --key my_secret queries.json scrubbed.json
+
+    # write a reproducible sample of 100 queries, proportionally by query shape
+    query_log_replay log sample queries.json sample.json -n 100 --stratify-by fingerprint --seed 1
+
 "#
 )]
 struct Config {
@@ -56,6 +144,19 @@ enum Command {
     Replay(replay::Replay),
     LoadReadBuffer(load::LoadReadBuffer),
     FullyCompact(compact::FullyCompact),
+    Scrub(scrub::Scrub),
+    Log(log_ops::Log),
+    Show(show::Show),
+    ToSql(to_sql::ToSql),
+    Diff(diff::Diff),
+    Determinism(determinism::Determinism),
+    Metamorphic(metamorphic::Metamorphic),
+    Fuzz(fuzz::Fuzz),
+    Minimize(minimize::Minimize),
+    ReduceQuery(reduce_query::ReduceQuery),
+    Coverage(coverage::Coverage),
+    SchemaCheck(schema::SchemaCheck),
+    Run(run::Run),
 }
 
 #[tokio::main]
@@ -64,20 +165,7 @@ async fn main() {
 
     println!("InfluxDB IOx Query Replay Tool... online");
 
-    println!("Connecting to {}", config.host);
-    let connection = influxdb_iox_client::connection::Builder::default()
-        .build(&config.host)
-        .await
-        .expect("Can not connect");
-
-    let command_result = match config.command {
-        Command::Save(s) => s.execute(connection).await,
-        Command::Replay(r) => r.execute(connection).await,
-        Command::LoadReadBuffer(lrb) => lrb.execute(connection).await,
-        Command::FullyCompact(fc) => fc.execute(connection).await,
-    };
-
-    match command_result {
+    match run(config).await {
         Ok(_) => {
             println!("Success");
             exit(0)
@@ -88,3 +176,28 @@ async fn main() {
         }
     }
 }
+
+/// Run the specified command, only connecting to the server if needed
+async fn run(config: Config) -> Result<(), String> {
+    let connector = Connector::new(config.host);
+
+    match config.command {
+        Command::Save(s) => s.execute(connector.connect().await?).await,
+        Command::Replay(r) => r.execute(&connector).await,
+        Command::LoadReadBuffer(lrb) => lrb.execute(connector.connect().await?).await,
+        Command::FullyCompact(fc) => fc.execute(connector.connect().await?).await,
+        Command::Scrub(s) => s.execute().await,
+        Command::Log(l) => l.execute().await,
+        Command::Show(s) => s.execute().await,
+        Command::ToSql(t) => t.execute().await,
+        Command::Diff(d) => d.execute(&connector).await,
+        Command::Determinism(d) => d.execute(&connector).await,
+        Command::Metamorphic(m) => m.execute(&connector).await,
+        Command::Fuzz(f) => f.execute(&connector).await,
+        Command::Minimize(m) => m.execute(&connector).await,
+        Command::ReduceQuery(r) => r.execute(&connector).await,
+        Command::Coverage(c) => c.execute(&connector).await,
+        Command::SchemaCheck(s) => s.execute(&connector).await,
+        Command::Run(r) => r.execute(&connector).await,
+    }
+}
@@ -0,0 +1,364 @@
+use std::path::Path;
+
+use generated_types::influxdata::platform::storage::{
+    node::{Comparison, Type, Value},
+    Node, Predicate, ReadFilterRequest, TimestampRange,
+};
+use influxdb_iox_client::connection::Connection;
+use structopt::StructOpt;
+
+use crate::{
+    predicate::{
+        and, is_special_key, split_conjuncts, tag_comparison, NodeDisplay, TAG_KEY_MEASUREMENT,
+    },
+    query::{Query, StorageRpc},
+    query_log::QueryLog,
+    util::Connector,
+    verify::{compare, RowOrder, Table},
+};
+
+pub type Result<T, E = String> = std::result::Result<T, E>;
+
+/// Check the results of logged Storage RPC requests against the results
+/// of related requests derived from them.
+///
+/// For each `read_filter` request this:
+/// * splits the time range in two: the halves must return the whole
+/// * adds a predicate that is always true: the results must not change
+/// * negates each tag predicate: the original and negated requests must
+///   together return what the request without that predicate returns
+#[derive(Debug, StructOpt)]
+pub struct Metamorphic {
+    /// The database name to run the queries against
+    db: String,
+
+    /// The filename of the query log
+    filename: String,
+}
+
+/// An expected relation between requests: the points returned by
+/// `whole` must be exactly the union of the points returned by `parts`
+#[derive(Debug)]
+struct Invariant {
+    description: String,
+    whole: ReadFilterRequest,
+    parts: Vec<ReadFilterRequest>,
+}
+
+impl Metamorphic {
+    pub async fn execute(&self, connector: &Connector) -> Result<()> {
+        let log = QueryLog::new_from_file(Path::new(&self.filename)).await?;
+        println!("Loaded query log with {} entries", log.queries.len());
+
+        let connection = connector.connect().await?;
+        let mut num_checks = 0;
+        let mut num_failures = 0;
+        for (i, row) in log.queries.iter().enumerate() {
+            let request = match row.query() {
+                Query::StorageRpc(StorageRpc::ReadFilter(request)) => request,
+                Query::Sql(_) => continue,
+            };
+
+            for invariant in invariants(request) {
+                num_checks += 1;
+                match self.check(&invariant, &connection).await {
+                    Ok(differences) if differences.is_empty() => {
+                        println!("query {} {}: ok", i, invariant.description)
+                    }
+                    Ok(differences) => {
+                        num_failures += 1;
+                        println!("query {} {}: FAILED", i, invariant.description);
+                        print_requests(&invariant)?;
+                        for difference in differences {
+                            println!("    {}", difference);
+                        }
+                    }
+                    Err(e) => {
+                        num_failures += 1;
+                        println!("query {} {}: ERROR {}", i, invariant.description, e);
+                        print_requests(&invariant)?;
+                    }
+                }
+            }
+        }
+
+        println!("{} of {} checks failed", num_failures, num_checks);
+        if num_failures > 0 {
+            return Err(format!("{} metamorphic checks failed", num_failures));
+        }
+        Ok(())
+    }
+
+    /// Run the requests of `invariant`, returning the differences between
+    /// the whole and the union of the parts
+    async fn check(&self, invariant: &Invariant, connection: &Connection) -> Result<Vec<String>> {
+        let whole = self.run(&invariant.whole, connection).await?;
+
+        let mut union = Table {
+            columns: whole.columns.clone(),
+            rows: vec![],
+        };
+        for part in &invariant.parts {
+            union.rows.extend(self.run(part, connection).await?.rows);
+        }
+
+        Ok(compare(&whole, &union, &RowOrder::Unordered, 0.0))
+    }
+
+    async fn run(&self, request: &ReadFilterRequest, connection: &Connection) -> Result<Table> {
+        let query = Query::StorageRpc(StorageRpc::ReadFilter(request.clone()));
+        let (_, output) = query
+            .replay_with_output(&self.db, connection.clone())
+            .await?;
+        Table::try_new(&output)
+    }
+}
+
+fn print_requests(invariant: &Invariant) -> Result<()> {
+    let text = |request: &ReadFilterRequest| {
+        Query::StorageRpc(StorageRpc::ReadFilter(request.clone())).query_text()
+    };
+
+    println!("  whole: {}", text(&invariant.whole)?);
+    for part in &invariant.parts {
+        println!("  part:  {}", text(part)?);
+    }
+    Ok(())
+}
+
+/// Return the invariants that the results of `request` should satisfy
+fn invariants(request: &ReadFilterRequest) -> Vec<Invariant> {
+    let mut invariants = vec![];
+    invariants.extend(split_time_range(request));
+    invariants.push(add_tautology(request));
+    invariants.extend(negate_tag_predicates(request));
+    invariants
+}
+
+/// The two halves of the time range return the whole range
+fn split_time_range(request: &ReadFilterRequest) -> Option<Invariant> {
+    let range = request.range.as_ref()?;
+    if range.start == i64::MIN || range.end == i64::MAX {
+        return None;
+    }
+    let width = range.end.checked_sub(range.start).filter(|w| *w >= 2)?;
+    let mid = range.start + width / 2;
+
+    let with_range = |start, end| ReadFilterRequest {
+        range: Some(TimestampRange { start, end }),
+        ..request.clone()
+    };
+
+    Some(Invariant {
+        description: "split time range".to_string(),
+        whole: request.clone(),
+        parts: vec![with_range(range.start, mid), with_range(mid, range.end)],
+    })
+}
+
+/// Adding `_measurement =~ /.*/` does not change the results
+fn add_tautology(request: &ReadFilterRequest) -> Invariant {
+    let mut conjuncts: Vec<Node> = root(request).cloned().into_iter().collect();
+    conjuncts.push(tag_comparison(
+        TAG_KEY_MEASUREMENT,
+        Comparison::Regex,
+        Value::RegexValue(".*".to_string()),
+    ));
+
+    Invariant {
+        description: "add tautology".to_string(),
+        whole: request.clone(),
+        parts: vec![with_root(request, and(conjuncts))],
+    }
+}
+
+/// For each tag predicate `p` in the top level conjunction `rest AND p`,
+/// `rest AND p` and `rest AND NOT p` together return `rest`
+fn negate_tag_predicates(request: &ReadFilterRequest) -> Vec<Invariant> {
+    let mut conjuncts = vec![];
+    if let Some(root) = root(request) {
+        split_conjuncts(root, &mut conjuncts);
+    }
+
+    let mut invariants = vec![];
+    for (i, conjunct) in conjuncts.iter().enumerate() {
+        let negated = match negate(conjunct) {
+            Some(negated) => negated,
+            None => continue,
+        };
+
+        let rest: Vec<Node> = conjuncts
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| *j != i)
+            .map(|(_, node)| (*node).clone())
+            .collect();
+        let mut with_negated = rest.clone();
+        with_negated.push(negated);
+
+        invariants.push(Invariant {
+            description: format!("negate {}", NodeDisplay::new(conjunct)),
+            whole: with_root(request, and(rest)),
+            parts: vec![request.clone(), with_root(request, and(with_negated))],
+        });
+    }
+    invariants
+}
+
+/// Return the negation of `node` if it compares a tag with a non empty
+/// value
+fn negate(node: &Node) -> Option<Node> {
+    if node.node_type != Type::ComparisonExpression as i32 {
+        return None;
+    }
+
+    let negated = match node.value {
+        Some(Value::Comparison(c)) => match Comparison::from_i32(c)? {
+            Comparison::Equal => Comparison::NotEqual,
+            Comparison::NotEqual => Comparison::Equal,
+            Comparison::Regex => Comparison::NotRegex,
+            Comparison::NotRegex => Comparison::Regex,
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    match node.children.as_slice() {
+        [left, right] => match (&left.value, &right.value) {
+            (Some(Value::TagRefValue(key)), Some(Value::StringValue(value)))
+            | (Some(Value::TagRefValue(key)), Some(Value::RegexValue(value)))
+                if !is_special_key(key) && !value.is_empty() =>
+            {
+                Some(Node {
+                    value: Some(Value::Comparison(negated as i32)),
+                    ..node.clone()
+                })
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn root(request: &ReadFilterRequest) -> Option<&Node> {
+    request.predicate.as_ref().and_then(|p| p.root.as_ref())
+}
+
+fn with_root(request: &ReadFilterRequest, root: Option<Node>) -> ReadFilterRequest {
+    ReadFilterRequest {
+        predicate: root.map(|root| Predicate { root: Some(root) }),
+        ..request.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generated_types::influxdata::platform::storage::node::Logical;
+
+    fn request(root: Option<Node>, start: i64, end: i64) -> ReadFilterRequest {
+        ReadFilterRequest {
+            range: Some(TimestampRange { start, end }),
+            predicate: root.map(|root| Predicate { root: Some(root) }),
+            ..Default::default()
+        }
+    }
+
+    fn tag(key: &[u8], comparison: Comparison, value: &str) -> Node {
+        tag_comparison(key, comparison, Value::StringValue(value.to_string()))
+    }
+
+    fn predicate(request: &ReadFilterRequest) -> String {
+        root(request)
+            .map(|root| NodeDisplay::new(root).to_string())
+            .unwrap_or_default()
+    }
+
+    fn range(request: &ReadFilterRequest) -> (i64, i64) {
+        let range = request.range.as_ref().unwrap();
+        (range.start, range.end)
+    }
+
+    #[test]
+    fn split_time_range_halves() {
+        let invariant = split_time_range(&request(None, 100, 201)).unwrap();
+        assert_eq!(range(&invariant.whole), (100, 201));
+        let parts: Vec<_> = invariant.parts.iter().map(range).collect();
+        assert_eq!(parts, vec![(100, 150), (150, 201)]);
+
+        assert!(split_time_range(&request(None, 100, 101)).is_none());
+        assert!(split_time_range(&request(None, i64::MIN, 100)).is_none());
+        assert!(split_time_range(&request(None, 100, i64::MAX)).is_none());
+        assert!(split_time_range(&ReadFilterRequest::default()).is_none());
+    }
+
+    #[test]
+    fn add_tautology_to_predicate() {
+        let host = tag(b"host", Comparison::Equal, "a");
+        let invariant = add_tautology(&request(Some(host), 0, 10));
+        assert_eq!(predicate(&invariant.whole), "host = 'a'");
+        assert_eq!(invariant.parts.len(), 1);
+        assert_eq!(
+            predicate(&invariant.parts[0]),
+            "host = 'a' AND _measurement =~ /.*/"
+        );
+
+        let invariant = add_tautology(&request(None, 0, 10));
+        assert_eq!(predicate(&invariant.parts[0]), "_measurement =~ /.*/");
+    }
+
+    #[test]
+    fn negate_each_tag_predicate() {
+        let root = and(vec![
+            tag(b"host", Comparison::Equal, "a"),
+            tag(b"region", Comparison::NotEqual, "west"),
+            tag(TAG_KEY_MEASUREMENT, Comparison::Equal, "cpu"),
+            tag(b"empty", Comparison::Equal, ""),
+        ]);
+        let invariants = negate_tag_predicates(&request(root, 0, 10));
+        assert_eq!(invariants.len(), 2);
+
+        let host = &invariants[0];
+        assert_eq!(host.description, "negate host = 'a'");
+        assert_eq!(
+            predicate(&host.whole),
+            "region != 'west' AND _measurement = 'cpu' AND empty = ''"
+        );
+        assert_eq!(
+            predicate(&host.parts[1]),
+            "region != 'west' AND _measurement = 'cpu' AND empty = '' AND host != 'a'"
+        );
+
+        let region = &invariants[1];
+        assert_eq!(region.description, "negate region != 'west'");
+        assert_eq!(
+            predicate(&region.parts[1]),
+            "host = 'a' AND _measurement = 'cpu' AND empty = '' AND region = 'west'"
+        );
+    }
+
+    #[test]
+    fn negate_only_tag_comparisons() {
+        let regex = tag_comparison(
+            b"host",
+            Comparison::Regex,
+            Value::RegexValue("^a".to_string()),
+        );
+        assert_eq!(
+            NodeDisplay::new(&negate(&regex).unwrap()).to_string(),
+            "host !~ /^a/"
+        );
+
+        assert!(negate(&tag(b"host", Comparison::Lt, "a")).is_none());
+        let or = Node {
+            value: Some(Value::Logical(Logical::Or as i32)),
+            ..and(vec![
+                tag(b"host", Comparison::Equal, "a"),
+                tag(b"host", Comparison::Equal, "b"),
+            ])
+            .unwrap()
+        };
+        assert!(negate(&or).is_none());
+        assert!(negate_tag_predicates(&request(Some(or), 0, 10)).is_empty());
+    }
+}
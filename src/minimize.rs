@@ -0,0 +1,287 @@
+use std::{future::Future, path::Path, time::Duration};
+
+use structopt::StructOpt;
+
+use crate::{
+    query::{Query, QueryExecution, QueryOutput},
+    query_log::QueryLog,
+    results::ResultStore,
+    util::Connector,
+    verify::{compare, row_order, Table},
+};
+
+pub type Result<T, E = String> = std::result::Result<T, E>;
+
+/// Find a minimal subset of a query log that still reproduces a failure.
+///
+/// The queries of each candidate subset are run once, in their original
+/// order, and the failure is reproduced if any of them fails. Candidates
+/// are chosen by delta debugging (ddmin), so the result is 1-minimal:
+/// removing any single query from it no longer reproduces the failure.
+///
+/// Exactly one of `--error`, `--slower-than-ms` or `--mismatch` chooses
+/// what counts as a failure.
+#[derive(Debug, StructOpt)]
+pub struct Minimize {
+    /// The database name to run the queries against
+    db: String,
+
+    /// The query log that reproduces the failure
+    input: String,
+
+    /// The filename to write the minimized query log to
+    output: String,
+
+    /// A query fails if it returns an error
+    #[structopt(long)]
+    error: bool,
+
+    /// With `--error`, only errors containing this text count, so the
+    /// minimized log reproduces the same error
+    #[structopt(long)]
+    error_containing: Option<String>,
+
+    /// A query fails if it takes longer than this many milliseconds
+    #[structopt(long)]
+    slower_than_ms: Option<u64>,
+
+    /// A query fails if its results differ from those previously saved
+    /// into this directory by `replay --save-results` for the same log
+    #[structopt(long)]
+    mismatch: Option<String>,
+
+    /// The relative difference allowed between floating point values
+    /// with `--mismatch`
+    #[structopt(long, default_value = "1e-9")]
+    float_tolerance: f64,
+}
+
+/// What counts as a failure when minimizing
+#[derive(Debug)]
+enum Condition {
+    /// An error, containing the text if given
+    Error(Option<String>),
+    SlowerThan(Duration),
+    Mismatch {
+        expected: ResultStore,
+        tolerance: f64,
+    },
+}
+
+impl Condition {
+    /// Does running `query`, which is at `index` in the original log,
+    /// with `result` exhibit the failure?
+    fn is_failure(
+        &self,
+        index: usize,
+        query: &Query,
+        result: &Result<(QueryExecution, QueryOutput)>,
+    ) -> Result<bool> {
+        Ok(match (self, result) {
+            (Self::Error(containing), Err(e)) => match containing {
+                Some(text) => e.contains(text.as_str()),
+                None => true,
+            },
+            (Self::SlowerThan(limit), Ok((execution, _))) => execution.duration > *limit,
+            (
+                Self::Mismatch {
+                    expected,
+                    tolerance,
+                },
+                Ok((_, output)),
+            ) => {
+                let expected = Table::try_new(&expected.load(index, query)?)?;
+                let actual = Table::try_new(output)?;
+                !compare(&expected, &actual, &row_order(query), *tolerance).is_empty()
+            }
+            (Self::Error(_), Ok(_)) | (_, Err(_)) => false,
+        })
+    }
+}
+
+impl Minimize {
+    pub async fn execute(&self, connector: &Connector) -> Result<()> {
+        let condition = match (self.error, self.slower_than_ms, &self.mismatch) {
+            (true, None, None) => Condition::Error(self.error_containing.clone()),
+            (false, Some(ms), None) => Condition::SlowerThan(Duration::from_millis(ms)),
+            (false, None, Some(dir)) => Condition::Mismatch {
+                expected: ResultStore::open(dir)?,
+                tolerance: self.float_tolerance,
+            },
+            _ => {
+                return Err(
+                    "Specify exactly one of --error, --slower-than-ms or --mismatch".to_string(),
+                )
+            }
+        };
+        if self.error_containing.is_some() && !self.error {
+            return Err("--error-containing requires --error".to_string());
+        }
+
+        let log = QueryLog::new_from_file(Path::new(&self.input)).await?;
+        println!("Loaded query log with {} entries", log.queries.len());
+
+        let all: Vec<usize> = (0..log.queries.len()).collect();
+        if !self.reproduces(&log, &all, &condition, connector).await? {
+            return Err(format!("{} does not reproduce the failure", self.input));
+        }
+
+        let minimal = {
+            let (log, condition) = (&log, &condition);
+            ddmin(all, |candidate| async move {
+                self.reproduces(log, &candidate, condition, connector).await
+            })
+            .await?
+        };
+        println!(
+            "Minimized {} queries to {}: {:?}",
+            log.queries.len(),
+            minimal.len(),
+            minimal
+        );
+
+        let minimized = QueryLog {
+            queries: minimal.iter().map(|&i| log.queries[i].clone()).collect(),
+        };
+        minimized.write_to_file(Path::new(&self.output))?;
+        println!("Wrote minimized log to {}", self.output);
+        Ok(())
+    }
+
+    /// Run the queries at `indices` of `log` in order, returning true if
+    /// any of them fails
+    async fn reproduces(
+        &self,
+        log: &QueryLog,
+        indices: &[usize],
+        condition: &Condition,
+        connector: &Connector,
+    ) -> Result<bool> {
+        // reconnect each time, in case the last attempt crashed the server
+        let connection = connector.reconnect().await?;
+        for &i in indices {
+            let query = log.queries[i].query();
+            let result = query
+                .clone()
+                .replay_with_output(&self.db, connection.clone())
+                .await;
+            if condition.is_failure(i, query, &result)? {
+                println!("{} queries: reproduced by query {}", indices.len(), i);
+                return Ok(true);
+            }
+        }
+        println!("{} queries: not reproduced", indices.len());
+        Ok(false)
+    }
+}
+
+/// Reduce `indices` to a 1-minimal subset for which `reproduces` is
+/// true, using Zeller's ddmin algorithm. `reproduces` is assumed to be
+/// true for `indices` itself
+async fn ddmin<F, Fut>(mut indices: Vec<usize>, mut reproduces: F) -> Result<Vec<usize>>
+where
+    F: FnMut(Vec<usize>) -> Fut,
+    Fut: Future<Output = Result<bool>>,
+{
+    let mut granularity = 2;
+    while indices.len() >= 2 {
+        // split into `granularity` chunks of (nearly) equal size
+        let len = indices.len();
+        let chunks: Vec<Vec<usize>> = (0..granularity)
+            .map(|i| indices[i * len / granularity..(i + 1) * len / granularity].to_vec())
+            .collect();
+
+        // try each chunk on its own, then each complement
+        let complements: Vec<Vec<usize>> = (0..chunks.len())
+            .map(|skip| {
+                chunks
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| *i != skip)
+                    .flat_map(|(_, c)| c.iter().copied())
+                    .collect()
+            })
+            .collect();
+
+        let mut reduced = None;
+        for (candidate, is_chunk) in chunks
+            .iter()
+            .map(|c| (c, true))
+            .chain(complements.iter().map(|c| (c, false)))
+        {
+            if reproduces(candidate.clone()).await? {
+                reduced = Some((candidate.clone(), is_chunk));
+                break;
+            }
+        }
+
+        match reduced {
+            Some((candidate, is_chunk)) => {
+                indices = candidate;
+                granularity = if is_chunk {
+                    2
+                } else {
+                    (granularity - 1).max(2)
+                };
+            }
+            None if granularity >= indices.len() => break,
+            None => granularity = (granularity * 2).min(indices.len()),
+        }
+    }
+    Ok(indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    /// Minimize `0..n` where the failure reproduces if `reproduces` is
+    /// true, returning the result and the number of candidates tried
+    fn minimize(n: usize, reproduces: impl Fn(&BTreeSet<usize>) -> bool) -> (Vec<usize>, usize) {
+        let mut num_tries = 0;
+        let minimal = block_on(ddmin((0..n).collect(), |candidate| {
+            num_tries += 1;
+            let reproduced = reproduces(&candidate.into_iter().collect());
+            async move { Ok(reproduced) }
+        }))
+        .unwrap();
+        (minimal, num_tries)
+    }
+
+    #[test]
+    fn single_culprit() {
+        let (minimal, num_tries) = minimize(100, |s| s.contains(&42));
+        assert_eq!(minimal, vec![42]);
+        assert!(num_tries < 30, "{} tries", num_tries);
+    }
+
+    #[test]
+    fn culprits_that_must_run_together() {
+        let (minimal, _) = minimize(20, |s| s.contains(&3) && s.contains(&17));
+        assert_eq!(minimal, vec![3, 17]);
+
+        let (minimal, _) = minimize(9, |s| [1, 4, 8].iter().all(|i| s.contains(i)));
+        assert_eq!(minimal, vec![1, 4, 8]);
+    }
+
+    #[test]
+    fn result_is_one_minimal() {
+        // any two of these reproduce
+        let culprits = [2, 5, 11];
+        let (minimal, _) = minimize(16, |s| {
+            culprits.iter().filter(|c| s.contains(c)).count() >= 2
+        });
+        assert_eq!(minimal.len(), 2);
+        assert!(minimal.iter().all(|i| culprits.contains(i)));
+    }
+
+    #[test]
+    fn everything_needed() {
+        let (minimal, _) = minimize(5, |s| s.len() == 5);
+        assert_eq!(minimal, vec![0, 1, 2, 3, 4]);
+    }
+}
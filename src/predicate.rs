@@ -0,0 +1,319 @@
+use std::fmt::{Display, Formatter};
+
+use generated_types::influxdata::platform::storage::{
+    node::{Comparison, Logical, Type, Value},
+    Node, Predicate,
+};
+
+/// Tag key used by the storage gRPC API to refer to the measurement name
+pub const TAG_KEY_MEASUREMENT: &[u8] = &[0];
+
+/// Tag key used by the storage gRPC API to refer to the field name
+pub const TAG_KEY_FIELD: &[u8] = &[255];
+
+/// Is `key` one of the special measurement or field tag keys?
+pub fn is_special_key(key: &[u8]) -> bool {
+    key == TAG_KEY_MEASUREMENT || key == TAG_KEY_FIELD
+}
+
+/// Return a human readable name for the tag key `key`
+pub fn tag_name(key: &[u8]) -> String {
+    match key {
+        TAG_KEY_MEASUREMENT => "_measurement".to_string(),
+        TAG_KEY_FIELD => "_field".to_string(),
+        _ => String::from_utf8_lossy(key).to_string(),
+    }
+}
+
+/// Return the "shape" of `predicate`: its structure with all literal
+/// values replaced by `?`
+pub fn shape(predicate: &Predicate) -> String {
+    match predicate.root.as_ref() {
+        Some(root) => NodeDisplay::new(root).without_literals().to_string(),
+        None => "<empty>".to_string(),
+    }
+}
+
+/// Displays a predicate `Node` tree as an infix expression such as
+/// `_measurement = 'cpu' AND host =~ /foo.*/`
+#[derive(Debug, Clone, Copy)]
+pub struct NodeDisplay<'a> {
+    node: &'a Node,
+    literals: bool,
+}
+
+impl<'a> NodeDisplay<'a> {
+    pub fn new(node: &'a Node) -> Self {
+        Self {
+            node,
+            literals: true,
+        }
+    }
+
+    /// Replace all literal values with `?`
+    pub fn without_literals(mut self) -> Self {
+        self.literals = false;
+        self
+    }
+
+    fn child(&self, node: &'a Node) -> Self {
+        Self { node, ..*self }
+    }
+}
+
+impl<'a> Display for NodeDisplay<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let node = self.node;
+        match Type::from_i32(node.node_type) {
+            Some(Type::LogicalExpression) => {
+                let op = match node.value {
+                    Some(Value::Logical(l)) if l == Logical::Or as i32 => " OR ",
+                    _ => " AND ",
+                };
+                for (i, child) in node.children.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "{}", op)?;
+                    }
+                    write!(f, "{}", self.child(child))?;
+                }
+                Ok(())
+            }
+            Some(Type::ComparisonExpression) => {
+                let op = match node.value {
+                    Some(Value::Comparison(c)) => Comparison::from_i32(c)
+                        .map(comparison_op)
+                        .unwrap_or("<unknown comparison>"),
+                    _ => "<missing comparison>",
+                };
+                match node.children.as_slice() {
+                    [left, right] => write!(f, "{} {} {}", self.child(left), op, self.child(right)),
+                    _ => write!(f, "<{} with {} children>", op, node.children.len()),
+                }
+            }
+            Some(Type::ParenExpression) => {
+                write!(f, "(")?;
+                for child in &node.children {
+                    write!(f, "{}", self.child(child))?;
+                }
+                write!(f, ")")
+            }
+            Some(Type::TagRef) | Some(Type::FieldRef) | Some(Type::Literal) => match &node.value {
+                Some(Value::TagRefValue(key)) => write!(f, "{}", tag_name(key)),
+                Some(Value::FieldRefValue(field)) => write!(f, "{}", field),
+                Some(_) if !self.literals => write!(f, "?"),
+                Some(Value::StringValue(s)) => write!(f, "'{}'", s),
+                Some(Value::RegexValue(r)) => write!(f, "/{}/", r),
+                Some(Value::BoolValue(b)) => write!(f, "{}", b),
+                Some(Value::IntValue(i)) => write!(f, "{}", i),
+                Some(Value::UintValue(u)) => write!(f, "{}", u),
+                Some(Value::FloatValue(v)) => write!(f, "{}", v),
+                Some(other) => write!(f, "<unexpected value {:?}>", other),
+                None => write!(f, "<missing value>"),
+            },
+            None => write!(f, "<unknown node type {}>", node.node_type),
+        }
+    }
+}
+
+/// Append the nodes that are combined with `AND` in `node` to `conjuncts`
+pub fn split_conjuncts<'a>(node: &'a Node, conjuncts: &mut Vec<&'a Node>) {
+    let is_and = node.node_type == Type::LogicalExpression as i32
+        && node.value == Some(Value::Logical(Logical::And as i32));
+    let is_single_paren =
+        node.node_type == Type::ParenExpression as i32 && node.children.len() == 1;
+
+    if is_and || is_single_paren {
+        for child in &node.children {
+            split_conjuncts(child, conjuncts);
+        }
+    } else {
+        conjuncts.push(node);
+    }
+}
+
+/// If `node` is `_measurement = '<name>'` return `name`
+pub fn measurement_equality(node: &Node) -> Option<&str> {
+    if node.node_type != Type::ComparisonExpression as i32
+        || node.value != Some(Value::Comparison(Comparison::Equal as i32))
+    {
+        return None;
+    }
+
+    match node.children.as_slice() {
+        [left, right] => match (&left.value, &right.value) {
+            (Some(Value::TagRefValue(key)), Some(Value::StringValue(name)))
+                if key.as_slice() == TAG_KEY_MEASUREMENT =>
+            {
+                Some(name.as_str())
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Return the single measurement that `predicate` restricts to with a
+/// top level `_measurement = '<name>'`, if any
+pub fn measurement(predicate: &Predicate) -> Option<&str> {
+    let mut conjuncts = vec![];
+    split_conjuncts(predicate.root.as_ref()?, &mut conjuncts);
+
+    let mut names = conjuncts.into_iter().filter_map(measurement_equality);
+    let name = names.next()?;
+    if names.all(|other| other == name) {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+/// Combine `nodes` with `AND`, returning `None` if there are none
+pub fn and(mut nodes: Vec<Node>) -> Option<Node> {
+    match nodes.len() {
+        0 => None,
+        1 => nodes.pop(),
+        _ => Some(Node {
+            node_type: Type::LogicalExpression as i32,
+            children: nodes,
+            value: Some(Value::Logical(Logical::And as i32)),
+        }),
+    }
+}
+
+/// Return a node comparing the tag `key` with the literal `value`
+pub fn tag_comparison(key: &[u8], comparison: Comparison, value: Value) -> Node {
+    Node {
+        node_type: Type::ComparisonExpression as i32,
+        children: vec![
+            Node {
+                node_type: Type::TagRef as i32,
+                children: vec![],
+                value: Some(Value::TagRefValue(key.to_vec())),
+            },
+            Node {
+                node_type: Type::Literal as i32,
+                children: vec![],
+                value: Some(value),
+            },
+        ],
+        value: Some(Value::Comparison(comparison as i32)),
+    }
+}
+
+/// Return the textual operator for `comparison`
+pub fn comparison_op(comparison: Comparison) -> &'static str {
+    match comparison {
+        Comparison::Equal => "=",
+        Comparison::NotEqual => "!=",
+        Comparison::StartsWith => "startsWith",
+        Comparison::Regex => "=~",
+        Comparison::NotRegex => "!~",
+        Comparison::Lt => "<",
+        Comparison::Lte => "<=",
+        Comparison::Gt => ">",
+        Comparison::Gte => ">=",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(node_type: Type, value: Value, children: Vec<Node>) -> Node {
+        Node {
+            node_type: node_type as i32,
+            children,
+            value: Some(value),
+        }
+    }
+
+    fn comparison(key: &[u8], comparison: Comparison, value: Value) -> Node {
+        node(
+            Type::ComparisonExpression,
+            Value::Comparison(comparison as i32),
+            vec![
+                node(Type::TagRef, Value::TagRefValue(key.to_vec()), vec![]),
+                node(Type::Literal, value, vec![]),
+            ],
+        )
+    }
+
+    /// `_measurement = 'cpu' AND (host =~ /a.*/ OR usage > 1.5)`
+    fn predicate() -> Predicate {
+        let or = node(
+            Type::LogicalExpression,
+            Value::Logical(Logical::Or as i32),
+            vec![
+                comparison(b"host", Comparison::Regex, Value::RegexValue("a.*".into())),
+                comparison(b"usage", Comparison::Gt, Value::FloatValue(1.5)),
+            ],
+        );
+        let root = node(
+            Type::LogicalExpression,
+            Value::Logical(Logical::And as i32),
+            vec![
+                comparison(
+                    TAG_KEY_MEASUREMENT,
+                    Comparison::Equal,
+                    Value::StringValue("cpu".into()),
+                ),
+                Node {
+                    node_type: Type::ParenExpression as i32,
+                    children: vec![or],
+                    value: None,
+                },
+            ],
+        );
+        Predicate { root: Some(root) }
+    }
+
+    #[test]
+    fn tag_names() {
+        assert_eq!(tag_name(TAG_KEY_MEASUREMENT), "_measurement");
+        assert_eq!(tag_name(TAG_KEY_FIELD), "_field");
+        assert_eq!(tag_name(b"host"), "host");
+    }
+
+    #[test]
+    fn displays_nodes() {
+        let predicate = predicate();
+        assert_eq!(
+            NodeDisplay::new(predicate.root.as_ref().unwrap()).to_string(),
+            "_measurement = 'cpu' AND (host =~ /a.*/ OR usage > 1.5)"
+        );
+        assert_eq!(
+            NodeDisplay::new(&comparison(b"host", Comparison::Lte, Value::IntValue(-3)))
+                .to_string(),
+            "host <= -3"
+        );
+    }
+
+    #[test]
+    fn shapes_replace_literals() {
+        assert_eq!(
+            shape(&predicate()),
+            "_measurement = ? AND (host =~ ? OR usage > ?)"
+        );
+        assert_eq!(shape(&Predicate { root: None }), "<empty>");
+    }
+
+    #[test]
+    fn malformed_nodes() {
+        let mut missing_child = comparison(b"host", Comparison::Equal, Value::BoolValue(true));
+        missing_child.children.pop();
+        assert_eq!(
+            NodeDisplay::new(&missing_child).to_string(),
+            "<= with 1 children>"
+        );
+
+        let unknown = Node {
+            node_type: 99,
+            children: vec![],
+            value: None,
+        };
+        assert_eq!(
+            NodeDisplay::new(&unknown).to_string(),
+            "<unknown node type 99>"
+        );
+    }
+}
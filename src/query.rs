@@ -3,10 +3,16 @@ use std::{
     time::{Duration, Instant},
 };
 
-use crate::error::StringifyError;
+use crate::{
+    error::StringifyError,
+    fingerprint::Fingerprint,
+    sql::is_system_query,
+    stats::{coefficient_of_variation, outliers, relative_standard_error},
+};
+use arrow::record_batch::RecordBatch;
 use futures::stream::TryStreamExt;
 use generated_types::influxdata::platform::storage::{
-    storage_client::StorageClient, ReadFilterRequest,
+    read_response::Frame, storage_client::StorageClient, ReadFilterRequest,
 };
 use generated_types::ReadSource;
 use influxdb_iox_client::connection::Connection;
@@ -54,19 +60,34 @@ impl StorageRpc {
 
     // Return the original org_id and bucket_id for this request
     pub fn read_source(&self) -> Result<(String, String)> {
+        let ReadSource {
+            org_id, bucket_id, ..
+        } = self.decode_read_source()?;
+
+        Ok((org_id.to_string(), bucket_id.to_string()))
+    }
+
+    /// Return the name of the database (`<org_id>_<bucket_id>`) that
+    /// this request was originally sent to
+    pub fn database_name(&self) -> Result<String> {
+        let ReadSource {
+            org_id, bucket_id, ..
+        } = self.decode_read_source()?;
+
+        Ok(format!("{:016x}_{:016x}", org_id, bucket_id))
+    }
+
+    /// Decode the `ReadSource` this request was originally sent to
+    pub fn decode_read_source(&self) -> Result<ReadSource> {
         let read_source = match self {
             StorageRpc::ReadFilter(request) => request.read_source.as_ref(),
         }
         .ok_or_else(|| format!("No read source found on request {}", self.name()))?;
 
-        let ReadSource {
-            bucket_id, org_id, ..
-        } = Message::decode(&read_source.value[..]).context(&format!(
+        Message::decode(&read_source.value[..]).context(&format!(
             "value could not be parsed as a ReadSource message on request {}",
             self.name()
-        ))?;
-
-        Ok((bucket_id.to_string(), org_id.to_string()))
+        ))
     }
 }
 
@@ -79,8 +100,35 @@ impl Display for Query {
     }
 }
 
+/// The data returned by running a `Query`
+#[derive(Debug, Clone)]
+pub enum QueryOutput {
+    /// Record batches returned by a SQL query
+    Sql(Vec<RecordBatch>),
+    /// Frames returned by a Storage RPC request
+    StorageRpc(Vec<Frame>),
+}
+
+impl QueryOutput {
+    /// The number of rows returned by a SQL query
+    pub fn num_rows(&self) -> usize {
+        match self {
+            QueryOutput::Sql(batches) => batches.iter().map(|batch| batch.num_rows()).sum(),
+            QueryOutput::StorageRpc(_) => 0,
+        }
+    }
+
+    /// The number of data frames returned by a Storage RPC request
+    pub fn num_frames(&self) -> usize {
+        match self {
+            QueryOutput::Sql(_) => 0,
+            QueryOutput::StorageRpc(frames) => frames.iter().filter(|f| f.data.is_some()).count(),
+        }
+    }
+}
+
 /// Information on the results of running a `Query`
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 pub struct QueryExecution {
     /// the total time to run the query (including network time)
     pub duration: Duration,
@@ -168,15 +216,53 @@ pub struct QueryExecutionSummary {
 
     /// The total number of executions aggregated
     pub count: usize,
+
+    /// The duration of each execution, in the order they ran
+    pub samples: Vec<Duration>,
 }
 
 impl QueryExecutionSummary {
+    /// The average duration of the aggregated executions
+    pub fn mean_duration(&self) -> Duration {
+        if self.count == 0 {
+            Duration::ZERO
+        } else {
+            self.inner.duration / self.count as u32
+        }
+    }
+
+    /// The duration of each execution, in milliseconds
+    pub fn samples_ms(&self) -> Vec<f64> {
+        self.samples
+            .iter()
+            .map(|d| d.as_secs_f64() * 1000.0)
+            .collect()
+    }
+
+    /// The standard deviation of the execution durations relative to
+    /// their mean: the precision achieved by the measurement
+    pub fn coefficient_of_variation(&self) -> f64 {
+        coefficient_of_variation(&self.samples_ms())
+    }
+
+    /// The standard error of the mean duration relative to the mean: the
+    /// value `--target-relative-error` is compared against
+    pub fn relative_standard_error(&self) -> f64 {
+        relative_standard_error(&self.samples_ms())
+    }
+
+    /// How many executions took unusually long (or short) compared to
+    /// the others?
+    pub fn num_outliers(&self) -> usize {
+        outliers(&self.samples_ms()).len()
+    }
+
     /// return something that displays headers for query execution summaries
     pub fn header() -> impl Display {
         struct Header {}
         impl Display for Header {
             fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-                write!(f, "total_duration_ms\tmin_duration_ms\tmax_duration_ms\tcount\ttotal_rows\ttotal_frames")
+                write!(f, "total_duration_ms\tmin_duration_ms\tmax_duration_ms\tcount\ttotal_rows\ttotal_frames\tcv\toutliers\trse")
             }
         }
         Header {}
@@ -187,13 +273,16 @@ impl Display for QueryExecutionSummary {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{}\t{}\t{}\t{}\t{}\t{}",
+            "{}\t{}\t{}\t{}\t{}\t{}\t{:.3}\t{}\t{:.4}",
             self.inner.duration.as_millis(),
             self.min_duration.as_millis(),
             self.max_duration.as_millis(),
             self.count,
             self.inner.num_rows,
             self.inner.num_frames,
+            self.coefficient_of_variation(),
+            self.num_outliers(),
+            self.relative_standard_error(),
         )
     }
 }
@@ -204,6 +293,7 @@ pub struct QueryExecutionSummaryBuilder {
     min_duration: Option<Duration>,
     max_duration: Option<Duration>,
     count: usize,
+    samples: Vec<Duration>,
 }
 
 impl QueryExecutionSummaryBuilder {
@@ -219,17 +309,30 @@ impl QueryExecutionSummaryBuilder {
             .unwrap_or_default()
     }
 
+    /// How many executions have been added so far?
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// The duration of each execution added so far
+    pub fn samples(&self) -> &[Duration] {
+        &self.samples
+    }
+
     pub fn build(self) -> QueryExecutionSummary {
         QueryExecutionSummary {
             inner: self.inner.unwrap(),
             min_duration: self.min_duration.unwrap(),
             max_duration: self.max_duration.unwrap(),
             count: self.count,
+            samples: self.samples,
         }
     }
 
     /// Add the query execution to the builder
     pub fn add(mut self, summary: QueryExecution) -> Self {
+        self.samples.push(summary.duration);
+
         self.min_duration = Some(
             self.min_duration
                 .take()
@@ -297,56 +400,119 @@ impl Query {
         }
     }
 
-    /// Resend the query to the specfied database name
-    pub async fn replay(
+    /// Return the `query_type` of this query, as stored in `system.queries`
+    pub fn query_type(&self) -> &'static str {
+        match self {
+            Query::Sql(_) => "sql",
+            Query::StorageRpc(StorageRpc::ReadFilter(_)) => "read_filter",
+        }
+    }
+
+    /// Return the `query_text` of this query, as stored in `system.queries`
+    pub fn query_text(&self) -> Result<String> {
+        match self {
+            Query::Sql(sql) => Ok(sql.clone()),
+            Query::StorageRpc(StorageRpc::ReadFilter(request)) => {
+                serde_json::to_string(request).context("Error serializing read_filter request")
+            }
+        }
+    }
+
+    /// Does this query read system tables (such as `system.queries`)
+    /// rather than user data? Such queries, often issued by operators or
+    /// this tool, measure nothing about the workload
+    pub fn is_system(&self) -> bool {
+        match self {
+            Query::Sql(sql) => is_system_query(sql),
+            Query::StorageRpc(_) => false,
+        }
+    }
+
+    /// Return the `Fingerprint` of this query
+    pub fn fingerprint(&self) -> Fingerprint {
+        Fingerprint::new(self)
+    }
+
+    /// Return this query as it would be sent to `database_name`,
+    /// rewriting Storage RPC requests to target that database.
+    ///
+    /// Storage RPC requests identify their database by org and bucket,
+    /// so they are only rewritten if `database_name` is of the form
+    /// `<org_id>_<bucket_id>`, and otherwise keep their `ReadSource`
+    pub fn for_database(self, database_name: &str) -> Result<Self> {
+        match self {
+            Query::Sql(_) => Ok(self),
+            Query::StorageRpc(StorageRpc::ReadFilter(mut request)) => {
+                request.with_database(database_name)?;
+                Ok(Query::StorageRpc(StorageRpc::ReadFilter(request)))
+            }
+        }
+    }
+
+    /// Return this query with Storage RPC requests rewritten to target
+    /// `org_id` and `bucket_id`
+    pub fn with_read_source(self, org_id: u64, bucket_id: u64) -> Self {
+        match self {
+            Query::Sql(_) => self,
+            Query::StorageRpc(StorageRpc::ReadFilter(mut request)) => {
+                request.with_read_source(org_id, bucket_id);
+                Query::StorageRpc(StorageRpc::ReadFilter(request))
+            }
+        }
+    }
+
+    /// Resend the query to the specfied database name, also returning
+    /// the data it produced
+    pub async fn replay_with_output(
         self,
         database_name: &str,
         connection: Connection,
-    ) -> Result<QueryExecution> {
+    ) -> Result<(QueryExecution, QueryOutput)> {
+        let query = self.for_database(database_name)?;
         let mut execution = QueryExecutionBuilder::new();
 
-        match self {
+        let output = match query {
             Query::Sql(sql) => {
                 let mut client = influxdb_iox_client::flight::Client::new(connection);
 
                 //println!("Running SQL query: '{}'", sql);
                 let mut result = client.perform_query(database_name, sql).await.stringify()?;
 
+                let mut batches = vec![];
                 while let Some(batch) = result.next().await.stringify()? {
                     //println!("received {} rows", batch.num_rows());
                     execution.add_rows(batch.num_rows());
+                    batches.push(batch);
                 }
+                QueryOutput::Sql(batches)
             }
             Query::StorageRpc(storagerpc) => {
                 let mut storage_client = StorageClient::new(connection);
                 //println!("Sending storage client request...");Z
 
                 match storagerpc {
-                    StorageRpc::ReadFilter(mut request) => {
-                        request.with_database(database_name)?;
-
+                    StorageRpc::ReadFilter(request) => {
                         let read_response = storage_client
                             .read_filter(request)
                             .await
                             .context("Error making read_filter request")?;
 
                         //println!("Got result: {:?}", read_response);
-                        let responses: Vec<_> =
-                            read_response.into_inner().try_collect().await.unwrap();
-
-                        responses
-                            .into_iter()
-                            .flat_map(|r| r.frames)
-                            .flat_map(|f| f.data)
-                            .for_each(|_d| {
-                                //println!("Got response data: {:?}", _d);
-                                execution.add_frames(1);
-                            })
+                        let responses: Vec<_> = read_response
+                            .into_inner()
+                            .try_collect()
+                            .await
+                            .context("Error reading read_filter response")?;
+
+                        let frames: Vec<_> = responses.into_iter().flat_map(|r| r.frames).collect();
+                        let output = QueryOutput::StorageRpc(frames);
+                        execution.add_frames(output.num_frames());
+                        output
                     }
                 }
             }
-        }
-        Ok(execution.build())
+        };
+        Ok((execution.build(), output))
     }
 }
 
@@ -361,19 +527,30 @@ fn truncate_and_clean(s: &str, max_chars: usize) -> String {
 trait WithDatabase {
     /// Modifies this request so its source field targets the specified database
     fn with_database(&mut self, database_name: &str) -> Result<()>;
+
+    /// Modifies this request so its source field targets the specified
+    /// org and bucket
+    fn with_read_source(&mut self, org_id: u64, bucket_id: u64);
 }
 
-// rewrite the ReadSource to refer to the specified database rather than the original
+// rewrite the ReadSource to refer to the specified database rather than the
+// original, if the database is named after an org and bucket
 impl WithDatabase for ReadFilterRequest {
     fn with_database(&mut self, database_name: &str) -> Result<()> {
-        self.read_source = make_read_source(database_name)?;
+        if let Ok((org_id, bucket_id)) = parse_database_name(database_name) {
+            self.with_read_source(org_id, bucket_id);
+        }
         Ok(())
     }
+
+    fn with_read_source(&mut self, org_id: u64, bucket_id: u64) {
+        self.read_source = Some(make_read_source(org_id, bucket_id));
+    }
 }
 
-pub fn make_read_source(
-    database_name: &str,
-) -> Result<Option<generated_types::google::protobuf::Any>> {
+/// Return the org_id and bucket_id of a database named
+/// `<org_id>_<bucket_id>` (both in hex)
+pub fn parse_database_name(database_name: &str) -> Result<(u64, u64)> {
     let mut split_name = database_name.split('_');
 
     let org_id = split_name
@@ -403,6 +580,10 @@ pub fn make_read_source(
         ));
     }
 
+    Ok((org_id, bucket_id))
+}
+
+pub fn make_read_source(org_id: u64, bucket_id: u64) -> generated_types::google::protobuf::Any {
     // pick an arbitrary partition id
     let partition_id = u64::from(u32::MAX);
     let read_source = ReadSource {
@@ -411,12 +592,18 @@ pub fn make_read_source(
         partition_id,
     };
 
+    encode_read_source(&read_source, "/TODO")
+}
+
+/// Encode `read_source` as a protobuf `Any` message with `type_url`
+pub fn encode_read_source(
+    read_source: &ReadSource,
+    type_url: &str,
+) -> generated_types::google::protobuf::Any {
     let mut d = bytes::BytesMut::new();
     read_source.encode(&mut d).unwrap();
-    let read_source = generated_types::google::protobuf::Any {
-        type_url: "/TODO".to_string(),
+    generated_types::google::protobuf::Any {
+        type_url: type_url.to_string(),
         value: d.freeze(),
-    };
-
-    Ok(Some(read_source))
+    }
 }
@@ -1,8 +1,14 @@
-use std::{fs::File, path::Path};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
 
-use serde_json::Value;
+use rand::{seq::SliceRandom, Rng};
+use serde_json::{json, Value};
 
-use crate::{error::StringifyError, query::Query};
+use crate::{error::StringifyError, query::Query, time::parse_timestamp};
 
 pub type Result<T, E = String> = std::result::Result<T, E>;
 
@@ -10,17 +16,43 @@ pub type Result<T, E = String> = std::result::Result<T, E>;
 /// Represents a row in the system.queries table
 pub struct QueryRow {
     /// time at which the query was issued
-    _issue_time: String,
+    issue_time: String,
 
     /// Type of the query (TODO parse this into the known types)
     query: Query,
 }
 
 impl QueryRow {
+    pub fn new(issue_time: impl Into<String>, query: Query) -> Self {
+        Self {
+            issue_time: issue_time.into(),
+            query,
+        }
+    }
+
     /// Return the contained `Query`
     pub fn into_inner(self) -> Query {
         self.query
     }
+
+    /// Return a reference to the contained `Query`
+    pub fn query(&self) -> &Query {
+        &self.query
+    }
+
+    /// Return the time at which the query was issued
+    pub fn issue_time(&self) -> &str {
+        &self.issue_time
+    }
+
+    /// Return this row in the same JSON format as `system.queries`
+    fn to_json(&self) -> Result<Value> {
+        Ok(json!({
+            "issue_time": self.issue_time,
+            "query_type": self.query.query_type(),
+            "query_text": self.query.query_text()?,
+        }))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -104,7 +136,7 @@ impl QueryLog {
                 )?;
 
                 let query = QueryRow {
-                    _issue_time: get_field(&mut map, "issue_time")?,
+                    issue_time: get_field(&mut map, "issue_time")?,
                     query: query_content,
                 };
                 Ok(query)
@@ -113,4 +145,191 @@ impl QueryLog {
 
         Ok(Self { queries })
     }
+
+    /// Write this log to `path` in a format that can be read back by
+    /// `new_from_file`
+    pub fn write_to_file(&self, path: &Path) -> Result<()> {
+        println!("Writing {} queries to {:?}", self.queries.len(), path);
+        let rows = self
+            .queries
+            .iter()
+            .map(|row| row.to_json())
+            .collect::<Result<Vec<_>>>()?;
+
+        let file = File::create(path).context(&format!("Creating file {:?}", path))?;
+        let mut file = BufWriter::new(file);
+        serde_json::to_writer(&mut file, &Value::Array(rows)).context("Writing query log")?;
+        file.flush().context("Flushing output buffer")
+    }
+
+    /// Return this log with its queries ordered by the time they were issued
+    pub fn sorted_by_issue_time(self) -> Result<Self> {
+        let mut queries = self
+            .queries
+            .into_iter()
+            .map(|row| Ok((parse_timestamp(&row.issue_time)?, row)))
+            .collect::<Result<Vec<_>>>()?;
+
+        // stable sort, so queries issued at the same time keep their order
+        queries.sort_by_key(|(issue_time, _)| *issue_time);
+
+        Ok(Self {
+            queries: queries.into_iter().map(|(_, row)| row).collect(),
+        })
+    }
+
+    /// Merge `logs` into a single log ordered by issue time
+    pub fn merge(logs: impl IntoIterator<Item = QueryLog>) -> Result<Self> {
+        let queries = logs.into_iter().flat_map(|log| log.queries).collect();
+        Self { queries }.sorted_by_issue_time()
+    }
+
+    /// Return the `n` earliest issued queries
+    pub fn head(self, n: usize) -> Result<Self> {
+        let mut log = self.sorted_by_issue_time()?;
+        log.queries.truncate(n);
+        Ok(log)
+    }
+
+    /// Return the `n` most recently issued queries
+    pub fn tail(self, n: usize) -> Result<Self> {
+        let mut log = self.sorted_by_issue_time()?;
+        let skip = log.queries.len().saturating_sub(n);
+        log.queries.drain(..skip);
+        Ok(log)
+    }
+
+    /// Return `n` queries chosen uniformly at random, in their original order
+    pub fn sample(&self, n: usize, rng: &mut impl Rng) -> Self {
+        let n = n.min(self.queries.len());
+        let indices = rand::seq::index::sample(rng, self.queries.len(), n).into_vec();
+        self.select(indices)
+    }
+
+    /// Return `n` queries (or all of them, if there are fewer) chosen at
+    /// random such that each group of queries with the same `key` is
+    /// represented proportionally, and by at least one query if `n`
+    /// allows.
+    pub fn stratified_sample<K: Ord>(
+        &self,
+        n: usize,
+        key: impl Fn(&QueryRow) -> K,
+        rng: &mut impl Rng,
+    ) -> Self {
+        let mut strata: BTreeMap<K, Vec<usize>> = BTreeMap::new();
+        for (i, row) in self.queries.iter().enumerate() {
+            strata.entry(key(row)).or_default().push(i);
+        }
+
+        // give each group one query, then share the rest proportionally
+        let sizes: Vec<usize> = strata.values().map(Vec::len).collect();
+        let counts = if n >= sizes.len() {
+            let rest: Vec<usize> = sizes.iter().map(|size| size - 1).collect();
+            largest_remainder(n - sizes.len(), &rest)
+                .into_iter()
+                .map(|count| count + 1)
+                .collect()
+        } else {
+            largest_remainder(n, &sizes)
+        };
+
+        let mut indices = vec![];
+        for (members, count) in strata.values().zip(counts) {
+            indices.extend(members.choose_multiple(rng, count).copied());
+        }
+        self.select(indices)
+    }
+
+    /// Split this log into separate logs, one for each distinct `key`
+    pub fn split_by<K: Ord>(
+        self,
+        key: impl Fn(&QueryRow) -> Result<K>,
+    ) -> Result<BTreeMap<K, QueryLog>> {
+        let mut logs: BTreeMap<K, QueryLog> = BTreeMap::new();
+        for row in self.queries {
+            logs.entry(key(&row)?)
+                .or_insert_with(|| QueryLog { queries: vec![] })
+                .queries
+                .push(row);
+        }
+        Ok(logs)
+    }
+
+    /// Return a log with the queries at `indices`, in their original order
+    fn select(&self, mut indices: Vec<usize>) -> Self {
+        indices.sort_unstable();
+        Self {
+            queries: indices
+                .into_iter()
+                .map(|i| self.queries[i].clone())
+                .collect(),
+        }
+    }
+}
+
+/// Share `n` (at most the total of `sizes`) between groups of the given
+/// `sizes` in proportion to their size, using the largest remainder
+/// method so that the shares add up to exactly `n`
+fn largest_remainder(n: usize, sizes: &[usize]) -> Vec<usize> {
+    let total: usize = sizes.iter().sum();
+    let n = n.min(total);
+    if n == 0 {
+        return vec![0; sizes.len()];
+    }
+
+    let mut shares: Vec<usize> = sizes.iter().map(|size| n * size / total).collect();
+    let mut by_remainder: Vec<usize> = (0..sizes.len()).collect();
+    by_remainder.sort_by_key(|&i| std::cmp::Reverse(n * sizes[i] % total));
+
+    let unallocated = n - shares.iter().sum::<usize>();
+    for &i in by_remainder.iter().take(unallocated) {
+        shares[i] += 1;
+    }
+    shares
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn largest_remainder_shares_exactly_n() {
+        assert_eq!(largest_remainder(10, &[50, 30, 20]), vec![5, 3, 2]);
+        assert_eq!(largest_remainder(3, &[5, 5, 5, 5]), vec![1, 1, 1, 0]);
+        assert_eq!(largest_remainder(4, &[1, 1, 1, 97]), vec![0, 0, 0, 4]);
+        assert_eq!(largest_remainder(7, &[2, 3]), vec![2, 3]);
+        assert_eq!(largest_remainder(0, &[2, 3]), vec![0, 0]);
+        assert_eq!(largest_remainder(5, &[]), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn stratified_sample_is_capped_at_n() {
+        let queries = (0..100)
+            .map(|i| {
+                let table = if i < 97 { i % 10 } else { 100 + i };
+                QueryRow::new(
+                    format!("2021-12-16 15:06:{:02}", i % 60),
+                    Query::Sql(format!("SELECT * FROM t{}", table)),
+                )
+            })
+            .collect();
+        let log = QueryLog { queries };
+        let mut rng = StdRng::seed_from_u64(42);
+
+        for n in [5, 13, 20, 100, 200] {
+            let sample = log.stratified_sample(n, |row| row.query().to_string(), &mut rng);
+            assert_eq!(sample.queries.len(), n.min(100));
+        }
+
+        // 13 distinct queries, so each is represented in a sample of 13
+        let sample = log.stratified_sample(13, |row| row.query().to_string(), &mut rng);
+        let distinct: std::collections::BTreeSet<_> = sample
+            .queries
+            .iter()
+            .map(|row| row.query().to_string())
+            .collect();
+        assert_eq!(distinct.len(), 13);
+    }
 }
@@ -0,0 +1,364 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Formatter},
+    fs::File,
+    path::Path,
+};
+
+use structopt::StructOpt;
+
+use crate::{
+    error::StringifyError,
+    query::{
+        parse_database_name, Query, QueryExecution, QueryExecutionSummary,
+        QueryExecutionSummaryBuilder,
+    },
+};
+
+pub type Result<T, E = String> = std::result::Result<T, E>;
+
+/// Options choosing the org and bucket that Storage RPC requests are sent
+/// to.
+///
+/// By default requests are rewritten to the org and bucket encoded in a
+/// database named `<org_id>_<bucket_id>`, and keep their original
+/// `ReadSource` for databases named any other way.
+#[derive(Debug, StructOpt)]
+pub struct ReadSourceOptions {
+    /// Send Storage RPC requests to this org id (in hex), for databases
+    /// not named `<org_id>_<bucket_id>`. Requires `--bucket-id`
+    #[structopt(long, parse(try_from_str = parse_id))]
+    org_id: Option<u64>,
+
+    /// Send Storage RPC requests to this bucket id (in hex), for
+    /// databases not named `<org_id>_<bucket_id>`. Requires `--org-id`
+    #[structopt(long, parse(try_from_str = parse_id))]
+    bucket_id: Option<u64>,
+}
+
+impl ReadSourceOptions {
+    /// Return `queries` rewritten to be sent to `database_name` as
+    /// requested
+    pub fn apply(&self, queries: Vec<Query>, database_name: &str) -> Result<Vec<Query>> {
+        match (self.org_id, self.bucket_id) {
+            (None, None) => Ok(queries),
+            (Some(org_id), Some(bucket_id)) => {
+                if parse_database_name(database_name).is_ok() {
+                    return Err(format!(
+                        "--org-id and --bucket-id can not be used with {}, \
+                         whose name already determines them",
+                        database_name
+                    ));
+                }
+                println!(
+                    "Sending Storage RPC requests to org {:016x} bucket {:016x}",
+                    org_id, bucket_id
+                );
+                Ok(queries
+                    .into_iter()
+                    .map(|q| q.with_read_source(org_id, bucket_id))
+                    .collect())
+            }
+            _ => Err("Specify both or neither of --org-id and --bucket-id".to_string()),
+        }
+    }
+}
+
+/// Parse an org or bucket id written in hex
+fn parse_id(s: &str) -> Result<u64> {
+    u64::from_str_radix(s, 16).map_err(|e| format!("Can not parse id '{}' as hex: {}", s, e))
+}
+
+/// Maps the org and bucket of Storage RPC requests in a log that
+/// contains requests for many tenants to the database each is replayed
+/// into.
+///
+/// The mapping file is a JSON object from `<org_id>_<bucket_id>` to the
+/// database to replay into. Storage RPC requests address a database by
+/// org and bucket, so the database is either named `<org_id>_<bucket_id>`
+/// or given with the org and bucket ids (in hex) to send requests to, for
+/// example
+///
+/// ```json
+/// {
+///   "26f7e5a4b7be365b_917b97a92e883afc": "0000000000000001_0000000000000001",
+///   "ab6ac6aa1fb6b962_b3f212db926c7897": {
+///     "database": "mirror_b",
+///     "org_id": "0000000000000002",
+///     "bucket_id": "0000000000000001"
+///   }
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct TenantMap {
+    databases: BTreeMap<(u64, u64), MappedDatabase>,
+}
+
+/// The database a tenant is replayed into
+#[derive(Debug, Clone, PartialEq)]
+struct MappedDatabase {
+    name: String,
+    /// The org and bucket to send Storage RPC requests to, if they are not
+    /// determined by `name`
+    read_source: Option<(u64, u64)>,
+}
+
+impl MappedDatabase {
+    fn try_from_json(value: serde_json::Value) -> Result<Self> {
+        let name = match value {
+            serde_json::Value::String(name) => name,
+            serde_json::Value::Object(mut fields) => {
+                let mut take = |field: &str| match fields.remove(field) {
+                    Some(serde_json::Value::String(value)) => Ok(value),
+                    _ => Err(format!("expected a string \"{}\"", field)),
+                };
+                let name = take("database")?;
+                let read_source = (parse_id(&take("org_id")?)?, parse_id(&take("bucket_id")?)?);
+                if let Some(unknown) = fields.keys().next() {
+                    return Err(format!("unknown field \"{}\"", unknown));
+                }
+                if matches!(parse_database_name(&name), Ok(ids) if ids != read_source) {
+                    return Err(format!(
+                        "database {} is named after a different org and bucket",
+                        name
+                    ));
+                }
+                return Ok(Self {
+                    name,
+                    read_source: Some(read_source),
+                });
+            }
+            other => return Err(format!("expected a database, got {}", other)),
+        };
+
+        parse_database_name(&name).map_err(|e| {
+            format!(
+                "{}, so give the org_id and bucket_id to send requests to {}",
+                e, name
+            )
+        })?;
+        Ok(Self {
+            name,
+            read_source: None,
+        })
+    }
+}
+
+impl TenantMap {
+    pub fn new_from_file(path: &Path) -> Result<Self> {
+        let file = File::open(path).context(&format!("Opening tenant map {:?}", path))?;
+        let entries: BTreeMap<String, serde_json::Value> =
+            serde_json::from_reader(file).context("Parsing tenant map")?;
+        Self::try_from_entries(entries)
+    }
+
+    fn try_from_entries(entries: BTreeMap<String, serde_json::Value>) -> Result<Self> {
+        let databases = entries
+            .into_iter()
+            .map(|(tenant, value)| {
+                let database = MappedDatabase::try_from_json(value)
+                    .map_err(|e| format!("Can not replay tenant {}: {}", tenant, e))?;
+                Ok((parse_database_name(&tenant)?, database))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { databases })
+    }
+
+    /// Return the database to replay the queries of `tenant` into, if it
+    /// is mapped
+    pub fn database(&self, tenant: Tenant) -> Option<&str> {
+        self.databases
+            .get(&tenant.ids()?)
+            .map(|database| database.name.as_str())
+    }
+
+    /// Return the org and bucket ids to send the Storage RPC requests of
+    /// `tenant` to, if they are given in the map rather than by the name
+    /// of its database
+    pub fn read_source(&self, tenant: Tenant) -> Option<(u64, u64)> {
+        self.databases.get(&tenant.ids()?)?.read_source
+    }
+}
+
+/// The org and bucket a query was originally sent to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Tenant {
+    /// A SQL query, which does not identify its tenant
+    Sql,
+    /// A Storage RPC request whose `ReadSource` can not be decoded
+    Unknown,
+    /// A Storage RPC request to this org_id and bucket_id
+    ReadSource(u64, u64),
+}
+
+impl Tenant {
+    /// The org_id and bucket_id of a Storage RPC request
+    pub fn ids(self) -> Option<(u64, u64)> {
+        match self {
+            Self::ReadSource(org_id, bucket_id) => Some((org_id, bucket_id)),
+            Self::Sql | Self::Unknown => None,
+        }
+    }
+}
+
+impl Display for Tenant {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sql => write!(f, "sql"),
+            Self::Unknown => write!(f, "unknown_read_source"),
+            Self::ReadSource(org_id, bucket_id) => write!(f, "{:016x}_{:016x}", org_id, bucket_id),
+        }
+    }
+}
+
+/// Return the tenant a `query` was originally sent to
+pub fn tenant(query: &Query) -> Tenant {
+    match query {
+        Query::Sql(_) => Tenant::Sql,
+        Query::StorageRpc(storagerpc) => match storagerpc.decode_read_source() {
+            Ok(read_source) => Tenant::ReadSource(read_source.org_id, read_source.bucket_id),
+            Err(_) => Tenant::Unknown,
+        },
+    }
+}
+
+#[derive(Debug, Default)]
+struct TenantSummary {
+    /// The database the tenant's queries were replayed into
+    database: String,
+    /// The number of queries of this tenant
+    num_queries: usize,
+    executions: QueryExecutionSummaryBuilder,
+}
+
+/// Summarizes `QueryExecution`s per tenant (org and bucket)
+#[derive(Debug, Default)]
+pub struct TenantSummaries {
+    tenants: BTreeMap<Tenant, TenantSummary>,
+}
+
+impl TenantSummaries {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Record that a query of `tenant` is being replayed into
+    /// `database_name`
+    pub fn add_query(&mut self, tenant: Tenant, database_name: &str) {
+        let tenant = self.tenants.entry(tenant).or_default();
+        tenant.database = database_name.to_string();
+        tenant.num_queries += 1;
+    }
+
+    /// Add an execution of a query of `tenant`
+    pub fn add_execution(&mut self, tenant: Tenant, execution: QueryExecution) {
+        let tenant = self.tenants.entry(tenant).or_default();
+        tenant.executions = std::mem::take(&mut tenant.executions).add(execution);
+    }
+
+    /// Print the summary of each tenant. SQL queries, which do not
+    /// identify their tenant, are summarized together, as are Storage RPC
+    /// requests whose tenant can not be decoded
+    pub fn print(self) {
+        println!("Summary of {} tenants", self.tenants.len());
+        println!(
+            "tenant,database,queries,mean_duration_ms,{}",
+            QueryExecutionSummary::header()
+        );
+        for (tenant, summary) in self.tenants {
+            if summary.executions.count() == 0 {
+                continue;
+            }
+            let executions = summary.executions.build();
+            println!(
+                "{},{},{},{},{}",
+                tenant,
+                summary.database,
+                summary.num_queries,
+                executions.mean_duration().as_millis(),
+                executions
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn tenant_map(entries: &[(&str, serde_json::Value)]) -> Result<TenantMap> {
+        TenantMap::try_from_entries(
+            entries
+                .iter()
+                .map(|(tenant, database)| (tenant.to_string(), database.clone()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn maps_tenants_to_databases() {
+        let map = tenant_map(&[(
+            "00000000000000aa_00000000000000bb",
+            json!("0000000000000001_0000000000000002"),
+        )])
+        .unwrap();
+        let tenant = Tenant::ReadSource(0xaa, 0xbb);
+        assert_eq!(
+            map.database(tenant),
+            Some("0000000000000001_0000000000000002")
+        );
+        assert_eq!(map.read_source(tenant), None);
+        assert_eq!(map.database(Tenant::ReadSource(0xaa, 0xcc)), None);
+        assert_eq!(map.database(Tenant::Sql), None);
+        assert_eq!(map.database(Tenant::Unknown), None);
+    }
+
+    #[test]
+    fn databases_must_name_an_org_and_bucket() {
+        let e =
+            tenant_map(&[("00000000000000aa_00000000000000bb", json!("tenant_a"))]).unwrap_err();
+        assert!(e.contains("tenant_a"), "{}", e);
+        assert!(tenant_map(&[("tenant_a", json!("0000000000000001_0000000000000002"))]).is_err());
+    }
+
+    #[test]
+    fn databases_with_explicit_ids() {
+        let map = tenant_map(&[(
+            "00000000000000aa_00000000000000bb",
+            json!({"database": "mirror_a", "org_id": "01", "bucket_id": "0f"}),
+        )])
+        .unwrap();
+        let tenant = Tenant::ReadSource(0xaa, 0xbb);
+        assert_eq!(map.database(tenant), Some("mirror_a"));
+        assert_eq!(map.read_source(tenant), Some((0x1, 0xf)));
+
+        for bad in [
+            json!({"database": "mirror_a", "org_id": "01"}),
+            json!({"database": "mirror_a", "org_id": "01", "bucket_id": "xyz"}),
+            json!({"database": "mirror_a", "org_id": "01", "bucket_id": "0f", "extra": "1"}),
+            json!({"database": "0000000000000002_000000000000000f", "org_id": "01", "bucket_id": "0f"}),
+            json!(1),
+        ] {
+            let e = tenant_map(&[("00000000000000aa_00000000000000bb", bad.clone())]).unwrap_err();
+            assert!(
+                e.contains("00000000000000aa_00000000000000bb"),
+                "{}: {}",
+                bad,
+                e
+            );
+        }
+    }
+
+    #[test]
+    fn tenant_labels() {
+        assert_eq!(Tenant::Sql.to_string(), "sql");
+        assert_eq!(Tenant::Unknown.to_string(), "unknown_read_source");
+        assert_eq!(
+            Tenant::ReadSource(0xaa, 0xbb).to_string(),
+            "00000000000000aa_00000000000000bb"
+        );
+        assert_eq!(tenant(&Query::Sql("SELECT 1".to_string())), Tenant::Sql);
+    }
+}
@@ -0,0 +1,364 @@
+use std::{future::Future, path::Path, time::Duration};
+
+use generated_types::influxdata::platform::storage::{
+    node::{Logical, Type, Value},
+    Node, ReadFilterRequest, TimestampRange,
+};
+use structopt::StructOpt;
+
+use crate::{
+    predicate::NodeDisplay,
+    query::{Query, StorageRpc},
+    query_log::QueryLog,
+    util::Connector,
+};
+
+pub type Result<T, E = String> = std::result::Result<T, E>;
+
+/// Shrink a single failing or slow Storage RPC request from a query log,
+/// printing the smallest request that still fails as pbjson.
+///
+/// Predicate subtrees are dropped, the time range is narrowed and
+/// regexes are simplified for as long as the failure persists. Exactly
+/// one of `--error` or `--slower-than-ms` chooses what counts as a
+/// failure.
+#[derive(Debug, StructOpt)]
+pub struct ReduceQuery {
+    /// The database name to run the request against
+    db: String,
+
+    /// The filename of the query log
+    filename: String,
+
+    /// The index of the request to reduce within the log
+    index: usize,
+
+    /// The request fails if it returns an error
+    #[structopt(long)]
+    error: bool,
+
+    /// With `--error`, only errors containing this text count, so the
+    /// reduced request reproduces the same error
+    #[structopt(long)]
+    error_containing: Option<String>,
+
+    /// The request fails if it takes longer than this many milliseconds
+    #[structopt(long)]
+    slower_than_ms: Option<u64>,
+}
+
+impl ReduceQuery {
+    pub async fn execute(&self, connector: &Connector) -> Result<()> {
+        if self.error == self.slower_than_ms.is_some() {
+            return Err("Specify exactly one of --error or --slower-than-ms".to_string());
+        }
+
+        let log = QueryLog::new_from_file(Path::new(&self.filename)).await?;
+        let row = log.queries.get(self.index).ok_or_else(|| {
+            format!(
+                "Query index {} out of range, log has {} entries",
+                self.index,
+                log.queries.len()
+            )
+        })?;
+        let request = match row.query() {
+            Query::StorageRpc(StorageRpc::ReadFilter(request)) => request.clone(),
+            Query::Sql(_) => return Err(format!("Query {} is not a Storage RPC", self.index)),
+        };
+
+        if !self.fails(&request, connector).await? {
+            return Err(format!("Query {} does not fail", self.index));
+        }
+
+        let (request, num_reductions) = reduce(request, |candidate| async move {
+            self.fails(&candidate, connector).await
+        })
+        .await?;
+
+        println!("Applied {} reductions", num_reductions);
+        if let Some(root) = request.predicate.as_ref().and_then(|p| p.root.as_ref()) {
+            println!("predicate: {}", NodeDisplay::new(root));
+        }
+        println!(
+            "{}",
+            Query::StorageRpc(StorageRpc::ReadFilter(request)).query_text()?
+        );
+        Ok(())
+    }
+
+    /// Does `request` still exhibit the failure?
+    async fn fails(&self, request: &ReadFilterRequest, connector: &Connector) -> Result<bool> {
+        // reconnect each time, in case the last attempt crashed the server
+        let connection = connector.reconnect().await?;
+        let query = Query::StorageRpc(StorageRpc::ReadFilter(request.clone()));
+        let result = query.replay_with_output(&self.db, connection).await;
+
+        Ok(match (result, self.slower_than_ms) {
+            (Ok((execution, _)), Some(ms)) => execution.duration > Duration::from_millis(ms),
+            (Ok(_), None) => false,
+            (Err(e), None) => match &self.error_containing {
+                Some(text) => e.contains(text.as_str()),
+                None => true,
+            },
+            (Err(_), Some(_)) => false,
+        })
+    }
+}
+
+/// Greedily apply the first reduction of `request` for which `fails` is
+/// true, until there are none. Returns the reduced request and the
+/// number of reductions applied.
+///
+/// Every reduction makes the predicate strictly smaller or the time
+/// range strictly narrower, so this terminates even if the failure
+/// does not depend on what is reduced
+async fn reduce<F, Fut>(
+    mut request: ReadFilterRequest,
+    mut fails: F,
+) -> Result<(ReadFilterRequest, usize)>
+where
+    F: FnMut(ReadFilterRequest) -> Fut,
+    Fut: Future<Output = Result<bool>>,
+{
+    let mut num_reductions = 0;
+    'reduce: loop {
+        for (description, candidate) in reductions(&request) {
+            if fails(candidate.clone()).await? {
+                println!("still fails after {}", description);
+                request = candidate;
+                num_reductions += 1;
+                continue 'reduce;
+            }
+        }
+        return Ok((request, num_reductions));
+    }
+}
+
+/// Return the requests that are one step smaller than `request`, largest
+/// reductions first
+fn reductions(request: &ReadFilterRequest) -> Vec<(String, ReadFilterRequest)> {
+    let mut reductions = vec![];
+
+    if let Some(root) = request.predicate.as_ref().and_then(|p| p.root.as_ref()) {
+        reductions.push((
+            "removing the predicate".to_string(),
+            ReadFilterRequest {
+                predicate: None,
+                ..request.clone()
+            },
+        ));
+
+        let mut paths = vec![];
+        collect_paths(root, &mut vec![], &mut paths);
+        for path in &paths {
+            for (description, node) in node_reductions(node_at(root, path)) {
+                let mut candidate = request.clone();
+                let root = candidate
+                    .predicate
+                    .as_mut()
+                    .and_then(|p| p.root.as_mut())
+                    .expect("predicate has a root");
+                *node_at_mut(root, path) = node;
+                reductions.push((description, candidate));
+            }
+        }
+    }
+
+    if let Some(range) = &request.range {
+        if let Some(width) = range.end.checked_sub(range.start).filter(|w| *w >= 2) {
+            let mid = range.start + width / 2;
+            for (start, end) in [(range.start, mid), (mid, range.end)] {
+                reductions.push((
+                    format!("narrowing the time range to [{}, {})", start, end),
+                    ReadFilterRequest {
+                        range: Some(TimestampRange { start, end }),
+                        ..request.clone()
+                    },
+                ));
+            }
+        }
+    }
+
+    reductions
+}
+
+/// Return the nodes that could replace `node`, each with a description
+fn node_reductions(node: &Node) -> Vec<(String, Node)> {
+    let mut reductions = vec![];
+    match (Type::from_i32(node.node_type), &node.value) {
+        (Some(Type::LogicalExpression), Some(Value::Logical(l))) if node.children.len() >= 2 => {
+            let op = if *l == Logical::Or as i32 {
+                "OR"
+            } else {
+                "AND"
+            };
+            for (i, child) in node.children.iter().enumerate() {
+                let mut children = node.children.clone();
+                children.remove(i);
+                let reduced = if children.len() == 1 {
+                    children.pop().expect("one child")
+                } else {
+                    Node {
+                        children,
+                        ..node.clone()
+                    }
+                };
+                reductions.push((
+                    format!("dropping {} from {}", NodeDisplay::new(child), op),
+                    reduced,
+                ));
+            }
+        }
+        (Some(Type::ParenExpression), _) if node.children.len() == 1 => {
+            reductions.push(("removing parentheses".to_string(), node.children[0].clone()));
+        }
+        (Some(Type::Literal), Some(Value::RegexValue(regex))) => {
+            // only strictly shorter regexes, so simplifying ends
+            let len = regex.chars().count();
+            let mut simpler = vec![];
+            if len > 2 {
+                simpler.push(".*".to_string());
+            }
+            if len > 0 {
+                let mut shorter = regex.clone();
+                shorter.pop();
+                simpler.push(regex.chars().take(len / 2).collect());
+                simpler.push(shorter);
+            }
+            simpler.dedup();
+            for candidate in simpler {
+                reductions.push((
+                    format!("simplifying regex /{}/ to /{}/", regex, candidate),
+                    Node {
+                        value: Some(Value::RegexValue(candidate)),
+                        ..node.clone()
+                    },
+                ));
+            }
+        }
+        _ => {}
+    }
+    reductions
+}
+
+/// Append the path (child indices from the root) of `node` and all of
+/// its descendants to `paths`
+fn collect_paths(node: &Node, path: &mut Vec<usize>, paths: &mut Vec<Vec<usize>>) {
+    paths.push(path.clone());
+    for (i, child) in node.children.iter().enumerate() {
+        path.push(i);
+        collect_paths(child, path, paths);
+        path.pop();
+    }
+}
+
+fn node_at<'a>(root: &'a Node, path: &[usize]) -> &'a Node {
+    path.iter().fold(root, |node, &i| &node.children[i])
+}
+
+fn node_at_mut<'a>(root: &'a mut Node, path: &[usize]) -> &'a mut Node {
+    path.iter().fold(root, |node, &i| &mut node.children[i])
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use generated_types::influxdata::platform::storage::{node::Comparison, Predicate};
+
+    use super::*;
+    use crate::predicate::{and, tag_comparison, TAG_KEY_MEASUREMENT};
+
+    fn request() -> ReadFilterRequest {
+        ReadFilterRequest {
+            range: Some(TimestampRange {
+                start: 0,
+                end: 1_000_000,
+            }),
+            predicate: Some(Predicate {
+                root: and(vec![
+                    tag_comparison(
+                        TAG_KEY_MEASUREMENT,
+                        Comparison::Equal,
+                        Value::StringValue("cpu".to_string()),
+                    ),
+                    tag_comparison(
+                        b"host",
+                        Comparison::Regex,
+                        Value::RegexValue("^server[0-9]+$".to_string()),
+                    ),
+                ]),
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn regexes(node: &Node, found: &mut Vec<String>) {
+        if let Some(Value::RegexValue(regex)) = &node.value {
+            found.push(regex.clone());
+        }
+        for child in &node.children {
+            regexes(child, found);
+        }
+    }
+
+    /// Reduce `request()` while `fails` is true for the candidate
+    fn reduce_while(fails: impl Fn(&ReadFilterRequest) -> bool) -> (ReadFilterRequest, usize) {
+        block_on(reduce(request(), |candidate| {
+            let failed = fails(&candidate);
+            async move { Ok(failed) }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn always_failing_reaches_a_fixpoint() {
+        let (reduced, num_reductions) = reduce_while(|_| true);
+        assert!(reduced.predicate.is_none());
+        assert_eq!(reduced.range, Some(TimestampRange { start: 0, end: 1 }));
+        assert!(num_reductions < 30, "{} reductions", num_reductions);
+    }
+
+    #[test]
+    fn regex_independent_failure_reaches_a_fixpoint() {
+        // fails whenever there is a regex, whatever it is
+        let has_regex = |request: &ReadFilterRequest| {
+            let mut found = vec![];
+            if let Some(root) = request.predicate.as_ref().and_then(|p| p.root.as_ref()) {
+                regexes(root, &mut found);
+            }
+            !found.is_empty()
+        };
+        let (reduced, num_reductions) = reduce_while(has_regex);
+
+        let root = reduced.predicate.unwrap().root.unwrap();
+        let mut found = vec![];
+        regexes(&root, &mut found);
+        assert_eq!(found, vec![""]);
+        assert_eq!(root.children.len(), 2);
+        assert!(num_reductions < 40, "{} reductions", num_reductions);
+    }
+
+    #[test]
+    fn regex_reductions_are_strictly_shorter() {
+        for regex in ["", ".", ".*", "a.*", "^server[0-9]+$"] {
+            let node = Node {
+                node_type: Type::Literal as i32,
+                children: vec![],
+                value: Some(Value::RegexValue(regex.to_string())),
+            };
+            for (_, reduced) in node_reductions(&node) {
+                match reduced.value {
+                    Some(Value::RegexValue(r)) => {
+                        assert!(
+                            r.chars().count() < regex.chars().count(),
+                            "{} -> {}",
+                            regex,
+                            r
+                        )
+                    }
+                    other => panic!("unexpected {:?}", other),
+                }
+            }
+        }
+    }
+}
@@ -1,17 +1,78 @@
-use std::{path::Path, time::Duration};
+use std::{collections::HashSet, path::Path, time::Duration};
 
 use structopt::StructOpt;
 
 use influxdb_iox_client::connection::Connection;
 
 use crate::{
-    query::{QueryExecutionSummary, QueryExecutionSummaryBuilder},
+    coverage::check_coverage,
+    fingerprint::FingerprintSummaries,
+    query::{
+        Query, QueryExecution, QueryExecutionSummary, QueryExecutionSummaryBuilder, QueryOutput,
+        StorageRpc,
+    },
     query_log::QueryLog,
+    read_source::{tenant, ReadSourceOptions, Tenant, TenantMap, TenantSummaries},
+    results::ResultStore,
+    rewrite::RewriteRules,
+    schema::check_schema,
+    stats::relative_standard_error,
+    time_shift::TimeShift,
+    to_sql::read_filter_to_sql,
+    util::Connector,
+    verify::{compare, row_order, Table},
 };
 
 pub type Result<T, E = String> = std::result::Result<T, E>;
 
-const TEST_DURATION_SECS: u64 = 5;
+/// When to stop running a query repeatedly.
+///
+/// By default each query runs for `--max-duration-secs`. With
+/// `--target-relative-error`, a query stops as soon as its mean latency
+/// is known precisely enough, so stable queries finish quickly and noisy
+/// ones get more samples.
+#[derive(Debug, Clone, Copy, StructOpt)]
+pub struct Iterations {
+    /// Stop running a query once the relative standard error of its mean
+    /// latency (standard deviation / (mean * sqrt(iterations))) is below
+    /// this target, such as `0.01`
+    #[structopt(long)]
+    target_relative_error: Option<f64>,
+
+    /// The minimum number of times to run each query before checking
+    /// `--target-relative-error`
+    #[structopt(long, default_value = "5")]
+    min_iterations: usize,
+
+    /// The maximum number of times to run each query. Unlimited by
+    /// default
+    #[structopt(long)]
+    max_iterations: Option<usize>,
+
+    /// The maximum time, in seconds, to spend running each query
+    #[structopt(long, default_value = "5")]
+    max_duration_secs: u64,
+}
+
+impl Iterations {
+    /// Should the query summarized by `summary` be run again?
+    pub fn should_continue(&self, summary: &QueryExecutionSummaryBuilder) -> bool {
+        let max_iterations = self.max_iterations.unwrap_or(usize::MAX);
+        if summary.count() >= max_iterations
+            || summary.total_duration() >= Duration::from_secs(self.max_duration_secs)
+        {
+            return false;
+        }
+
+        match self.target_relative_error {
+            Some(target) if summary.count() >= self.min_iterations => {
+                let samples: Vec<f64> = summary.samples().iter().map(|d| d.as_secs_f64()).collect();
+                relative_standard_error(&samples) > target
+            }
+            _ => true,
+        }
+    }
+}
 
 /// Replay the contents of previously saved queries from a file back to a databse
 #[derive(Debug, StructOpt)]
@@ -21,10 +82,82 @@ pub struct Replay {
 
     /// The filename to replay the queries to
     filename: String,
+
+    /// Also replay SQL queries of system tables (`system.*`,
+    /// `information_schema` and `SHOW`), which are skipped by default
+    /// as they do not measure the user workload
+    #[structopt(long)]
+    include_system_queries: bool,
+
+    /// Only replay the first query with each distinct fingerprint
+    /// (queries that differ only in their literals and time ranges)
+    #[structopt(long)]
+    one_per_fingerprint: bool,
+
+    /// The number of query fingerprints to report, slowest first
+    #[structopt(long, default_value = "10")]
+    top: usize,
+
+    /// Parse every query and print exactly what would be sent, without
+    /// connecting to a server
+    #[structopt(long)]
+    dry_run: bool,
+
+    /// Also run the SQL equivalent of each Storage RPC request, and
+    /// report it alongside the original. The SQL equivalents are
+    /// summarized separately from the logged queries
+    #[structopt(long)]
+    with_sql: bool,
+
+    /// Save the data returned by each query into this directory, for
+    /// later comparison
+    #[structopt(long)]
+    save_results: Option<String>,
+
+    /// Compare the data returned by each query against the results
+    /// previously saved into this directory with `--save-results`,
+    /// failing if any differ
+    #[structopt(long)]
+    verify: Option<String>,
+
+    /// The relative difference allowed between floating point values
+    /// when verifying results
+    #[structopt(long, default_value = "1e-9")]
+    float_tolerance: f64,
+
+    #[structopt(flatten)]
+    iterations: Iterations,
+
+    #[structopt(flatten)]
+    time_shift: TimeShift,
+
+    /// Rewrite SQL queries with the rules in this JSON file before
+    /// replaying them (see `RewriteRules`)
+    #[structopt(long)]
+    rewrite_rules: Option<String>,
+
+    #[structopt(flatten)]
+    read_source: ReadSourceOptions,
+
+    /// Replay each Storage RPC request into the database its original
+    /// org and bucket map to in this JSON file, reporting statistics per
+    /// tenant. Other queries are replayed into `db`
+    #[structopt(long)]
+    tenant_map: Option<String>,
+
+    /// Before replaying, check that the database has data where each
+    /// query looks for it (see the `coverage` subcommand)
+    #[structopt(long)]
+    check_coverage: bool,
+
+    /// Before replaying, check that the tables and columns referenced
+    /// by each query exist (see the `schema-check` subcommand)
+    #[structopt(long)]
+    check_schema: bool,
 }
 
 impl Replay {
-    pub async fn execute(&self, connection: Connection) -> Result<()> {
+    pub async fn execute(&self, connector: &Connector) -> Result<()> {
         println!(
             "Replaying from {} into database {}...",
             self.db, self.filename
@@ -35,26 +168,431 @@ impl Replay {
 
         println!("Loaded query log with {} entries", log.queries.len());
 
+        // the tenant of each query comes from its original read source,
+        // before any rewriting
+        let tenant_map = self
+            .tenant_map
+            .as_ref()
+            .map(|path| TenantMap::new_from_file(Path::new(path)))
+            .transpose()?;
+        let mut queries: Vec<LoggedQuery> = log
+            .queries
+            .into_iter()
+            .enumerate()
+            .map(|(index, row)| {
+                let query = row.into_inner();
+                let tenant = tenant(&query);
+                let database = tenant_map
+                    .as_ref()
+                    .and_then(|map| map.database(tenant))
+                    .unwrap_or(&self.db)
+                    .to_string();
+                LoggedQuery {
+                    index,
+                    tenant,
+                    database,
+                    query,
+                }
+            })
+            .collect();
+        if !self.include_system_queries {
+            let num_queries = queries.len();
+            queries.retain(|logged| !logged.query.is_system());
+            println!(
+                "Skipping {} queries of system tables (use --include-system-queries to replay them)",
+                num_queries - queries.len()
+            );
+        }
+        let mut queries = map_queries(queries, |queries| self.time_shift.apply(queries))?;
+        if let Some(path) = &self.rewrite_rules {
+            let rules = RewriteRules::new_from_file(Path::new(path))?;
+            let num_queries = queries.len();
+            queries = queries
+                .into_iter()
+                .filter_map(|logged| {
+                    let query = rules.rewrite(logged.query)?;
+                    Some(LoggedQuery { query, ..logged })
+                })
+                .collect();
+            println!(
+                "Rewrote SQL queries, skipping {} of {} queries",
+                num_queries - queries.len(),
+                num_queries
+            );
+        }
+        let mut queries =
+            map_queries(queries, |queries| self.read_source.apply(queries, &self.db))?;
+        if let Some(map) = &tenant_map {
+            // databases not named after an org and bucket have them given
+            // in the map
+            queries = queries
+                .into_iter()
+                .map(|logged| match map.read_source(logged.tenant) {
+                    Some((org_id, bucket_id)) => LoggedQuery {
+                        query: logged.query.with_read_source(org_id, bucket_id),
+                        ..logged
+                    },
+                    None => logged,
+                })
+                .collect();
+        }
+        if self.one_per_fingerprint {
+            let mut seen = HashSet::new();
+            queries.retain(|logged| seen.insert(logged.query.fingerprint()));
+            println!(
+                "Replaying {} queries with distinct fingerprints",
+                queries.len()
+            );
+        }
+
+        if self.dry_run {
+            return self.print_dry_run(queries);
+        }
+
+        let mut tenants = TenantSummaries::new();
+
         // now execute the queries against the specified database and connection
-        println!("description,{}", QueryExecutionSummary::header());
-        for (i, query) in log.queries.into_iter().map(|r| r.into_inner()).enumerate() {
-            let description = query.to_string();
-            let mut summary = QueryExecutionSummaryBuilder::new();
-            while summary.total_duration() < Duration::from_secs(TEST_DURATION_SECS) {
-                let execution = query.clone().replay(&self.db, connection.clone()).await?;
-                //println!("Ran {}: {}", description, execution);
-                summary = summary.add(execution);
+        let connection = connector.connect().await?;
+        if self.check_coverage || self.check_schema {
+            // each query is checked against the database it is routed to
+            let routed: Vec<_> = queries
+                .iter()
+                .map(|l| (l.index, l.database.clone(), l.query.clone()))
+                .collect();
+            if self.check_coverage {
+                check_coverage(&routed, &connection).await?;
             }
-            let summary = summary.build();
-            let description = if description.len() > 10 {
-                &description.as_str()[0..10]
-            } else {
-                description.as_str()
-            };
+            if self.check_schema {
+                check_schema(&routed, &connection).await?;
+            }
+        }
 
-            println!("query {}: {},{}", i, description, summary);
+        let mut shapes = FingerprintSummaries::new();
+        let mut sql_shapes = FingerprintSummaries::new();
+        println!(
+            "description,fingerprint,{}",
+            QueryExecutionSummary::header()
+        );
+        let mut results = self
+            .save_results
+            .as_ref()
+            .map(ResultStore::try_new)
+            .transpose()?;
+        let expected = self.verify.as_ref().map(ResultStore::open).transpose()?;
+        let mut num_mismatches = 0;
+        for logged in queries {
+            // queries are numbered by their position in the log, as in `show`
+            let LoggedQuery {
+                index: i,
+                tenant,
+                database,
+                query,
+            } = logged;
+            tenants.add_query(tenant, &database);
+            let (summary, output) = self
+                .measure(
+                    &query,
+                    tenant,
+                    &database,
+                    &connection,
+                    &mut shapes,
+                    &mut tenants,
+                )
+                .await?;
+            if let Some(results) = results.as_mut() {
+                results.save(i, &query, &output)?;
+            }
+            if let Some(expected) = expected.as_ref() {
+                if !self.verify_output(i, &query, &output, expected)? {
+                    num_mismatches += 1;
+                }
+            }
+            println!(
+                "query {}: {},{},{}",
+                i,
+                short_description(&query),
+                query.fingerprint(),
+                summary
+            );
+
+            if let (true, Query::StorageRpc(StorageRpc::ReadFilter(request))) =
+                (self.with_sql, &query)
+            {
+                match read_filter_to_sql(request) {
+                    Ok(sql) => {
+                        // summarized apart from the logged queries, so the
+                        // top queries compare like with like
+                        let query = Query::Sql(sql);
+                        let fingerprint = query.fingerprint();
+                        sql_shapes.add_query(&fingerprint);
+                        let (summary, _) = measure(
+                            &query,
+                            &database,
+                            &connection,
+                            &self.iterations,
+                            |execution| sql_shapes.add_execution(&fingerprint, execution.clone()),
+                        )
+                        .await?;
+                        println!(
+                            "query {} as sql: {},{},{}",
+                            i,
+                            short_description(&query),
+                            query.fingerprint(),
+                            summary
+                        );
+                    }
+                    Err(e) => println!("query {} as sql: can not convert: {}", i, e),
+                }
+            }
         }
 
+        if let Some(results) = results {
+            results.finish();
+        }
+        shapes.print_top(self.top);
+        if self.with_sql {
+            println!("SQL equivalents of Storage RPC requests:");
+            sql_shapes.print_top(self.top);
+        }
+        if tenant_map.is_some() {
+            tenants.print();
+        }
+
+        if num_mismatches > 0 {
+            return Err(format!(
+                "{} queries returned different results than those in {}",
+                num_mismatches,
+                self.verify.as_deref().unwrap_or_default()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Compare `output` of `query` against the `expected` saved
+    /// results, printing any differences. Returns true if they match
+    fn verify_output(
+        &self,
+        index: usize,
+        query: &Query,
+        output: &QueryOutput,
+        expected: &ResultStore,
+    ) -> Result<bool> {
+        let expected = match expected.load(index, query) {
+            Ok(expected) => Table::try_new(&expected)?,
+            Err(e) => {
+                println!("query {}: MISMATCH no saved result: {}", index, e);
+                return Ok(false);
+            }
+        };
+        let actual = Table::try_new(output)?;
+
+        let differences = compare(&expected, &actual, &row_order(query), self.float_tolerance);
+        if differences.is_empty() {
+            return Ok(true);
+        }
+
+        println!("query {}: MISMATCH {}", index, query);
+        for difference in differences {
+            println!("  {}", difference);
+        }
+        Ok(false)
+    }
+
+    /// Run `query` against `database_name` repeatedly, also recording
+    /// each execution in `shapes` and `tenants`
+    async fn measure(
+        &self,
+        query: &Query,
+        tenant: Tenant,
+        database_name: &str,
+        connection: &Connection,
+        shapes: &mut FingerprintSummaries,
+        tenants: &mut TenantSummaries,
+    ) -> Result<(QueryExecutionSummary, QueryOutput)> {
+        let fingerprint = query.fingerprint();
+        shapes.add_query(&fingerprint);
+
+        measure(
+            query,
+            database_name,
+            connection,
+            &self.iterations,
+            |execution| {
+                shapes.add_execution(&fingerprint, execution.clone());
+                tenants.add_execution(tenant, execution.clone());
+            },
+        )
+        .await
+    }
+
+    /// Print each query as it would be sent to the database
+    fn print_dry_run(&self, queries: Vec<LoggedQuery>) -> Result<()> {
+        let mut num_errors = 0;
+        for logged in queries {
+            let LoggedQuery {
+                index: i,
+                database,
+                query,
+                ..
+            } = logged;
+            let query_type = query.query_type();
+            match query.for_database(&database).and_then(|q| q.query_text()) {
+                Ok(text) => println!("query {} ({}) into {}: {}", i, query_type, database, text),
+                Err(e) => {
+                    println!("query {} ({}): ERROR {}", i, query_type, e);
+                    num_errors += 1;
+                }
+            }
+        }
+
+        if num_errors > 0 {
+            return Err(format!("{} queries could not be prepared", num_errors));
+        }
         Ok(())
     }
 }
+
+/// A query of the log and where it is replayed
+#[derive(Debug)]
+struct LoggedQuery {
+    /// The position of the query in the log
+    index: usize,
+    /// The org and bucket a Storage RPC request was originally sent to
+    tenant: Tenant,
+    /// The database to replay the query into
+    database: String,
+    query: Query,
+}
+
+/// Replace the queries of `queries` by `f`, which returns one query for
+/// each query it is given
+fn map_queries(
+    queries: Vec<LoggedQuery>,
+    f: impl FnOnce(Vec<Query>) -> Result<Vec<Query>>,
+) -> Result<Vec<LoggedQuery>> {
+    let (targets, plain): (Vec<_>, Vec<_>) = queries
+        .into_iter()
+        .map(|l| ((l.index, l.tenant, l.database), l.query))
+        .unzip();
+    let mapped = f(plain)?;
+    assert_eq!(mapped.len(), targets.len(), "one query for each query");
+    Ok(targets
+        .into_iter()
+        .zip(mapped)
+        .map(|((index, tenant, database), query)| LoggedQuery {
+            index,
+            tenant,
+            database,
+            query,
+        })
+        .collect())
+}
+
+/// Return the start of the description of `query`
+fn short_description(query: &Query) -> String {
+    let description = query.to_string();
+    if description.len() > 10 {
+        description[0..10].to_string()
+    } else {
+        description
+    }
+}
+
+/// Run `query` against `database_name` repeatedly until `iterations`
+/// says to stop, calling `on_execution` with each execution. Returns
+/// the summary of the executions and the output of the first one
+pub async fn measure(
+    query: &Query,
+    database_name: &str,
+    connection: &Connection,
+    iterations: &Iterations,
+    mut on_execution: impl FnMut(&QueryExecution),
+) -> Result<(QueryExecutionSummary, QueryOutput)> {
+    let mut summary = QueryExecutionSummaryBuilder::new();
+    let mut first_output = None;
+    while first_output.is_none() || iterations.should_continue(&summary) {
+        let (execution, output) = query
+            .clone()
+            .replay_with_output(database_name, connection.clone())
+            .await?;
+        //println!("Ran {}: {}", query, execution);
+        if first_output.is_none() {
+            first_output = Some(output);
+        }
+        on_execution(&execution);
+        summary = summary.add(execution);
+    }
+    Ok((
+        summary.build(),
+        first_output.expect("query ran at least once"),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use generated_types::influxdata::platform::storage::ReadFilterRequest;
+
+    use super::*;
+    use crate::{query::StorageRpc, query_log::QueryRow};
+
+    /// Dry run `queries` into `db` with the options `args`, with a
+    /// connector that can not connect
+    async fn dry_run(name: &str, db: &str, args: &[&str], queries: Vec<Query>) -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("replay_{}_{}.json", name, std::process::id()));
+        let queries = queries
+            .into_iter()
+            .map(|query| QueryRow::new("2021-12-16 15:06:22", query))
+            .collect();
+        QueryLog { queries }.write_to_file(&path)?;
+
+        let replay = Replay::from_iter(
+            ["replay", db, path.to_str().unwrap(), "--dry-run"]
+                .into_iter()
+                .chain(args.iter().copied()),
+        );
+        let result = replay
+            .execute(&Connector::new("http://unused.invalid:1"))
+            .await;
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    fn read_filter() -> Query {
+        Query::StorageRpc(StorageRpc::ReadFilter(ReadFilterRequest::default()))
+    }
+
+    #[tokio::test]
+    async fn dry_run_does_not_connect() {
+        let queries = vec![Query::Sql("SELECT * FROM cpu".to_string()), read_filter()];
+        dry_run(
+            "no_connect",
+            "0000000000000001_0000000000000002",
+            &[],
+            queries,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn dry_run_checks_read_source_options() {
+        let queries = || vec![Query::Sql("SELECT * FROM cpu".to_string()), read_filter()];
+        // requests keep their original read source for other databases,
+        // unless one is given
+        dry_run("original", "mydb", &[], queries()).await.unwrap();
+        let ids = ["--org-id", "1", "--bucket-id", "2"];
+        dry_run("explicit", "mydb", &ids, queries()).await.unwrap();
+
+        let err = dry_run(
+            "conflicting",
+            "0000000000000001_0000000000000002",
+            &ids,
+            queries(),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.contains("can not be used with"), "{}", err);
+        assert!(dry_run("half", "mydb", &ids[..2], queries()).await.is_err());
+    }
+}
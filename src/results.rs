@@ -0,0 +1,190 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use arrow::{
+    datatypes::Schema,
+    ipc::{reader::FileReader, writer::FileWriter},
+    record_batch::RecordBatch,
+};
+use generated_types::influxdata::platform::storage::{read_response::Frame, ReadResponse};
+use prost::Message;
+use serde_json::{json, Value};
+
+use crate::{
+    error::StringifyError,
+    query::{Query, QueryOutput},
+};
+
+pub type Result<T, E = String> = std::result::Result<T, E>;
+
+/// The name of the file describing the saved results in a directory
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// Saves the data returned by queries into a directory, one file per
+/// query, so they can be compared against later runs.
+///
+/// SQL results are stored as Arrow IPC files and Storage RPC results
+/// as an encoded `ReadResponse` containing all returned frames.
+#[derive(Debug)]
+pub struct ResultStore {
+    dir: PathBuf,
+    manifest: Vec<Value>,
+}
+
+impl ResultStore {
+    /// Create a store that saves results into `dir`, creating it if needed
+    pub fn try_new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).context(&format!("Creating directory {:?}", dir))?;
+        let store = Self {
+            dir,
+            manifest: vec![],
+        };
+        store.write_manifest()?;
+        Ok(store)
+    }
+
+    /// Open a directory of previously saved results
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        if !dir.join(MANIFEST_FILE_NAME).exists() {
+            return Err(format!("No saved results found in {:?}", dir));
+        }
+        Ok(Self {
+            dir,
+            manifest: vec![],
+        })
+    }
+
+    /// Load the previously saved output of `query`, the query at
+    /// `index` in its log
+    pub fn load(&self, index: usize, query: &Query) -> Result<QueryOutput> {
+        let path = self.dir.join(file_name(index, query));
+        match query {
+            Query::Sql(_) => read_batches(&path).map(QueryOutput::Sql),
+            Query::StorageRpc(_) => read_frames(&path).map(QueryOutput::StorageRpc),
+        }
+    }
+
+    /// Save the `output` of `query`, the query at `index` in its log
+    pub fn save(&mut self, index: usize, query: &Query, output: &QueryOutput) -> Result<()> {
+        let file_name = file_name(index, query);
+        let path = self.dir.join(&file_name);
+
+        match output {
+            QueryOutput::Sql(batches) => write_batches(&path, batches)?,
+            QueryOutput::StorageRpc(frames) => write_frames(&path, frames)?,
+        }
+
+        self.manifest.push(json!({
+            "index": index,
+            "fingerprint": query.fingerprint().id(),
+            "query_type": query.query_type(),
+            "query_text": query.query_text()?,
+            "file": file_name,
+        }));
+        self.write_manifest()
+    }
+
+    /// Report the saved results. The manifest is already up to date
+    pub fn finish(self) {
+        println!("Saved {} results to {:?}", self.manifest.len(), self.dir);
+    }
+
+    /// Write the manifest describing the results saved so far, so that
+    /// they can be used even if the replay does not complete
+    fn write_manifest(&self) -> Result<()> {
+        let path = self.dir.join(MANIFEST_FILE_NAME);
+        let file = File::create(&path).context(&format!("Creating file {:?}", path))?;
+        let mut file = BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut file, &self.manifest)
+            .context("Writing results manifest")?;
+        file.flush().context("Flushing results manifest")
+    }
+}
+
+/// Return the name of the file that stores the results of `query`,
+/// the query at `index` in its log
+fn file_name(index: usize, query: &Query) -> String {
+    let extension = match query {
+        Query::Sql(_) => "arrow",
+        Query::StorageRpc(_) => "frames",
+    };
+    format!("{:06}_{}.{}", index, query.fingerprint().id(), extension)
+}
+
+fn write_batches(path: &Path, batches: &[RecordBatch]) -> Result<()> {
+    let schema = batches
+        .first()
+        .map(|batch| batch.schema())
+        .unwrap_or_else(|| Arc::new(Schema::empty()));
+
+    let file = File::create(path).context(&format!("Creating file {:?}", path))?;
+    let mut writer =
+        FileWriter::try_new(BufWriter::new(file), &schema).context("Creating arrow writer")?;
+    for batch in batches {
+        writer.write(batch).context("Writing record batch")?;
+    }
+    writer.finish().context("Completing arrow file")
+}
+
+fn write_frames(path: &Path, frames: &[Frame]) -> Result<()> {
+    let response = ReadResponse {
+        frames: frames.to_vec(),
+    };
+    std::fs::write(path, response.encode_to_vec()).context(&format!("Writing file {:?}", path))
+}
+
+fn read_batches(path: &Path) -> Result<Vec<RecordBatch>> {
+    let file = File::open(path).context(&format!("Opening file {:?}", path))?;
+    let reader = FileReader::try_new(file).context("Creating arrow reader")?;
+    reader
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .context(&format!("Reading record batches from {:?}", path))
+}
+
+fn read_frames(path: &Path) -> Result<Vec<Frame>> {
+    let bytes = std::fs::read(path).context(&format!("Reading file {:?}", path))?;
+    let response =
+        ReadResponse::decode(&bytes[..]).context(&format!("Decoding frames from {:?}", path))?;
+    Ok(response.frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use generated_types::influxdata::platform::storage::ReadFilterRequest;
+
+    use super::*;
+    use crate::query::StorageRpc;
+
+    fn manifest(dir: &Path) -> Vec<Value> {
+        let file = File::open(dir.join(MANIFEST_FILE_NAME)).unwrap();
+        match serde_json::from_reader(file).unwrap() {
+            Value::Array(entries) => entries,
+            other => panic!("expected an array, got {}", other),
+        }
+    }
+
+    #[test]
+    fn manifest_is_written_as_results_are_saved() {
+        let dir = std::env::temp_dir().join(format!("results_{}", std::process::id()));
+        let mut store = ResultStore::try_new(&dir).unwrap();
+        assert!(manifest(&dir).is_empty());
+
+        let query = Query::StorageRpc(StorageRpc::ReadFilter(ReadFilterRequest::default()));
+        store
+            .save(3, &query, &QueryOutput::StorageRpc(vec![]))
+            .unwrap();
+        let entries = manifest(&dir);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["index"], 3);
+        assert_eq!(entries[0]["query_type"], "read_filter");
+        assert!(dir.join(file_name(3, &query)).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
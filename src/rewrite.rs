@@ -0,0 +1,557 @@
+use std::{collections::BTreeMap, fs::File, path::Path};
+
+use serde_json::Value;
+
+use crate::{
+    error::StringifyError,
+    query::Query,
+    sql::{is_system_query, tokenize, Token},
+    time::{format_timestamp, parse_timestamp},
+    time_shift::{reverse_comparison, time_comparisons},
+};
+
+pub type Result<T, E = String> = std::result::Result<T, E>;
+
+/// Clauses that end a `WHERE` clause
+const CLAUSES_AFTER_WHERE: &[&str] = &["group", "having", "order", "limit", "offset", "union"];
+
+/// Words that end a comma separated list of tables after `FROM`
+const WORDS_AFTER_FROM: &[&str] = &[
+    "where", "group", "having", "order", "limit", "offset", "union", "select", "join", "on",
+    "using",
+];
+
+/// Rules for rewriting logged SQL queries, which often reference tables
+/// or relative times that make no sense against a local mirror of the
+/// original database. Storage RPC requests are not rewritten.
+///
+/// The rules file is a JSON object in which every rule is optional, for
+/// example
+///
+/// ```json
+/// {
+///   "rename_tables": { "cpu": "cpu_copy" },
+///   "now": "2021-12-16T15:00:00Z",
+///   "time_range": { "start": "2021-12-16T14:00:00Z", "end": "2021-12-16T15:00:00Z" },
+///   "limit": 1000,
+///   "skip_system_tables": true
+/// }
+/// ```
+///
+/// * `rename_tables`: replace references to tables
+/// * `now`: replace `now()` with this fixed timestamp
+/// * `time_range`: replace the literals `time` is compared with so
+///   that the query selects `[start, end)`, or add that range to the
+///   `WHERE` clause of queries that do not compare `time` with literals
+/// * `limit`: add a `LIMIT` to queries without one, and lower any
+///   larger `LIMIT`
+/// * `skip_system_tables`: drop queries of `system.*`,
+///   `information_schema` and `SHOW`
+#[derive(Debug, Default)]
+pub struct RewriteRules {
+    rename_tables: BTreeMap<String, String>,
+    now: Option<i64>,
+    time_range: Option<(i64, i64)>,
+    limit: Option<u64>,
+    skip_system_tables: bool,
+}
+
+impl RewriteRules {
+    pub fn new_from_file(path: &Path) -> Result<Self> {
+        let file = File::open(path).context(&format!("Opening rewrite rules {:?}", path))?;
+        let value: Value = serde_json::from_reader(file).context("Parsing rewrite rules")?;
+        Self::try_from_json(value)
+    }
+
+    fn try_from_json(value: Value) -> Result<Self> {
+        let rules = match value {
+            Value::Object(rules) => rules,
+            _ => return Err(format!("Expected rewrite rules object, got {}", value)),
+        };
+
+        let string = |value: &Value, name: &str| -> Result<String> {
+            value
+                .as_str()
+                .map(|s| s.to_string())
+                .ok_or_else(|| format!("Expected a string for {}, got {}", name, value))
+        };
+
+        let mut parsed = Self::default();
+        for (name, value) in &rules {
+            match name.as_str() {
+                "rename_tables" => {
+                    let renames = value
+                        .as_object()
+                        .ok_or_else(|| format!("Expected an object for {}", name))?;
+                    for (from, to) in renames {
+                        parsed
+                            .rename_tables
+                            .insert(from.clone(), string(to, "rename_tables")?);
+                    }
+                }
+                "now" => parsed.now = Some(parse_timestamp(&string(value, name)?)?),
+                "time_range" => {
+                    let bound = |bound: &str| -> Result<i64> {
+                        let value = value
+                            .get(bound)
+                            .ok_or_else(|| format!("Missing {} in time_range", bound))?;
+                        parse_timestamp(&string(value, bound)?)
+                    };
+                    parsed.time_range = Some((bound("start")?, bound("end")?));
+                }
+                "limit" => {
+                    let limit = value.as_u64().ok_or_else(|| {
+                        format!("Expected a positive integer for limit, got {}", value)
+                    })?;
+                    parsed.limit = Some(limit);
+                }
+                "skip_system_tables" => {
+                    parsed.skip_system_tables = value
+                        .as_bool()
+                        .ok_or_else(|| format!("Expected a boolean for {}, got {}", name, value))?;
+                }
+                _ => return Err(format!("Unknown rewrite rule '{}'", name)),
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// Return `query` rewritten, or `None` if the rules skip it
+    pub fn rewrite(&self, query: Query) -> Option<Query> {
+        let sql = match query {
+            Query::Sql(sql) => sql,
+            Query::StorageRpc(_) => return Some(query),
+        };
+        if self.skip_system_tables && is_system_query(&sql) {
+            return None;
+        }
+
+        let mut tokens = tokenize(&sql);
+        if !self.rename_tables.is_empty() {
+            tokens = rename_tables(tokens, &self.rename_tables);
+        }
+        if let Some(now) = self.now {
+            tokens = replace_now(tokens, now);
+        }
+        if let Some(range) = self.time_range {
+            tokens = set_time_range(tokens, range);
+        }
+        if let Some(limit) = self.limit {
+            tokens = set_limit(tokens, limit);
+        }
+        Some(Query::Sql(tokens.iter().map(|t| t.to_string()).collect()))
+    }
+}
+
+/// Return the index of the first token after `i` that is not whitespace
+fn next_token(tokens: &[Token], i: usize) -> Option<usize> {
+    (i + 1..tokens.len()).find(|&j| !matches!(tokens[j], Token::Whitespace(_)))
+}
+
+/// Return the index of the whitespace before the token at `i`, if any,
+/// otherwise `i`
+fn before_whitespace(tokens: &[Token], mut i: usize) -> usize {
+    while i > 0 && matches!(tokens[i - 1], Token::Whitespace(_)) {
+        i -= 1;
+    }
+    i
+}
+
+/// Return the index of the trailing `;` of `tokens`, if any, otherwise
+/// the number of tokens
+fn statement_end(tokens: &[Token]) -> usize {
+    tokens
+        .iter()
+        .rposition(|t| !matches!(t, Token::Whitespace(_)))
+        .filter(|&i| tokens[i] == Token::Punct(";".into()))
+        .unwrap_or(tokens.len())
+}
+
+/// Return the index and lowercased text of each word that is not
+/// within parentheses
+fn top_level_words(tokens: &[Token]) -> Vec<(usize, String)> {
+    let mut words = vec![];
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::Punct(p) if p == "(" => depth += 1,
+            Token::Punct(p) if p == ")" => depth -= 1,
+            Token::Word(w) if depth == 0 => words.push((i, w.to_ascii_lowercase())),
+            _ => {}
+        }
+    }
+    words
+}
+
+/// Rename tables after `FROM` (including each of a comma separated
+/// list) or `JOIN`, and where they qualify columns
+fn rename_tables(mut tokens: Vec<Token>, renames: &BTreeMap<String, String>) -> Vec<Token> {
+    // for each enclosing parenthesis, whether it is within a list of
+    // tables after `FROM`
+    let mut in_from = vec![false];
+    let mut previous: Option<usize> = None;
+    for i in 0..tokens.len() {
+        let name = match &tokens[i] {
+            Token::Whitespace(_) => continue,
+            Token::Word(name) | Token::QuotedIdent(name) => name,
+            token => {
+                match token {
+                    Token::Punct(p) if p == "(" => in_from.push(false),
+                    Token::Punct(p) if p == ")" && in_from.len() > 1 => {
+                        in_from.pop();
+                    }
+                    _ => {}
+                }
+                previous = Some(i);
+                continue;
+            }
+        };
+        if let Token::Word(word) = &tokens[i] {
+            let word = word.to_ascii_lowercase();
+            let in_from = in_from.last_mut().expect("not empty");
+            if word == "from" {
+                *in_from = true;
+            } else if WORDS_AFTER_FROM.contains(&word.as_str()) {
+                *in_from = false;
+            }
+        }
+
+        let after_table_keyword = match previous.map(|p| &tokens[p]) {
+            Some(Token::Word(w)) => {
+                w.eq_ignore_ascii_case("from") || w.eq_ignore_ascii_case("join")
+            }
+            Some(Token::Punct(p)) => p == "," && in_from.last() == Some(&true),
+            _ => false,
+        };
+        let is_qualifier = matches!(
+            next_token(&tokens, i).map(|n| &tokens[n]),
+            Some(Token::Punct(p)) if p == "."
+        );
+        let is_qualified =
+            matches!(previous.map(|p| &tokens[p]), Some(Token::Punct(p)) if p == ".");
+
+        if (after_table_keyword || is_qualifier) && !is_qualified {
+            if let Some(renamed) = renames.get(name) {
+                tokens[i] = Token::QuotedIdent(renamed.clone());
+            }
+        }
+        previous = Some(i);
+    }
+    tokens
+}
+
+/// Replace each `now()` with the timestamp `now`
+fn replace_now(tokens: Vec<Token>, now: i64) -> Vec<Token> {
+    let mut rewritten = Vec::with_capacity(tokens.len());
+    let mut i = 0;
+    while i < tokens.len() {
+        let is_now = matches!(&tokens[i], Token::Word(w) if w.eq_ignore_ascii_case("now"));
+        let open = next_token(&tokens, i).filter(|&j| tokens[j] == Token::Punct("(".into()));
+        let close = open
+            .and_then(|j| next_token(&tokens, j))
+            .filter(|&j| tokens[j] == Token::Punct(")".into()));
+
+        match close {
+            Some(close) if is_now => {
+                rewritten.extend(tokenize(&format!(
+                    "to_timestamp({})",
+                    Token::StringLiteral(format_timestamp(now))
+                )));
+                i = close + 1;
+            }
+            _ => {
+                rewritten.push(tokens[i].clone());
+                i += 1;
+            }
+        }
+    }
+    rewritten
+}
+
+/// Make `tokens` select `[start, end)`: replace the literals `time` is
+/// compared with, or if there are none, add the range to the `WHERE`
+/// clause
+fn set_time_range(mut tokens: Vec<Token>, (start, end): (i64, i64)) -> Vec<Token> {
+    let mut num_replaced = 0;
+    for comparison in time_comparisons(&tokens) {
+        let (op, time) = match comparison.op {
+            ">" | ">=" => (">=", start),
+            "<" | "<=" if comparison.operator.is_some() => ("<", end),
+            // the upper bound of `BETWEEN` is inclusive
+            "<=" => ("<=", end.saturating_sub(1)),
+            _ => continue,
+        };
+        if let Some(operator) = comparison.operator {
+            let op = if comparison.reversed {
+                reverse_comparison(op)
+            } else {
+                op
+            };
+            tokens[operator] = Token::Punct(op.to_string());
+        }
+        let position = comparison.position;
+        tokens[position] = match &tokens[position] {
+            Token::Number(_) => Token::Number(time.to_string()),
+            _ => Token::StringLiteral(format_timestamp(time)),
+        };
+        num_replaced += 1;
+    }
+    if num_replaced > 0 {
+        return tokens;
+    }
+
+    let predicate = tokenize(&format!(
+        "time >= {} AND time < {}",
+        Token::StringLiteral(format_timestamp(start)),
+        Token::StringLiteral(format_timestamp(end))
+    ));
+    let words = top_level_words(&tokens);
+    let position = |word: &str| words.iter().find(|(_, w)| w == word).map(|(i, _)| *i);
+    // the end of the `WHERE` clause, before any whitespace
+    let where_end = |after: usize| {
+        let end = words
+            .iter()
+            .find(|(i, w)| *i > after && CLAUSES_AFTER_WHERE.contains(&w.as_str()))
+            .map(|(i, _)| *i)
+            .unwrap_or_else(|| statement_end(&tokens));
+        before_whitespace(&tokens, end)
+    };
+
+    let mut rewritten = vec![];
+    match (position("where"), position("from")) {
+        (Some(where_position), _) => {
+            // WHERE <range> AND (<original condition>)
+            let end = where_end(where_position);
+            let start = next_token(&tokens, where_position).unwrap_or(end).min(end);
+            rewritten.extend_from_slice(&tokens[..=where_position]);
+            rewritten.push(Token::Whitespace(" ".to_string()));
+            rewritten.extend(predicate);
+            rewritten.extend(tokenize(" AND ("));
+            rewritten.extend_from_slice(&tokens[start..end]);
+            rewritten.push(Token::Punct(")".to_string()));
+            rewritten.extend_from_slice(&tokens[end..]);
+        }
+        (None, Some(from_position)) => {
+            let end = where_end(from_position);
+            rewritten.extend_from_slice(&tokens[..end]);
+            rewritten.extend(tokenize(" WHERE "));
+            rewritten.extend(predicate);
+            rewritten.extend_from_slice(&tokens[end..]);
+        }
+        // no table to restrict
+        (None, None) => return tokens,
+    }
+    rewritten
+}
+
+/// Add `LIMIT limit` if there is no `LIMIT`, or lower a larger one
+fn set_limit(mut tokens: Vec<Token>, limit: u64) -> Vec<Token> {
+    let words = top_level_words(&tokens);
+
+    if let Some((i, _)) = words.iter().find(|(_, w)| w == "limit") {
+        if let Some(n) = next_token(&tokens, *i) {
+            if let Token::Number(existing) = &tokens[n] {
+                if matches!(existing.parse::<u64>(), Ok(existing) if existing > limit) {
+                    tokens[n] = Token::Number(limit.to_string());
+                }
+            }
+        }
+        return tokens;
+    }
+
+    // LIMIT goes before any OFFSET or trailing `;`
+    let position = words
+        .iter()
+        .find(|(_, w)| w == "offset")
+        .map(|(i, _)| *i)
+        .unwrap_or_else(|| statement_end(&tokens));
+
+    let rest = tokens.split_off(before_whitespace(&tokens, position));
+    tokens.extend(tokenize(&format!(" LIMIT {}", limit)));
+    tokens.extend(rest);
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn rewrite(rules: Value, sql: &str) -> String {
+        let rules = RewriteRules::try_from_json(rules).unwrap();
+        match rules.rewrite(Query::Sql(sql.to_string())) {
+            Some(Query::Sql(sql)) => sql,
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_rules_are_errors() {
+        assert!(RewriteRules::try_from_json(json!({ "limt": 5 })).is_err());
+        assert!(RewriteRules::try_from_json(json!({ "limit": -5 })).is_err());
+        assert!(RewriteRules::try_from_json(json!([])).is_err());
+    }
+
+    #[test]
+    fn renames_tables() {
+        let rules = json!({ "rename_tables": { "cpu": "cpu_copy" } });
+        assert_eq!(
+            rewrite(
+                rules.clone(),
+                "SELECT cpu.usage FROM cpu JOIN mem ON cpu.host = mem.host"
+            ),
+            r#"SELECT "cpu_copy".usage FROM "cpu_copy" JOIN mem ON "cpu_copy".host = mem.host"#
+        );
+        // columns and tables of other schemas keep their names
+        assert_eq!(
+            rewrite(rules.clone(), "SELECT cpu FROM system.cpu"),
+            "SELECT cpu FROM system.cpu"
+        );
+    }
+
+    #[test]
+    fn renames_each_table_of_a_list() {
+        let rules = json!({ "rename_tables": { "cpu": "cpu_copy", "mem": "mem_copy" } });
+        assert_eq!(
+            rewrite(
+                rules.clone(),
+                "SELECT * FROM cpu, mem WHERE cpu.host = mem.host"
+            ),
+            r#"SELECT * FROM "cpu_copy", "mem_copy" WHERE "cpu_copy".host = "mem_copy".host"#
+        );
+        assert_eq!(
+            rewrite(rules.clone(), "SELECT * FROM cpu AS c, mem m"),
+            r#"SELECT * FROM "cpu_copy" AS c, "mem_copy" m"#
+        );
+        assert_eq!(
+            rewrite(
+                rules.clone(),
+                "SELECT * FROM (SELECT cpu, mem FROM disk) AS d, mem"
+            ),
+            r#"SELECT * FROM (SELECT cpu, mem FROM disk) AS d, "mem_copy""#
+        );
+        // selected columns and function arguments are not tables
+        assert_eq!(
+            rewrite(rules, "SELECT cpu, mem FROM disk GROUP BY cpu, mem"),
+            "SELECT cpu, mem FROM disk GROUP BY cpu, mem"
+        );
+    }
+
+    #[test]
+    fn replaces_now() {
+        assert_eq!(
+            rewrite(
+                json!({ "now": "2021-12-16T15:00:00Z" }),
+                "SELECT * FROM cpu WHERE time > now() - interval '1 hour'"
+            ),
+            "SELECT * FROM cpu WHERE time > to_timestamp('2021-12-16T15:00:00Z') - interval '1 hour'"
+        );
+    }
+
+    #[test]
+    fn replaces_time_comparisons() {
+        let rules = json!({ "time_range": { "start": "1970-01-01T00:00:00.00000001Z", "end": "1970-01-01T00:00:00.00000002Z" } });
+        assert_eq!(
+            rewrite(
+                rules.clone(),
+                "SELECT * FROM cpu WHERE time > 1 AND time <= 2"
+            ),
+            "SELECT * FROM cpu WHERE time >= 10 AND time < 20"
+        );
+        assert_eq!(
+            rewrite(
+                rules.clone(),
+                "SELECT * FROM cpu WHERE 1 < time AND 2 >= time"
+            ),
+            "SELECT * FROM cpu WHERE 10 <= time AND 20 > time"
+        );
+        assert_eq!(
+            rewrite(rules, "SELECT * FROM cpu WHERE time BETWEEN 1 AND 2"),
+            "SELECT * FROM cpu WHERE time BETWEEN 10 AND 19"
+        );
+    }
+
+    #[test]
+    fn adds_time_range() {
+        let rules = json!({ "time_range": { "start": "2021-12-16T14:00:00Z", "end": "2021-12-16T15:00:00Z" } });
+        assert_eq!(
+            rewrite(
+                rules.clone(),
+                "SELECT host, count(*) FROM cpu GROUP BY host"
+            ),
+            "SELECT host, count(*) FROM cpu WHERE time >= '2021-12-16T14:00:00Z' \
+             AND time < '2021-12-16T15:00:00Z' GROUP BY host"
+        );
+        assert_eq!(
+            rewrite(
+                rules.clone(),
+                "SELECT * FROM cpu WHERE a = 1 OR b = 2 LIMIT 5"
+            ),
+            "SELECT * FROM cpu WHERE time >= '2021-12-16T14:00:00Z' \
+             AND time < '2021-12-16T15:00:00Z' AND (a = 1 OR b = 2) LIMIT 5"
+        );
+        assert_eq!(rewrite(rules, "SELECT 1"), "SELECT 1");
+    }
+
+    #[test]
+    fn adds_time_range_before_semicolon() {
+        let rules = json!({ "time_range": { "start": "2021-12-16T14:00:00Z", "end": "2021-12-16T15:00:00Z" } });
+        assert_eq!(
+            rewrite(rules.clone(), "select * from cpu where a = 1;"),
+            "select * from cpu where time >= '2021-12-16T14:00:00Z' \
+             AND time < '2021-12-16T15:00:00Z' AND (a = 1);"
+        );
+        assert_eq!(
+            rewrite(rules, "select * from cpu ;"),
+            "select * from cpu WHERE time >= '2021-12-16T14:00:00Z' \
+             AND time < '2021-12-16T15:00:00Z' ;"
+        );
+    }
+
+    #[test]
+    fn skips_system_tables() {
+        let rules = RewriteRules::try_from_json(json!({ "skip_system_tables": true })).unwrap();
+        for sql in [
+            "SELECT * FROM system.queries",
+            "select * from \"SYSTEM\".\"queries\"",
+            "SELECT * FROM Information_Schema.columns",
+            "show tables",
+        ] {
+            assert!(
+                rules.rewrite(Query::Sql(sql.to_string())).is_none(),
+                "{}",
+                sql
+            );
+        }
+        assert!(matches!(
+            rules.rewrite(Query::Sql("SELECT * FROM cpu".to_string())),
+            Some(Query::Sql(sql)) if sql == "SELECT * FROM cpu"
+        ));
+    }
+
+    #[test]
+    fn limits_queries() {
+        let rules = json!({ "limit": 100 });
+        assert_eq!(
+            rewrite(rules.clone(), "SELECT * FROM cpu"),
+            "SELECT * FROM cpu LIMIT 100"
+        );
+        assert_eq!(
+            rewrite(rules.clone(), "SELECT * FROM cpu LIMIT 1000"),
+            "SELECT * FROM cpu LIMIT 100"
+        );
+        assert_eq!(
+            rewrite(rules.clone(), "SELECT * FROM cpu LIMIT 10"),
+            "SELECT * FROM cpu LIMIT 10"
+        );
+        assert_eq!(
+            rewrite(rules.clone(), "SELECT * FROM cpu OFFSET 5;"),
+            "SELECT * FROM cpu LIMIT 100 OFFSET 5;"
+        );
+        assert_eq!(
+            rewrite(rules, "SELECT * FROM cpu;"),
+            "SELECT * FROM cpu LIMIT 100;"
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use std::path::Path;
+
+use structopt::StructOpt;
+
+use crate::{
+    error::StringifyError,
+    query::{Query, QueryExecutionSummary},
+    read_source::ReadSourceOptions,
+    replay::{measure, Iterations},
+    util::Connector,
+};
+
+pub type Result<T, E = String> = std::result::Result<T, E>;
+
+/// Run a single ad-hoc query repeatedly and summarize its performance,
+/// as `replay` does for each query of a log.
+///
+/// Exactly one of `--sql` or `--read-filter` gives the query.
+#[derive(Debug, StructOpt)]
+pub struct Run {
+    /// The database name to run the query against
+    db: String,
+
+    /// The SQL query to run
+    #[structopt(long)]
+    sql: Option<String>,
+
+    /// The read_filter request to run, as pbjson or the name of a file
+    /// containing it
+    #[structopt(long)]
+    read_filter: Option<String>,
+
+    #[structopt(flatten)]
+    iterations: Iterations,
+
+    #[structopt(flatten)]
+    read_source: ReadSourceOptions,
+}
+
+impl Run {
+    pub async fn execute(&self, connector: &Connector) -> Result<()> {
+        let query = self.query()?;
+
+        let connection = connector.connect().await?;
+        let (summary, _) = measure(&query, &self.db, &connection, &self.iterations, |_| {}).await?;
+
+        println!("fingerprint,{}", QueryExecutionSummary::header());
+        println!("{},{}", query.fingerprint(), summary);
+        Ok(())
+    }
+
+    /// Return the query given by `--sql` or `--read-filter`, sent to the
+    /// read source of the database
+    fn query(&self) -> Result<Query> {
+        let query = match (&self.sql, &self.read_filter) {
+            (Some(sql), None) => Query::try_new("sql", sql.as_str())?,
+            (None, Some(read_filter)) => {
+                let path = Path::new(read_filter);
+                let text = if path.is_file() {
+                    std::fs::read_to_string(path)
+                        .context(&format!("Reading read_filter request from {:?}", path))?
+                } else {
+                    read_filter.clone()
+                };
+                Query::try_new("read_filter", text)?
+            }
+            _ => return Err("Specify exactly one of --sql or --read-filter".to_string()),
+        };
+        Ok(self
+            .read_source
+            .apply(vec![query], &self.db)?
+            .pop()
+            .expect("one query"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use generated_types::influxdata::platform::storage::ReadFilterRequest;
+
+    use super::*;
+    use crate::{
+        query::StorageRpc,
+        read_source::{tenant, Tenant},
+    };
+
+    fn run(args: &[&str]) -> Run {
+        Run::from_iter(std::iter::once("run").chain(args.iter().copied()))
+    }
+
+    fn read_filter() -> String {
+        Query::StorageRpc(StorageRpc::ReadFilter(ReadFilterRequest::default()))
+            .query_text()
+            .unwrap()
+    }
+
+    #[test]
+    fn sql_query() {
+        let query = run(&["mydb", "--sql", "SELECT * FROM cpu"])
+            .query()
+            .unwrap();
+        assert!(matches!(query, Query::Sql(sql) if sql == "SELECT * FROM cpu"));
+    }
+
+    #[test]
+    fn read_filter_query() {
+        let query = run(&["mydb", "--read-filter", &read_filter()])
+            .query()
+            .unwrap();
+        assert_eq!(query.query_type(), "read_filter");
+    }
+
+    #[test]
+    fn read_filter_query_from_file() {
+        let path =
+            std::env::temp_dir().join(format!("run_read_filter_{}.json", std::process::id()));
+        std::fs::write(&path, read_filter()).unwrap();
+        let query = run(&["mydb", "--read-filter", path.to_str().unwrap()]).query();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(query.unwrap().query_type(), "read_filter");
+    }
+
+    #[test]
+    fn read_filter_sent_to_read_source() {
+        let read_filter = read_filter();
+        let query = run(&[
+            "mydb",
+            "--read-filter",
+            &read_filter,
+            "--org-id",
+            "1",
+            "--bucket-id",
+            "2",
+        ])
+        .query()
+        .unwrap();
+        assert!(matches!(tenant(&query), Tenant::ReadSource(1, 2)));
+
+        // the database name already determines the org and bucket
+        assert!(run(&[
+            "0000000000000003_0000000000000004",
+            "--read-filter",
+            &read_filter,
+            "--org-id",
+            "1",
+            "--bucket-id",
+            "2",
+        ])
+        .query()
+        .is_err());
+    }
+
+    #[test]
+    fn exactly_one_query() {
+        assert!(run(&["mydb"]).query().is_err());
+        assert!(
+            run(&["mydb", "--sql", "SELECT 1", "--read-filter", &read_filter()])
+                .query()
+                .is_err()
+        );
+        assert!(run(&["mydb", "--read-filter", "not a request"])
+            .query()
+            .is_err());
+    }
+}
@@ -33,7 +33,7 @@ impl Save {
         );
 
         let path = Path::new(&self.filename);
-        let file = File::create(&path).context(&format!("Creating file {:?}", path))?;
+        let file = File::create(path).context(&format!("Creating file {:?}", path))?;
         let mut file = BufWriter::new(file);
 
         let mut client = influxdb_iox_client::flight::Client::new(connection);
@@ -52,7 +52,6 @@ impl Save {
             .write_batches(&batches)
             .context("writing batches as json")?;
         writer.finish().context("completing json-ification")?;
-        std::mem::drop(writer);
         file.flush().context("Flushing output buffer")?;
 
         Ok(())
@@ -0,0 +1,298 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use generated_types::influxdata::platform::storage::{node::Value, Node, ReadFilterRequest};
+use influxdb_iox_client::connection::Connection;
+use structopt::StructOpt;
+
+use crate::{
+    predicate::{is_special_key, measurement, tag_name, TAG_KEY_FIELD},
+    query::{Query, StorageRpc},
+    query_log::QueryLog,
+    sql::{column_names, table_name},
+    util::Connector,
+    verify::{Cell, Table},
+};
+
+pub type Result<T, E = String> = std::result::Result<T, E>;
+
+/// Check that the tables (measurements) and columns (tags and fields)
+/// referenced by each logged query exist in the target database, as
+/// queries referencing missing objects silently return nothing.
+#[derive(Debug, StructOpt)]
+pub struct SchemaCheck {
+    /// The database name to check
+    db: String,
+
+    /// The filename of the query log
+    filename: String,
+}
+
+impl SchemaCheck {
+    pub async fn execute(&self, connector: &Connector) -> Result<()> {
+        let log = QueryLog::new_from_file(Path::new(&self.filename)).await?;
+        println!("Loaded query log with {} entries", log.queries.len());
+
+        let queries: Vec<_> = log
+            .queries
+            .into_iter()
+            .enumerate()
+            .map(|(i, r)| (i, self.db.clone(), r.into_inner()))
+            .collect();
+        let connection = connector.connect().await?;
+        let num_incompatible = check_schema(&queries, &connection).await?;
+
+        if num_incompatible > 0 {
+            return Err(format!(
+                "{} queries reference objects missing from {}",
+                num_incompatible, self.db
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The tables of a database and their columns
+#[derive(Debug, Default)]
+struct Schema {
+    tables: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl Schema {
+    /// Read the schema of `database_name` from its `information_schema`
+    async fn load(database_name: &str, connection: &Connection) -> Result<Self> {
+        let query = Query::Sql(
+            "SELECT table_name, column_name FROM information_schema.columns \
+             WHERE table_schema = 'iox'"
+                .to_string(),
+        );
+        let (_, output) = query
+            .replay_with_output(database_name, connection.clone())
+            .await?;
+
+        let mut schema = Self::default();
+        for row in Table::try_new(&output)?.rows {
+            if let [Cell::Other(table), Cell::Other(column)] = row.as_slice() {
+                schema
+                    .tables
+                    .entry(table.clone())
+                    .or_default()
+                    .insert(column.clone());
+            }
+        }
+        Ok(schema)
+    }
+
+    /// Return a description of each of `columns` of `table` that does not
+    /// exist. Without a `table`, columns are missing if no table has them
+    fn missing(&self, table: Option<&str>, columns: &BTreeSet<String>) -> Vec<String> {
+        match table {
+            Some(table) => match self.tables.get(table) {
+                Some(existing) => columns
+                    .difference(existing)
+                    .map(|column| format!("column {}.{}", table, column))
+                    .collect(),
+                None => vec![format!("table {}", table)],
+            },
+            None => columns
+                .iter()
+                .filter(|column| !self.tables.values().any(|t| t.contains(*column)))
+                .map(|column| format!("column {}", column))
+                .collect(),
+        }
+    }
+}
+
+/// Check the objects referenced by each of `queries` (with its position
+/// in the log) exist in the database it is paired with, printing those that are missing. Returns
+/// the number of queries that reference missing objects
+pub async fn check_schema(
+    queries: &[(usize, String, Query)],
+    connection: &Connection,
+) -> Result<usize> {
+    let mut schemas = BTreeMap::new();
+    for (_, database_name, _) in queries {
+        if schemas.contains_key(database_name) {
+            continue;
+        }
+        let schema = Schema::load(database_name, connection).await?;
+        println!(
+            "Database {} has {} tables",
+            database_name,
+            schema.tables.len()
+        );
+        schemas.insert(database_name.clone(), schema);
+    }
+
+    let mut num_incompatible = 0;
+    for (i, database_name, query) in queries {
+        let (table, columns) = references(query);
+        let missing = schemas[database_name].missing(table.as_deref(), &columns);
+        if missing.is_empty() {
+            println!("query {}: ok", i);
+        } else {
+            num_incompatible += 1;
+            println!("query {}: MISSING {}", i, missing.join(", "));
+        }
+    }
+
+    println!(
+        "{} of {} queries reference objects missing from {}",
+        num_incompatible,
+        queries.len(),
+        schemas.into_keys().collect::<Vec<_>>().join(", ")
+    );
+    Ok(num_incompatible)
+}
+
+/// Return the table (if known) and columns referenced by `query`
+fn references(query: &Query) -> (Option<String>, BTreeSet<String>) {
+    match query {
+        Query::Sql(sql) => (table_name(sql), column_names(sql)),
+        Query::StorageRpc(StorageRpc::ReadFilter(request)) => read_filter_references(request),
+    }
+}
+
+fn read_filter_references(request: &ReadFilterRequest) -> (Option<String>, BTreeSet<String>) {
+    let predicate = match &request.predicate {
+        Some(predicate) => predicate,
+        None => return (None, BTreeSet::new()),
+    };
+
+    let mut columns = BTreeSet::new();
+    if let Some(root) = &predicate.root {
+        predicate_columns(root, &mut columns);
+    }
+    (measurement(predicate).map(|m| m.to_string()), columns)
+}
+
+/// Add the tags and fields referenced by `node` to `columns`
+fn predicate_columns(node: &Node, columns: &mut BTreeSet<String>) {
+    if let [left, right] = node.children.as_slice() {
+        // `_field = 'name'` references the field `name`
+        if let (Some(Value::TagRefValue(key)), Some(Value::StringValue(field))) =
+            (&left.value, &right.value)
+        {
+            if key.as_slice() == TAG_KEY_FIELD {
+                columns.insert(field.clone());
+            }
+        }
+    }
+
+    match &node.value {
+        Some(Value::TagRefValue(key)) if !is_special_key(key) => {
+            columns.insert(tag_name(key));
+        }
+        Some(Value::FieldRefValue(field)) if field != "_value" => {
+            columns.insert(field.clone());
+        }
+        _ => {}
+    }
+
+    for child in &node.children {
+        predicate_columns(child, columns);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use generated_types::influxdata::platform::storage::{
+        node::{Comparison, Type},
+        Predicate,
+    };
+
+    use super::*;
+    use crate::predicate::{and, tag_comparison, TAG_KEY_MEASUREMENT};
+
+    fn names(names: &[&str]) -> BTreeSet<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    fn schema() -> Schema {
+        let mut tables = BTreeMap::new();
+        tables.insert("cpu".to_string(), names(&["time", "host", "usage"]));
+        tables.insert("mem".to_string(), names(&["time", "host", "free"]));
+        Schema { tables }
+    }
+
+    #[test]
+    fn sql_references() {
+        let query = Query::Sql(
+            "SELECT host, avg(usage) FROM cpu WHERE time > now() - interval '1 hour' \
+             GROUP BY host"
+                .to_string(),
+        );
+        assert_eq!(
+            references(&query),
+            (Some("cpu".to_string()), names(&["host", "time", "usage"]))
+        );
+
+        let query = Query::Sql("SHOW TABLES".to_string());
+        assert_eq!(references(&query), (None, BTreeSet::new()));
+    }
+
+    #[test]
+    fn read_filter_references() {
+        let string = |s: &str| Value::StringValue(s.to_string());
+        let field_ref = Node {
+            node_type: Type::FieldRef as i32,
+            children: vec![],
+            value: Some(Value::FieldRefValue("usage".to_string())),
+        };
+        let field_comparison = Node {
+            node_type: Type::ComparisonExpression as i32,
+            children: vec![
+                field_ref,
+                Node {
+                    node_type: Type::Literal as i32,
+                    children: vec![],
+                    value: Some(Value::FloatValue(1.0)),
+                },
+            ],
+            value: Some(Value::Comparison(Comparison::Gt as i32)),
+        };
+        let request = ReadFilterRequest {
+            predicate: Some(Predicate {
+                root: and(vec![
+                    tag_comparison(TAG_KEY_MEASUREMENT, Comparison::Equal, string("cpu")),
+                    tag_comparison(b"host", Comparison::Equal, string("a")),
+                    tag_comparison(TAG_KEY_FIELD, Comparison::Equal, string("idle")),
+                    field_comparison,
+                ]),
+            }),
+            ..Default::default()
+        };
+        let query = Query::StorageRpc(StorageRpc::ReadFilter(request));
+        assert_eq!(
+            references(&query),
+            (Some("cpu".to_string()), names(&["host", "idle", "usage"]))
+        );
+
+        let query = Query::StorageRpc(StorageRpc::ReadFilter(ReadFilterRequest::default()));
+        assert_eq!(references(&query), (None, BTreeSet::new()));
+    }
+
+    #[test]
+    fn missing_objects() {
+        let schema = schema();
+        assert!(schema
+            .missing(Some("cpu"), &names(&["host", "usage"]))
+            .is_empty());
+        assert_eq!(
+            schema.missing(Some("cpu"), &names(&["free", "region"])),
+            vec!["column cpu.free", "column cpu.region"]
+        );
+        assert_eq!(
+            schema.missing(Some("disk"), &names(&["host"])),
+            vec!["table disk"]
+        );
+
+        // without a table, a column is only missing if no table has it
+        assert_eq!(
+            schema.missing(None, &names(&["free", "usage", "region"])),
+            vec!["column region"]
+        );
+    }
+}
@@ -0,0 +1,500 @@
+use std::path::Path;
+
+use generated_types::{
+    influxdata::platform::storage::{
+        node::{Type, Value},
+        Node, ReadFilterRequest,
+    },
+    ReadSource,
+};
+use hmac::{Hmac, Mac, NewMac};
+use prost::Message;
+use sha2::Sha256;
+use structopt::StructOpt;
+
+use crate::{
+    error::StringifyError,
+    predicate::is_special_key,
+    query::{encode_read_source, Query, StorageRpc},
+    query_log::{QueryLog, QueryRow},
+    sql::{is_keyword, tokenize, Token},
+};
+
+pub type Result<T, E = String> = std::result::Result<T, E>;
+
+/// Identifiers that are left as is so scrubbed queries remain valid
+const PRESERVED_IDENTIFIERS: &[&str] = &[
+    "time",
+    "system",
+    "information_schema",
+    "_measurement",
+    "_field",
+    "_value",
+];
+
+/// Anonymize a saved query log so it can be shared.
+///
+/// Identifiers (tables, columns, measurements, tag keys and fields),
+/// string literals (such as tag values) and `ReadSource` org / bucket
+/// ids are replaced with keyed hashes (HMAC-SHA256), so a given name is
+/// replaced with the same value everywhere in the log. Numeric literals,
+/// timestamps and time ranges are left as is, as is the structure of
+/// regular expressions.
+#[derive(Debug, StructOpt)]
+pub struct Scrub {
+    /// The query log to scrub
+    input: String,
+
+    /// The filename to write the scrubbed query log to
+    output: String,
+
+    /// Secret key for hashing. The same key always produces the same
+    /// replacements
+    #[structopt(long, env = "QUERY_LOG_SCRUB_KEY")]
+    key: String,
+}
+
+impl Scrub {
+    pub async fn execute(&self) -> Result<()> {
+        println!("Scrubbing {} into {}...", self.input, self.output);
+        let log = QueryLog::new_from_file(Path::new(&self.input)).await?;
+
+        let scrubber = Scrubber::new(&self.key);
+        let queries = log
+            .queries
+            .iter()
+            .map(|row| {
+                Ok(QueryRow::new(
+                    row.issue_time(),
+                    scrubber.query(row.query())?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        QueryLog { queries }.write_to_file(Path::new(&self.output))
+    }
+}
+
+/// Replaces names and values in queries with keyed hashes
+#[derive(Debug)]
+pub struct Scrubber {
+    key: String,
+}
+
+impl Scrubber {
+    pub fn new(key: impl Into<String>) -> Self {
+        Self { key: key.into() }
+    }
+
+    /// Return a scrubbed copy of `query`
+    pub fn query(&self, query: &Query) -> Result<Query> {
+        match query {
+            Query::Sql(sql) => Ok(Query::Sql(self.sql(sql))),
+            Query::StorageRpc(StorageRpc::ReadFilter(request)) => {
+                let mut request = request.clone();
+                self.read_filter(&mut request)?;
+                Ok(Query::StorageRpc(StorageRpc::ReadFilter(request)))
+            }
+        }
+    }
+
+    /// The first 64 bits of the HMAC-SHA256 of `value` keyed with the
+    /// secret key, so replacements can not be reversed without the key
+    fn hash(&self, kind: &str, value: &str) -> u64 {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.key.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(kind.as_bytes());
+        mac.update(&[0]);
+        mac.update(value.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        u64::from_be_bytes(digest[..8].try_into().expect("digest is 32 bytes"))
+    }
+
+    /// Scrub the name of a table, column, measurement, tag key or field
+    fn identifier(&self, name: &str) -> String {
+        if PRESERVED_IDENTIFIERS.contains(&name) {
+            name.to_string()
+        } else {
+            format!("n_{:016x}", self.hash("identifier", name))
+        }
+    }
+
+    /// Scrub a literal value such as a tag value
+    fn value(&self, value: &str) -> String {
+        if looks_like_timestamp(value) {
+            value.to_string()
+        } else {
+            format!("v_{:016x}", self.hash("value", value))
+        }
+    }
+
+    fn name_or_value(&self, s: &str, is_name: bool) -> String {
+        if is_name {
+            self.identifier(s)
+        } else {
+            self.value(s)
+        }
+    }
+
+    /// Scrub the literal text of `regex`, keeping its anchors, classes,
+    /// quantifiers, groups and alternation. Each run of literal text is
+    /// hashed on its own, so a regex that matched whole names (such as
+    /// `^cpu$|^mem$`) matches the scrubbed names, but one that matched
+    /// part of a name (such as `^cpu`) generally no longer matches it
+    fn regex(&self, regex: &str, is_name: bool) -> String {
+        let chars: Vec<char> = regex.chars().collect();
+        let mut scrubbed = String::new();
+        let mut literal = String::new();
+        let mut i = 0;
+
+        while i < chars.len() {
+            let c = chars[i];
+            if c.is_alphanumeric() || c == '_' {
+                literal.push(c);
+                i += 1;
+                continue;
+            }
+            if !literal.is_empty() {
+                scrubbed.push_str(&self.name_or_value(&literal, is_name));
+                literal.clear();
+            }
+
+            // copy escapes (`\d`, `\.`), classes (`[a-z]`), repetitions
+            // (`{2,3}`) and group flags (`(?i)`) unchanged
+            let end = match c {
+                '\\' => i + 2,
+                '[' => regex_class_end(&chars, i),
+                '{' => find(&chars, i, |c| c == '}'),
+                '(' if chars.get(i + 1) == Some(&'?') => find(&chars, i, |c| c == ')' || c == ':'),
+                _ => i + 1,
+            }
+            .min(chars.len());
+            scrubbed.extend(&chars[i..end]);
+            i = end;
+        }
+        if !literal.is_empty() {
+            scrubbed.push_str(&self.name_or_value(&literal, is_name));
+        }
+
+        scrubbed
+    }
+
+    fn sql(&self, sql: &str) -> String {
+        let tokens = tokenize(sql);
+        let significant: Vec<_> = tokens
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| !matches!(t, Token::Whitespace(_)))
+            .map(|(i, _)| i)
+            .collect();
+
+        let mut scrubbed = tokens.clone();
+        for (pos, &i) in significant.iter().enumerate() {
+            let prev = pos.checked_sub(1).map(|p| &tokens[significant[p]]);
+            let next = significant.get(pos + 1).map(|&n| &tokens[n]);
+            let prev2 = pos.checked_sub(2).map(|p| &tokens[significant[p]]);
+
+            // system schemas and the names they qualify, such as
+            // `system.queries` or `"INFORMATION_SCHEMA"."columns"`
+            let is_system_table = (is_system_schema(&tokens[i]) && is_punct(next, "."))
+                || (is_punct(prev, ".") && prev2.map(is_system_schema).unwrap_or(false));
+
+            scrubbed[i] = match &tokens[i] {
+                Token::Word(w) if !is_keyword_use(w, prev, next) => {
+                    let is_function = is_punct(next, "(");
+                    if is_function || is_system_table {
+                        continue;
+                    }
+                    Token::Word(self.identifier(w))
+                }
+                Token::QuotedIdent(name) if !is_system_table => {
+                    Token::QuotedIdent(self.identifier(name))
+                }
+                Token::StringLiteral(s) => {
+                    // keep the text of `interval '1 hour'` and similar
+                    let is_typed = matches!(prev, Some(Token::Word(w))
+                        if w.eq_ignore_ascii_case("interval") || w.eq_ignore_ascii_case("timestamp"));
+                    if is_typed {
+                        continue;
+                    }
+                    Token::StringLiteral(self.value(s))
+                }
+                _ => continue,
+            };
+        }
+
+        scrubbed.iter().map(|t| t.to_string()).collect()
+    }
+
+    fn read_filter(&self, request: &mut ReadFilterRequest) -> Result<()> {
+        if let Some(any) = request.read_source.as_mut() {
+            let mut read_source: ReadSource = Message::decode(&any.value[..])
+                .context("value could not be parsed as a ReadSource message")?;
+            read_source.org_id = self.hash("id", &read_source.org_id.to_string());
+            read_source.bucket_id = self.hash("id", &read_source.bucket_id.to_string());
+            *any = encode_read_source(&read_source, &any.type_url);
+        }
+
+        if let Some(root) = request.predicate.as_mut().and_then(|p| p.root.as_mut()) {
+            self.node(root, false);
+        }
+        Ok(())
+    }
+
+    /// Scrub a predicate node. `is_name` is true if the literals in this
+    /// node are measurement or field names rather than tag values
+    fn node(&self, node: &mut Node, is_name: bool) {
+        // values compared with `_measurement` or `_field` are names
+        let is_name = is_name
+            || (node.node_type == Type::ComparisonExpression as i32
+                && node
+                    .children
+                    .first()
+                    .map(|left| matches!(&left.value, Some(Value::TagRefValue(key)) if is_special_key(key)))
+                    .unwrap_or(false));
+
+        match &mut node.value {
+            Some(Value::TagRefValue(key)) if !is_special_key(key) => {
+                let name = self.identifier(&String::from_utf8_lossy(key));
+                *key = name.into_bytes();
+            }
+            Some(Value::FieldRefValue(field)) => *field = self.identifier(field),
+            Some(Value::StringValue(s)) => *s = self.name_or_value(s, is_name),
+            Some(Value::RegexValue(r)) => *r = self.regex(r, is_name),
+            _ => {}
+        }
+
+        for child in &mut node.children {
+            self.node(child, is_name);
+        }
+    }
+}
+
+/// Keywords that are also common names of columns or tables, such as
+/// a `count` column or a `tables` measurement
+const NAME_LIKE_KEYWORDS: &[&str] = &[
+    "avg",
+    "columns",
+    "count",
+    "date_bin",
+    "date_trunc",
+    "first",
+    "last",
+    "max",
+    "mean",
+    "min",
+    "now",
+    "sum",
+    "tables",
+    "timestamp",
+    "to_timestamp",
+];
+
+/// Is the word `word`, between `prev` and `next`, used as a SQL keyword
+/// or function rather than as the name of a table or column?
+fn is_keyword_use(word: &str, prev: Option<&Token>, next: Option<&Token>) -> bool {
+    let lower = word.to_ascii_lowercase();
+    if !NAME_LIKE_KEYWORDS.contains(&lower.as_str()) {
+        return is_keyword(word);
+    }
+    let prev_word = |w: &str| matches!(prev, Some(Token::Word(p)) if p.eq_ignore_ascii_case(w));
+    match lower.as_str() {
+        // `SHOW TABLES`, `SHOW COLUMNS FROM cpu`
+        "tables" | "columns" => prev_word("show"),
+        // `timestamp '2021-12-16'`, `x::timestamp`, `CAST(x AS timestamp)`
+        "timestamp" => {
+            matches!(next, Some(Token::StringLiteral(_)))
+                || is_punct(prev, "::")
+                || (prev_word("as") && is_punct(next, ")"))
+        }
+        // `ORDER BY x NULLS FIRST`
+        "first" | "last" if prev_word("nulls") => true,
+        // functions such as `count(*)`
+        _ => is_punct(next, "("),
+    }
+}
+
+/// Is `token` the punctuation `punct`?
+fn is_punct(token: Option<&Token>, punct: &str) -> bool {
+    matches!(token, Some(Token::Punct(p)) if p == punct)
+}
+
+/// Is `token` the name of a system schema? Like other SQL names these
+/// match case insensitively, quoted or not
+fn is_system_schema(token: &Token) -> bool {
+    match token {
+        Token::Word(name) | Token::QuotedIdent(name) => {
+            name.eq_ignore_ascii_case("system") || name.eq_ignore_ascii_case("information_schema")
+        }
+        _ => false,
+    }
+}
+
+/// Return the index just past the `]` closing the character class that
+/// starts at `chars[start]`. A `]` first in the class is literal
+fn regex_class_end(chars: &[char], start: usize) -> usize {
+    let mut i = start + 1;
+    if chars.get(i) == Some(&'^') {
+        i += 1;
+    }
+    if chars.get(i) == Some(&']') {
+        i += 1;
+    }
+    while i < chars.len() && chars[i] != ']' {
+        i += if chars[i] == '\\' { 2 } else { 1 };
+    }
+    i + 1
+}
+
+/// Return the index just past the first character after `chars[start]`
+/// that matches `pred`, or the end of `chars`
+fn find(chars: &[char], start: usize, pred: impl Fn(char) -> bool) -> usize {
+    chars[start + 1..]
+        .iter()
+        .position(|&c| pred(c))
+        .map(|offset| start + offset + 2)
+        .unwrap_or(chars.len())
+}
+
+/// Does `value` look like a timestamp such as `2021-12-16 15:06:22`?
+fn looks_like_timestamp(value: &str) -> bool {
+    let bytes = value.as_bytes();
+    bytes.len() >= 10
+        && bytes[..4].iter().all(|b| b.is_ascii_digit())
+        && bytes[4] == b'-'
+        && bytes[7] == b'-'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The kind of each significant token of `sql`, with the text of
+    /// keywords, numbers and punctuation
+    fn shape(sql: &str) -> Vec<String> {
+        tokenize(sql)
+            .into_iter()
+            .filter_map(|token| match token {
+                Token::Whitespace(_) => None,
+                Token::Word(w) if is_keyword(&w) => Some(w.to_ascii_lowercase()),
+                Token::Word(_) => Some("<word>".to_string()),
+                Token::QuotedIdent(_) => Some("<ident>".to_string()),
+                Token::StringLiteral(_) => Some("<string>".to_string()),
+                Token::Number(n) => Some(n),
+                Token::Punct(p) => Some(p),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn scrubbed_sql_tokenizes_the_same() {
+        let scrubber = Scrubber::new("secret");
+        for sql in [
+            "SELECT usage_idle, host FROM cpu WHERE host = 'server01' \
+             AND time > now() - interval '1 hour' ORDER BY time DESC LIMIT 10",
+            "SHOW TABLES",
+            "SHOW COLUMNS FROM cpu",
+            "SELECT * FROM cpu WHERE EXISTS (SELECT 1 FROM mem WHERE mem.host ILIKE 'a%')",
+            "SELECT count(*) FROM system.queries",
+            r#"SELECT "user" FROM "my table" WHERE region IN ('us-west', 'it''s')"#,
+            "SELECT mean(value) FROM disk -- trailing comment",
+        ] {
+            let scrubbed = scrubber.sql(sql);
+            assert_eq!(
+                shape(&scrubbed),
+                shape(sql),
+                "scrubbed {} to {}",
+                sql,
+                scrubbed
+            );
+        }
+    }
+
+    #[test]
+    fn replacements_are_keyed_and_consistent() {
+        let scrubber = Scrubber::new("secret");
+        let name = scrubber.identifier("cpu");
+        assert_eq!(name, scrubber.identifier("cpu"));
+        assert_eq!(name.len(), "n_".len() + 16);
+        assert_ne!(name, scrubber.identifier("mem"));
+        assert_ne!(name, Scrubber::new("other").identifier("cpu"));
+        assert_ne!(scrubber.value("cpu"), name.replace("n_", "v_"));
+
+        assert_eq!(scrubber.identifier("time"), "time");
+        assert_eq!(scrubber.value("2021-12-16 15:06:22"), "2021-12-16 15:06:22");
+    }
+
+    #[test]
+    fn regex_structure_is_preserved() {
+        let scrubber = Scrubber::new("secret");
+        let cpu = scrubber.identifier("cpu");
+        let mem = scrubber.identifier("mem");
+
+        assert_eq!(scrubber.regex("^cpu$", true), format!("^{}$", cpu));
+        assert_eq!(
+            scrubber.regex("^cpu.*|^mem", true),
+            format!("^{}.*|^{}", cpu, mem)
+        );
+        assert_eq!(
+            scrubber.regex(r"(?i)cpu\.[0-9]{2}[^]a]", true),
+            format!(r"(?i){}\.[0-9]{{2}}[^]a]", cpu)
+        );
+
+        let host = scrubber.value("host");
+        assert_eq!(scrubber.regex(r"host\d+", false), format!(r"{}\d+", host));
+    }
+
+    #[test]
+    fn system_tables_are_kept() {
+        let scrubber = Scrubber::new("secret");
+        for sql in [
+            "SELECT * FROM system.queries",
+            "SELECT * FROM SYSTEM.queries",
+            r#"SELECT * FROM "system"."queries""#,
+            r#"SELECT * FROM "Information_Schema".columns"#,
+        ] {
+            assert_eq!(scrubber.sql(sql), sql);
+        }
+    }
+
+    #[test]
+    fn keyword_named_identifiers_are_scrubbed() {
+        let scrubber = Scrubber::new("secret");
+        let count = scrubber.identifier("count");
+        let timestamp = scrubber.identifier("timestamp");
+        let tables = scrubber.identifier("tables");
+        let host = scrubber.identifier("host");
+
+        assert_eq!(
+            scrubber.sql("SELECT count, timestamp FROM tables"),
+            format!("SELECT {}, {} FROM {}", count, timestamp, tables)
+        );
+        assert_eq!(
+            scrubber.sql("SELECT COUNT FROM Tables WHERE count > 1 ORDER BY host NULLS FIRST"),
+            format!(
+                "SELECT {} FROM {} WHERE {} > 1 ORDER BY {} NULLS FIRST",
+                scrubber.identifier("COUNT"),
+                scrubber.identifier("Tables"),
+                count,
+                host
+            )
+        );
+
+        // used as keywords and functions they are kept
+        for sql in [
+            "SHOW TABLES",
+            "SHOW COLUMNS FROM t",
+            "SELECT count(*), max(x) FROM t",
+            "SELECT * FROM t WHERE time > timestamp '2021-12-16 15:06:22'",
+            "SELECT CAST(x AS TIMESTAMP), y::timestamp FROM t",
+        ] {
+            let kept = |sql: &str| {
+                tokenize(sql)
+                    .into_iter()
+                    .filter(|t| matches!(t, Token::Word(w) if is_keyword(w)))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(kept(&scrubber.sql(sql)), kept(sql), "{}", sql);
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use std::path::Path;
+
+use serde_json::{json, Value};
+use structopt::StructOpt;
+
+use crate::{
+    error::StringifyError,
+    predicate::NodeDisplay,
+    query::{Query, StorageRpc},
+    query_log::QueryLog,
+    sql,
+    time::format_timestamp,
+};
+
+pub type Result<T, E = String> = std::result::Result<T, E>;
+
+/// Print queries from a saved log in full detail
+#[derive(Debug, StructOpt)]
+pub struct Show {
+    /// The filename of the query log
+    filename: String,
+
+    /// Which queries to show, by index, such as `3`, `2-5`, `7-` or
+    /// `1,4,7-9`. Shows all queries if not specified
+    selection: Option<String>,
+}
+
+impl Show {
+    pub async fn execute(&self) -> Result<()> {
+        let log = QueryLog::new_from_file(Path::new(&self.filename)).await?;
+
+        let indices = match &self.selection {
+            Some(selection) => parse_selection(selection, log.queries.len())?,
+            None => (0..log.queries.len()).collect(),
+        };
+
+        for i in indices {
+            let row = &log.queries[i];
+            let query = row.query();
+            println!(
+                "query {} ({}) issued at {}",
+                i,
+                query.query_type(),
+                row.issue_time()
+            );
+            println!("{}", describe(query)?);
+            println!();
+        }
+        Ok(())
+    }
+}
+
+/// Return the full description of `query`
+fn describe(query: &Query) -> Result<String> {
+    match query {
+        Query::Sql(sql) => Ok(sql::format(sql)),
+        Query::StorageRpc(storagerpc) => {
+            let StorageRpc::ReadFilter(request) = storagerpc;
+
+            let mut value = serde_json::to_value(request).context("Serializing request")?;
+            if let Value::Object(map) = &mut value {
+                // replace the encoded fields with human readable ones
+                if let Ok(read_source) = storagerpc.decode_read_source() {
+                    map.insert(
+                        "ReadSource".to_string(),
+                        json!({
+                            "orgId": format!("{:016x}", read_source.org_id),
+                            "bucketId": format!("{:016x}", read_source.bucket_id),
+                            "partitionId": read_source.partition_id,
+                            "database": storagerpc.database_name()?,
+                        }),
+                    );
+                }
+                if let Some(range) = &request.range {
+                    map.insert(
+                        "range".to_string(),
+                        json!({
+                            "start": format_timestamp(range.start),
+                            "end": format_timestamp(range.end),
+                        }),
+                    );
+                }
+            }
+
+            let predicate = request
+                .predicate
+                .as_ref()
+                .and_then(|p| p.root.as_ref())
+                .map(|root| NodeDisplay::new(root).to_string())
+                .unwrap_or_else(|| "<none>".to_string());
+
+            let json = serde_json::to_string_pretty(&value).context("Formatting request")?;
+            Ok(format!("predicate: {}\n{}", predicate, json))
+        }
+    }
+}
+
+/// Parse a selection such as `1,4,7-9` into indices of a log with `len` entries
+pub fn parse_selection(selection: &str, len: usize) -> Result<Vec<usize>> {
+    let parse_index = |s: &str| {
+        s.trim()
+            .parse::<usize>()
+            .context(&format!("Invalid query index '{}'", s))
+    };
+
+    let mut indices = vec![];
+    for part in selection.split(',') {
+        let (start, end) = match part.split_once('-') {
+            Some((start, end)) if end.trim().is_empty() => {
+                (parse_index(start)?, len.saturating_sub(1))
+            }
+            Some((start, end)) => (parse_index(start)?, parse_index(end)?),
+            None => {
+                let i = parse_index(part)?;
+                (i, i)
+            }
+        };
+
+        if start.max(end) >= len {
+            return Err(format!(
+                "Query index {} out of range, log has {} entries",
+                start.max(end),
+                len
+            ));
+        }
+        if start > end {
+            return Err(format!(
+                "Invalid query range '{}', {} is after {}",
+                part.trim(),
+                start,
+                end
+            ));
+        }
+        indices.extend(start..=end);
+    }
+    Ok(indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selections() {
+        assert_eq!(parse_selection("3", 10), Ok(vec![3]));
+        assert_eq!(parse_selection("1,4,7-9", 10), Ok(vec![1, 4, 7, 8, 9]));
+        assert_eq!(parse_selection("8-", 10), Ok(vec![8, 9]));
+        assert_eq!(parse_selection(" 2 - 2 ", 10), Ok(vec![2]));
+
+        assert!(parse_selection("5-3", 10).is_err());
+        assert!(parse_selection("10", 10).is_err());
+        assert!(parse_selection("12-", 10).is_err());
+        assert!(parse_selection("a", 10).is_err());
+    }
+}
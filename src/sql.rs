@@ -0,0 +1,662 @@
+use std::{
+    collections::BTreeSet,
+    fmt::{Display, Formatter},
+};
+
+/// A lexical token of a SQL statement, as produced by [`tokenize`].
+///
+/// This is not a full SQL parser, but is enough to find literals and
+/// identifiers in logged queries and reassemble them afterwards.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    /// Whitespace, including any comments
+    Whitespace(String),
+    /// A keyword or unquoted identifier, such as `select` or `cpu`
+    Word(String),
+    /// A double quoted identifier such as `"cpu"` (stored unquoted)
+    QuotedIdent(String),
+    /// A single quoted string literal such as `'foo'` (stored unquoted)
+    StringLiteral(String),
+    /// A numeric literal such as `1` or `1.5e10`
+    Number(String),
+    /// Any other character(s), such as operators and punctuation
+    Punct(String),
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Whitespace(s) | Token::Word(s) | Token::Number(s) | Token::Punct(s) => {
+                write!(f, "{}", s)
+            }
+            Token::QuotedIdent(s) => write!(f, "\"{}\"", s.replace('"', "\"\"")),
+            Token::StringLiteral(s) => write!(f, "'{}'", s.replace('\'', "''")),
+        }
+    }
+}
+
+/// Words that are treated as SQL keywords / functions rather than identifiers
+const KEYWORDS: &[&str] = &[
+    "all",
+    "and",
+    "as",
+    "asc",
+    "avg",
+    "between",
+    "by",
+    "case",
+    "cast",
+    "columns",
+    "count",
+    "date_bin",
+    "date_trunc",
+    "desc",
+    "distinct",
+    "else",
+    "end",
+    "exists",
+    "explain",
+    "false",
+    "first",
+    "from",
+    "full",
+    "group",
+    "having",
+    "ilike",
+    "in",
+    "inner",
+    "interval",
+    "is",
+    "join",
+    "last",
+    "left",
+    "like",
+    "limit",
+    "max",
+    "mean",
+    "min",
+    "not",
+    "now",
+    "null",
+    "nulls",
+    "offset",
+    "on",
+    "or",
+    "order",
+    "outer",
+    "right",
+    "select",
+    "show",
+    "sum",
+    "tables",
+    "then",
+    "timestamp",
+    "to_timestamp",
+    "true",
+    "union",
+    "when",
+    "where",
+    "with",
+];
+
+/// Keywords that start a new line when formatting SQL
+const CLAUSES: &[&str] = &[
+    "select", "from", "where", "group", "having", "order", "limit", "offset", "union",
+];
+
+/// Is `word` a known SQL keyword or function name?
+pub fn is_keyword(word: &str) -> bool {
+    let word = word.to_ascii_lowercase();
+    KEYWORDS.contains(&word.as_str())
+}
+
+/// Split `sql` into `Token`s. Concatenating the `Display` of the
+/// tokens reproduces equivalent SQL.
+pub fn tokenize(sql: &str) -> Vec<Token> {
+    let chars: Vec<char> = sql.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+
+        if c.is_whitespace() || starts_with(&chars, i, "--") || starts_with(&chars, i, "/*") {
+            // consume whitespace and comments
+            while i < chars.len() {
+                if chars[i].is_whitespace() {
+                    i += 1;
+                } else if starts_with(&chars, i, "--") {
+                    while i < chars.len() && chars[i] != '\n' {
+                        i += 1;
+                    }
+                } else if starts_with(&chars, i, "/*") {
+                    i += 2;
+                    while i < chars.len() && !starts_with(&chars, i, "*/") {
+                        i += 1;
+                    }
+                    i = (i + 2).min(chars.len());
+                } else {
+                    break;
+                }
+            }
+            tokens.push(Token::Whitespace(collect(&chars[start..i])));
+        } else if c == '\'' || c == '"' {
+            // quoted string or identifier, with doubled quotes as escapes
+            let mut value = String::new();
+            i += 1;
+            while i < chars.len() {
+                if chars[i] == c {
+                    if i + 1 < chars.len() && chars[i + 1] == c {
+                        value.push(c);
+                        i += 2;
+                    } else {
+                        i += 1;
+                        break;
+                    }
+                } else {
+                    value.push(chars[i]);
+                    i += 1;
+                }
+            }
+            if c == '\'' {
+                tokens.push(Token::StringLiteral(value));
+            } else {
+                tokens.push(Token::QuotedIdent(value));
+            }
+        } else if c.is_ascii_digit() || (c == '.' && next_is_digit(&chars, i)) {
+            while i < chars.len() {
+                let c = chars[i];
+                if c.is_ascii_digit() || c == '.' {
+                    i += 1;
+                } else if (c == 'e' || c == 'E')
+                    && (next_is_digit(&chars, i)
+                        || (i + 2 < chars.len()
+                            && (chars[i + 1] == '-' || chars[i + 1] == '+')
+                            && chars[i + 2].is_ascii_digit()))
+                {
+                    i += 2;
+                } else {
+                    break;
+                }
+            }
+            tokens.push(Token::Number(collect(&chars[start..i])));
+        } else if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Word(collect(&chars[start..i])));
+        } else {
+            // group multi-character operators such as `<=`, `!=` and `::`
+            i += 1;
+            if i < chars.len()
+                && matches!(
+                    (c, chars[i]),
+                    ('<', '=') | ('>', '=') | ('<', '>') | ('!', '=') | (':', ':') | ('|', '|')
+                )
+            {
+                i += 1;
+            }
+            tokens.push(Token::Punct(collect(&chars[start..i])));
+        }
+    }
+
+    tokens
+}
+
+/// Does `sql` have an `ORDER BY` clause at its outermost level? Those
+/// within subqueries and window functions (`OVER (...)`) do not order
+/// the result
+pub fn has_order_by(sql: &str) -> bool {
+    order_by_clause(sql).is_some()
+}
+
+/// Return the expressions that the outermost `ORDER BY` of `sql` sorts
+/// by, without any `ASC` / `DESC` or `NULLS FIRST` / `NULLS LAST`. Plain
+/// column names are returned unquoted
+pub fn order_by_keys(sql: &str) -> Vec<String> {
+    let tokens = match order_by_clause(sql) {
+        Some(tokens) => tokens,
+        None => return vec![],
+    };
+
+    let mut keys = vec![];
+    let mut key = vec![];
+    let mut depth = 0usize;
+    for token in tokens.into_iter().skip(2) {
+        match &token {
+            Token::Punct(p) if p == "(" => depth += 1,
+            Token::Punct(p) if p == ")" => depth = depth.saturating_sub(1),
+            Token::Punct(p) if p == "," && depth == 0 => {
+                keys.push(sort_key(&key));
+                key.clear();
+                continue;
+            }
+            _ => {}
+        }
+        key.push(token);
+    }
+    if !key.is_empty() {
+        keys.push(sort_key(&key));
+    }
+    keys
+}
+
+/// Return the significant tokens of the outermost `ORDER BY` clause of
+/// `sql`, if it has one
+fn order_by_clause(sql: &str) -> Option<Vec<Token>> {
+    split_clauses(sql).into_iter().find_map(|clause| {
+        let tokens: Vec<_> = tokenize(&clause)
+            .into_iter()
+            .filter(|t| !matches!(t, Token::Whitespace(_)))
+            .collect();
+        let is_order_by = matches!(tokens.first(), Some(Token::Word(w)) if w.eq_ignore_ascii_case("order"))
+            && matches!(tokens.get(1), Some(Token::Word(w)) if w.eq_ignore_ascii_case("by"));
+        is_order_by.then_some(tokens)
+    })
+}
+
+/// Return the text of a single `ORDER BY` item without its direction
+fn sort_key(tokens: &[Token]) -> String {
+    let mut end = tokens.len();
+    while end > 0
+        && matches!(&tokens[end - 1], Token::Word(w)
+            if ["asc", "desc", "nulls", "first", "last"].contains(&w.to_ascii_lowercase().as_str()))
+    {
+        end -= 1;
+    }
+
+    match &tokens[..end] {
+        [Token::Word(name)] | [Token::QuotedIdent(name)] => name.clone(),
+        expr => expr
+            .iter()
+            .map(|t| t.to_string())
+            .collect::<Vec<_>>()
+            .join(" "),
+    }
+}
+
+/// Format `sql` for display, starting each clause on a new line and
+/// indenting subqueries
+pub fn format(sql: &str) -> String {
+    let mut formatted = String::new();
+    let mut depth = 0;
+
+    for token in tokenize(sql) {
+        match &token {
+            Token::Whitespace(w) => {
+                for comment in comments(w) {
+                    if !formatted.is_empty() && !formatted.ends_with(char::is_whitespace) {
+                        formatted.push(' ');
+                    }
+                    formatted.push_str(comment);
+                    // a line comment extends to the end of the line
+                    if comment.starts_with("--") {
+                        formatted.push('\n');
+                        formatted.push_str(&"  ".repeat(depth));
+                    }
+                }
+                if !formatted.is_empty() && !formatted.ends_with(char::is_whitespace) {
+                    formatted.push(' ');
+                }
+                continue;
+            }
+            Token::Word(w)
+                if !formatted.is_empty() && CLAUSES.contains(&w.to_ascii_lowercase().as_str()) =>
+            {
+                formatted.truncate(formatted.trim_end().len());
+                formatted.push('\n');
+                formatted.push_str(&"  ".repeat(depth));
+            }
+            Token::Punct(p) if p == "(" => depth += 1,
+            Token::Punct(p) if p == ")" => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        formatted.push_str(&token.to_string());
+    }
+
+    formatted.truncate(formatted.trim_end().len());
+    formatted
+}
+
+/// Return the `--` and `/* */` comments in the text of a
+/// `Token::Whitespace`
+fn comments(whitespace: &str) -> Vec<&str> {
+    let mut comments = vec![];
+    let mut rest = whitespace.trim_start();
+    while !rest.is_empty() {
+        let end = if rest.starts_with("--") {
+            rest.find('\n').unwrap_or(rest.len())
+        } else if let Some(body) = rest.strip_prefix("/*") {
+            body.find("*/").map(|i| i + 4).unwrap_or(rest.len())
+        } else {
+            break;
+        };
+        comments.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+    comments
+}
+
+/// Split `sql` into its top level clauses (`SELECT ...`, `FROM ...`,
+/// `WHERE ...` etc). Subqueries are kept within their clause
+pub fn split_clauses(sql: &str) -> Vec<String> {
+    let mut clauses: Vec<String> = vec![];
+    let mut depth = 0usize;
+
+    for token in tokenize(sql) {
+        match &token {
+            Token::Word(w) if depth == 0 && CLAUSES.contains(&w.to_ascii_lowercase().as_str()) => {
+                clauses.push(String::new())
+            }
+            Token::Punct(p) if p == "(" => depth += 1,
+            Token::Punct(p) if p == ")" => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        match clauses.last_mut() {
+            Some(clause) => clause.push_str(&token.to_string()),
+            None => clauses.push(token.to_string()),
+        }
+    }
+
+    clauses
+        .iter()
+        .map(|c| c.trim().to_string())
+        .filter(|c| !c.is_empty())
+        .collect()
+}
+
+/// Return the table that `sql` selects from, if it selects from a
+/// single unqualified table
+pub fn table_name(sql: &str) -> Option<String> {
+    let tokens: Vec<_> = tokenize(sql)
+        .into_iter()
+        .filter(|t| !matches!(t, Token::Whitespace(_)))
+        .collect();
+
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::Punct(p) if p == "(" => depth += 1,
+            Token::Punct(p) if p == ")" => depth -= 1,
+            Token::Word(w) if depth == 0 && w.eq_ignore_ascii_case("from") => {
+                let name = match tokens.get(i + 1)? {
+                    Token::Word(w) if !is_keyword(w) => w.clone(),
+                    Token::QuotedIdent(name) => name.clone(),
+                    _ => return None,
+                };
+                return match tokens.get(i + 2) {
+                    Some(Token::Punct(p)) if p == "." || p == "," => None,
+                    _ => Some(name),
+                };
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Return the names of the columns that `sql` appears to reference.
+///
+/// This is a heuristic: keywords, function names, (qualified) table
+/// names, aliases (and the names they are referred to by), cast types
+/// and keyword arguments such as `epoch` in `extract(epoch FROM time)`
+/// are excluded. Statements other than queries, such as `SHOW TABLES`,
+/// reference no columns
+pub fn column_names(sql: &str) -> BTreeSet<String> {
+    let tokens: Vec<_> = tokenize(sql)
+        .into_iter()
+        .filter(|t| !matches!(t, Token::Whitespace(_)))
+        .collect();
+    let is_punct = |i: usize, p: &str| matches!(tokens.get(i), Some(Token::Punct(t)) if t == p);
+    let is_word = |i: usize, w: &str| matches!(tokens.get(i), Some(Token::Word(t)) if t.eq_ignore_ascii_case(w));
+
+    // only queries reference columns, not `SHOW` and the like
+    if !["select", "with", "explain"].iter().any(|w| is_word(0, w)) {
+        return BTreeSet::new();
+    }
+
+    let mut columns = BTreeSet::new();
+    let mut excluded = BTreeSet::new();
+    // for each enclosing parenthesis, whether it holds function arguments
+    // rather than a subquery or expression
+    let mut parentheses = vec![];
+    for (i, token) in tokens.iter().enumerate() {
+        let in_function = parentheses.last() == Some(&true);
+        let name = match token {
+            Token::Punct(p) if p == "(" => {
+                let after_name = i > 0
+                    && matches!(tokens[i - 1], Token::Word(_) | Token::QuotedIdent(_))
+                    && !is_word(i - 1, "in");
+                let subquery = is_word(i + 1, "select") || is_word(i + 1, "with");
+                parentheses.push(after_name && !subquery);
+                continue;
+            }
+            Token::Punct(p) if p == ")" => {
+                parentheses.pop();
+                continue;
+            }
+            Token::Word(w) if !is_keyword(w) => w,
+            Token::QuotedIdent(name) => name,
+            _ => continue,
+        };
+
+        // the start of a qualified name such as `system.queries`
+        let mut start = i;
+        while start >= 2 && is_punct(start - 1, ".") {
+            start -= 2;
+        }
+        // `FROM` within function arguments, as in `extract(epoch FROM
+        // time)`, does not name a table
+        let after_table_keyword = start > 0
+            && (is_word(start - 1, "join") || (is_word(start - 1, "from") && !in_function));
+        let is_keyword_argument = in_function && is_word(i + 1, "from");
+        let is_alias = i > 0 && is_word(i - 1, "as");
+        let is_cast_type = i > 0 && is_punct(i - 1, "::");
+        let is_function = is_punct(i + 1, "(");
+        let is_qualifier = is_punct(i + 1, ".");
+
+        if after_table_keyword || is_alias || is_qualifier {
+            excluded.insert(name.clone());
+        } else if !is_cast_type && !is_function && !is_keyword_argument {
+            columns.insert(name.clone());
+        }
+    }
+
+    columns.difference(&excluded).cloned().collect()
+}
+
+fn starts_with(chars: &[char], i: usize, pat: &str) -> bool {
+    pat.chars()
+        .enumerate()
+        .all(|(offset, p)| chars.get(i + offset) == Some(&p))
+}
+
+fn next_is_digit(chars: &[char], i: usize) -> bool {
+    chars
+        .get(i + 1)
+        .map(|c| c.is_ascii_digit())
+        .unwrap_or(false)
+}
+
+fn collect(chars: &[char]) -> String {
+    chars.iter().collect()
+}
+
+/// Does `sql` query the system tables (`system.*`, `information_schema`
+/// or `SHOW ...`) rather than the tables of the database? Only tables
+/// after `FROM` or `JOIN` count: `SELECT system.load1 FROM system` reads
+/// the user table `system`
+pub fn is_system_query(sql: &str) -> bool {
+    let tokens: Vec<_> = tokenize(sql)
+        .into_iter()
+        .filter(|t| !matches!(t, Token::Whitespace(_)))
+        .collect();
+
+    if matches!(tokens.first(), Some(Token::Word(w)) if w.eq_ignore_ascii_case("show")) {
+        return true;
+    }
+    tokens
+        .windows(3)
+        .any(|triple| match (&triple[0], &triple[1], &triple[2]) {
+            (
+                Token::Word(keyword),
+                Token::Word(schema) | Token::QuotedIdent(schema),
+                Token::Punct(p),
+            ) if p == "." => {
+                (keyword.eq_ignore_ascii_case("from") || keyword.eq_ignore_ascii_case("join"))
+                    && (schema.eq_ignore_ascii_case("system")
+                        || schema.eq_ignore_ascii_case("information_schema"))
+            }
+            _ => false,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenize_round_trips() {
+        for sql in [
+            "SELECT a, \"b c\" FROM cpu WHERE host = 'it''s' AND x >= 1.5e10",
+            "select count(*) from t -- comment\n where time::bigint <> 2 /* block */",
+            "SELECT \"say \"\"hi\"\"\" FROM t",
+        ] {
+            let tokens = tokenize(sql);
+            assert_eq!(
+                tokens.iter().map(|t| t.to_string()).collect::<String>(),
+                sql
+            );
+        }
+
+        assert_eq!(
+            tokenize("a<=.5 'x'"),
+            vec![
+                Token::Word("a".into()),
+                Token::Punct("<=".into()),
+                Token::Number(".5".into()),
+                Token::Whitespace(" ".into()),
+                Token::StringLiteral("x".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn order_by_at_the_outermost_level() {
+        assert!(has_order_by("SELECT a FROM t ORDER BY a"));
+        assert!(has_order_by("select a from t order by a desc limit 5"));
+        assert!(!has_order_by("SELECT a FROM t"));
+        assert!(!has_order_by(
+            "SELECT a FROM (SELECT a FROM t ORDER BY a LIMIT 1) AS s"
+        ));
+        assert!(!has_order_by(
+            "SELECT a, row_number() OVER (PARTITION BY b ORDER BY a) FROM t"
+        ));
+        assert!(has_order_by(
+            "SELECT a, row_number() OVER (ORDER BY a) FROM t ORDER BY b"
+        ));
+    }
+
+    #[test]
+    fn order_by_keys_without_direction() {
+        assert_eq!(
+            order_by_keys(r#"SELECT * FROM t ORDER BY host, "Time" DESC NULLS LAST LIMIT 3"#),
+            vec!["host", "Time"]
+        );
+        assert_eq!(
+            order_by_keys("SELECT * FROM t ORDER BY date_trunc('hour', time), 2 ASC"),
+            vec!["date_trunc ( 'hour' , time )", "2"]
+        );
+        assert!(order_by_keys("SELECT * FROM (SELECT * FROM t ORDER BY a) AS s").is_empty());
+    }
+
+    #[test]
+    fn format_starts_clauses_on_new_lines() {
+        assert_eq!(
+            format("select a from (select a from t where x = 1) limit 5"),
+            "select a\nfrom (\n  select a\n  from t\n  where x = 1)\nlimit 5"
+        );
+    }
+
+    #[test]
+    fn format_keeps_comments() {
+        assert_eq!(
+            format("-- dashboard 7\nSELECT a /* the a */ FROM t -- all of it"),
+            "-- dashboard 7\nSELECT a /* the a */\nFROM t -- all of it"
+        );
+        // the rest of the query is not commented out
+        assert_eq!(
+            format("SELECT a -- first\n, b FROM t"),
+            "SELECT a -- first\n, b\nFROM t"
+        );
+    }
+
+    fn columns(sql: &str) -> Vec<String> {
+        column_names(sql).into_iter().collect()
+    }
+
+    #[test]
+    fn column_names_of_queries() {
+        assert_eq!(
+            columns("SELECT host, max(usage) AS m FROM cpu WHERE region = 'us' GROUP BY host"),
+            vec!["host", "region", "usage"]
+        );
+        assert_eq!(
+            columns("SELECT c.usage FROM cpu AS c JOIN mem ON c.host = mem.host"),
+            vec!["host", "usage"]
+        );
+    }
+
+    #[test]
+    fn column_names_exclude_qualified_tables() {
+        assert_eq!(
+            columns("SELECT query_text FROM system.queries"),
+            vec!["query_text"]
+        );
+        assert_eq!(
+            columns("SELECT column_name FROM information_schema.columns"),
+            vec!["column_name"]
+        );
+    }
+
+    #[test]
+    fn column_names_exclude_keyword_arguments() {
+        assert_eq!(
+            columns("SELECT extract(epoch FROM time) FROM cpu"),
+            vec!["time"]
+        );
+        assert_eq!(
+            columns("SELECT * FROM cpu WHERE host IN (SELECT host FROM mem)"),
+            vec!["host"]
+        );
+    }
+
+    #[test]
+    fn column_names_of_other_statements() {
+        assert!(columns("SHOW TABLES").is_empty());
+        assert!(columns("SHOW COLUMNS FROM cpu").is_empty());
+    }
+
+    #[test]
+    fn system_queries() {
+        assert!(is_system_query("SELECT * FROM system.queries"));
+        assert!(is_system_query(
+            "SELECT c.name FROM cpu JOIN information_schema.columns AS c ON true"
+        ));
+        assert!(is_system_query(
+            "select count(*) from \"system\".\"chunks\""
+        ));
+        assert!(is_system_query("SHOW TABLES"));
+    }
+
+    #[test]
+    fn user_tables_named_system() {
+        // Telegraf writes a `system` measurement
+        assert!(!is_system_query("SELECT system.load1 FROM system"));
+        assert!(!is_system_query(
+            "SELECT * FROM cpu WHERE host IN (SELECT system.host FROM system)"
+        ));
+        assert!(!is_system_query("SELECT * FROM cpu"));
+    }
+}
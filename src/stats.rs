@@ -0,0 +1,299 @@
+use std::cmp::Ordering;
+
+use rand::Rng;
+
+/// Two sided critical values of Student's t distribution at 95%
+/// confidence, for 1 to 30 degrees of freedom
+const T_95: [f64; 30] = [
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+    2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+    2.052, 2.048, 2.045, 2.042,
+];
+
+/// Critical value of the normal distribution at 95% confidence
+const Z_95: f64 = 1.96;
+
+/// The number of resamples used for bootstrap confidence intervals
+const BOOTSTRAP_RESAMPLES: usize = 1000;
+
+/// Samples further than this many interquartile ranges outside the
+/// quartiles are outliers (Tukey's fences)
+const OUTLIER_IQR_FACTOR: f64 = 1.5;
+
+/// Return the arithmetic mean of `samples`
+pub fn mean(samples: &[f64]) -> f64 {
+    samples.iter().sum::<f64>() / samples.len() as f64
+}
+
+/// Return the sample standard deviation of `samples`
+pub fn std_dev(samples: &[f64]) -> f64 {
+    if samples.len() < 2 {
+        return 0.0;
+    }
+    let mean = mean(samples);
+    let sum_squares: f64 = samples.iter().map(|s| (s - mean).powi(2)).sum();
+    (sum_squares / (samples.len() - 1) as f64).sqrt()
+}
+
+/// Return the standard deviation of `samples` relative to their mean
+pub fn coefficient_of_variation(samples: &[f64]) -> f64 {
+    let mean = mean(samples);
+    if mean == 0.0 {
+        return 0.0;
+    }
+    std_dev(samples) / mean
+}
+
+/// Return the standard error of the mean of `samples` relative to the
+/// mean, which (unlike the coefficient of variation) shrinks as more
+/// samples are taken
+pub fn relative_standard_error(samples: &[f64]) -> f64 {
+    coefficient_of_variation(samples) / (samples.len() as f64).sqrt()
+}
+
+/// Return the 95% confidence interval of the mean of `samples`, using
+/// Student's t distribution. Returns `None` for fewer than two samples
+pub fn mean_confidence_interval(samples: &[f64]) -> Option<(f64, f64)> {
+    let n = samples.len();
+    if n < 2 {
+        return None;
+    }
+
+    let critical = T_95.get(n - 2).copied().unwrap_or(Z_95);
+    let half_width = critical * std_dev(samples) / (n as f64).sqrt();
+    let mean = mean(samples);
+    Some((mean - half_width, mean + half_width))
+}
+
+/// Return the 95% bootstrap (percentile) confidence interval of
+/// `statistic(a, b)`, resampling `a` and `b` independently with
+/// replacement. Returns `None` if either set of samples is empty
+pub fn bootstrap_interval(
+    a: &[f64],
+    b: &[f64],
+    statistic: impl Fn(&[f64], &[f64]) -> f64,
+    rng: &mut impl Rng,
+) -> Option<(f64, f64)> {
+    if a.is_empty() || b.is_empty() {
+        return None;
+    }
+
+    let mut resample = |samples: &[f64]| -> Vec<f64> {
+        (0..samples.len())
+            .map(|_| samples[rng.gen_range(0..samples.len())])
+            .collect()
+    };
+
+    let mut estimates = vec![];
+    for _ in 0..BOOTSTRAP_RESAMPLES {
+        let (a, b) = (resample(a), resample(b));
+        let estimate = statistic(&a, &b);
+        if estimate.is_finite() {
+            estimates.push(estimate);
+        }
+    }
+    if estimates.is_empty() {
+        return None;
+    }
+
+    sort(&mut estimates);
+    Some((percentile(&estimates, 0.025), percentile(&estimates, 0.975)))
+}
+
+/// Return the two sided p-value of the Mann-Whitney U test that
+/// samples `a` and `b` come from the same distribution, using the
+/// normal approximation with tie and continuity corrections. Returns
+/// `None` if either set of samples is empty
+pub fn mann_whitney_p_value(a: &[f64], b: &[f64]) -> Option<f64> {
+    if a.is_empty() || b.is_empty() {
+        return None;
+    }
+    let (n1, n2) = (a.len() as f64, b.len() as f64);
+    let n = n1 + n2;
+
+    // (value, is from a), ranked together
+    let mut combined: Vec<(f64, bool)> = a
+        .iter()
+        .map(|v| (*v, true))
+        .chain(b.iter().map(|v| (*v, false)))
+        .collect();
+    combined.sort_by(|x, y| x.0.partial_cmp(&y.0).unwrap_or(Ordering::Equal));
+
+    // tied values all get the average of their ranks
+    let mut rank_sum_a = 0.0;
+    let mut tie_correction = 0.0;
+    let mut start = 0;
+    while start < combined.len() {
+        let mut end = start + 1;
+        while end < combined.len() && combined[end].0 == combined[start].0 {
+            end += 1;
+        }
+        let ties = (end - start) as f64;
+        let rank = (start + end + 1) as f64 / 2.0;
+        rank_sum_a += rank
+            * combined[start..end]
+                .iter()
+                .filter(|(_, in_a)| *in_a)
+                .count() as f64;
+        tie_correction += ties.powi(3) - ties;
+        start = end;
+    }
+
+    let u = rank_sum_a - n1 * (n1 + 1.0) / 2.0;
+    let mean_u = n1 * n2 / 2.0;
+    let variance = n1 * n2 / 12.0 * ((n + 1.0) - tie_correction / (n * (n - 1.0)));
+    if variance <= 0.0 || !variance.is_finite() {
+        // all samples are identical
+        return Some(1.0);
+    }
+
+    let z = ((u - mean_u).abs() - 0.5).max(0.0) / variance.sqrt();
+    Some(erfc(z / std::f64::consts::SQRT_2).min(1.0))
+}
+
+/// Return the indices of the samples that lie outside Tukey's fences
+pub fn outliers(samples: &[f64]) -> Vec<usize> {
+    if samples.len() < 4 {
+        return vec![];
+    }
+
+    let mut sorted = samples.to_vec();
+    sort(&mut sorted);
+    let (q1, q3) = (percentile(&sorted, 0.25), percentile(&sorted, 0.75));
+    let iqr = q3 - q1;
+    let (low, high) = (q1 - OUTLIER_IQR_FACTOR * iqr, q3 + OUTLIER_IQR_FACTOR * iqr);
+
+    samples
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| **s < low || **s > high)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+fn sort(samples: &mut [f64]) {
+    samples.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+}
+
+/// Return the `p` quantile of non empty `sorted` samples, interpolating
+/// between neighbouring samples
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = p * (sorted.len() - 1) as f64;
+    let (lower, upper) = (rank.floor() as usize, rank.ceil() as usize);
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64)
+}
+
+/// The complementary error function, for `x >= 0`, accurate to about
+/// 1e-7 (Numerical Recipes `erfcc`)
+fn erfc(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.5 * x);
+    t * (-x * x - 1.26551223
+        + t * (1.00002368
+            + t * (0.37409196
+                + t * (0.09678418
+                    + t * (-0.18628806
+                        + t * (0.27886807
+                            + t * (-1.13520398
+                                + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277)))))))))
+        .exp()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn summary_statistics() {
+        let samples = [1.0, 2.0, 3.0, 4.0, 5.0];
+        assert_eq!(mean(&samples), 3.0);
+        assert_close(std_dev(&samples), 1.581139, 1e-6);
+        assert_close(coefficient_of_variation(&samples), 0.527046, 1e-6);
+        assert_eq!(std_dev(&[7.0]), 0.0);
+        assert_eq!(coefficient_of_variation(&[0.0, 0.0]), 0.0);
+        assert_close(relative_standard_error(&samples), 0.235702, 1e-6);
+    }
+
+    #[test]
+    fn relative_standard_error_shrinks_with_more_samples() {
+        let samples = |n: usize| -> Vec<f64> { (0..n).map(|i| (10 + i % 3) as f64).collect() };
+        let (few, many) = (samples(9), samples(900));
+        assert_close(
+            coefficient_of_variation(&few),
+            coefficient_of_variation(&many),
+            0.01,
+        );
+        assert_close(
+            relative_standard_error(&many),
+            relative_standard_error(&few) / 10.0,
+            0.001,
+        );
+    }
+
+    #[test]
+    fn t_confidence_interval() {
+        // t(4) = 2.776, standard error = 1.5811 / sqrt(5)
+        let (low, high) = mean_confidence_interval(&[1.0, 2.0, 3.0, 4.0, 5.0]).unwrap();
+        assert_close(low, 3.0 - 1.962929, 1e-5);
+        assert_close(high, 3.0 + 1.962929, 1e-5);
+
+        // beyond the table the normal approximation is used
+        let samples: Vec<f64> = (0..100).map(|i| (i % 2) as f64).collect();
+        let (low, high) = mean_confidence_interval(&samples).unwrap();
+        assert_close(high - low, 2.0 * 1.96 * std_dev(&samples) / 10.0, 1e-9);
+
+        assert_eq!(mean_confidence_interval(&[1.0]), None);
+    }
+
+    #[test]
+    fn mann_whitney() {
+        // U = 0 for completely separated samples
+        let a = [1.0, 2.0, 3.0, 4.0, 5.0];
+        let b = [6.0, 7.0, 8.0, 9.0, 10.0];
+        assert_close(mann_whitney_p_value(&a, &b).unwrap(), 0.01219, 1e-4);
+        assert_eq!(mann_whitney_p_value(&a, &b), mann_whitney_p_value(&b, &a));
+
+        // interleaved samples are indistinguishable
+        let c = [1.5, 2.5, 3.5, 4.5, 5.5];
+        assert!(mann_whitney_p_value(&a, &c).unwrap() > 0.5);
+
+        assert_eq!(
+            mann_whitney_p_value(&[2.0, 2.0], &[2.0, 2.0, 2.0]),
+            Some(1.0)
+        );
+        assert_eq!(mann_whitney_p_value(&[], &a), None);
+    }
+
+    #[test]
+    fn bootstrap() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let a: Vec<f64> = (0..50).map(|i| 10.0 + (i % 5) as f64).collect();
+        let b: Vec<f64> = a.iter().map(|v| v + 5.0).collect();
+
+        let (low, high) = bootstrap_interval(&a, &b, |a, b| mean(b) - mean(a), &mut rng).unwrap();
+        assert!(low < 5.0 && 5.0 < high, "({}, {})", low, high);
+        assert!(high - low < 2.0, "({}, {})", low, high);
+
+        assert_eq!(
+            bootstrap_interval(&[], &b, |a, b| mean(b) - mean(a), &mut rng),
+            None
+        );
+    }
+
+    #[test]
+    fn tukey_outliers() {
+        assert_eq!(outliers(&[1.0, 2.0, 2.0, 3.0, 2.0, 50.0]), vec![5]);
+        assert!(outliers(&[1.0, 2.0, 3.0]).is_empty());
+    }
+}
@@ -0,0 +1,146 @@
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+
+pub type Result<T, E = String> = std::result::Result<T, E>;
+
+/// Parse a timestamp into nanoseconds since the epoch (UTC).
+///
+/// Accepts the format used by `system.queries` (e.g.
+/// `2021-12-16 15:06:22.456268343`), RFC3339 (e.g.
+/// `2021-12-16T15:06:22Z`) or an integer number of nanoseconds.
+pub fn parse_timestamp(s: &str) -> Result<i64> {
+    let s = s.trim();
+    if let Ok(nanos) = s.parse::<i64>() {
+        return Ok(nanos);
+    }
+
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return checked_nanos(&t.naive_utc(), s);
+    }
+
+    for format in ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"] {
+        if let Ok(t) = NaiveDateTime::parse_from_str(s, format) {
+            return checked_nanos(&t, s);
+        }
+    }
+
+    Err(format!("Can not parse '{}' as a timestamp", s))
+}
+
+/// Return `t` as nanoseconds since the epoch, or an error if it is
+/// outside the range of an `i64` (roughly the years 1677 to 2262)
+fn checked_nanos(t: &NaiveDateTime, s: &str) -> Result<i64> {
+    t.timestamp()
+        .checked_mul(1_000_000_000)
+        .and_then(|nanos| nanos.checked_add(t.timestamp_subsec_nanos() as i64))
+        .ok_or_else(|| format!("Timestamp '{}' is out of range", s))
+}
+
+/// Format `nanos` since the epoch as an RFC3339 timestamp
+pub fn format_timestamp(nanos: i64) -> String {
+    Utc.timestamp_nanos(nanos)
+        .to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+/// Parse a duration such as `30d`, `-2h`, `1h30m` or `500ms` into
+/// nanoseconds. A plain integer is a number of nanoseconds. Supported
+/// units are `w`, `d`, `h`, `m`, `s`, `ms`, `us` and `ns`
+pub fn parse_duration(s: &str) -> Result<i64> {
+    let s = s.trim();
+    if let Ok(nanos) = s.parse::<i64>() {
+        return Ok(nanos);
+    }
+
+    let (negative, mut rest) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    if rest.is_empty() {
+        return Err(format!("Can not parse '{}' as a duration", s));
+    }
+
+    let mut nanos: i64 = 0;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let units = rest[digits..]
+            .find(|c: char| c.is_ascii_digit())
+            .map(|i| digits + i)
+            .unwrap_or(rest.len());
+
+        let value: i64 = rest[..digits]
+            .parse()
+            .map_err(|_| format!("Can not parse '{}' as a duration", s))?;
+        let unit = match &rest[digits..units] {
+            "w" => 7 * 24 * 3_600_000_000_000,
+            "d" => 24 * 3_600_000_000_000,
+            "h" => 3_600_000_000_000,
+            "m" => 60_000_000_000,
+            "s" => 1_000_000_000,
+            "ms" => 1_000_000,
+            "us" => 1_000,
+            "ns" => 1,
+            other => {
+                return Err(format!(
+                    "Unknown unit '{}' in duration '{}', expected w, d, h, m, s, ms, us or ns",
+                    other, s
+                ))
+            }
+        };
+
+        nanos = value
+            .checked_mul(unit)
+            .and_then(|v| nanos.checked_add(v))
+            .ok_or_else(|| format!("Duration '{}' is out of range", s))?;
+        rest = &rest[units..];
+    }
+
+    Ok(if negative { -nanos } else { nanos })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps() {
+        let nanos = 1_639_667_182_456_268_343;
+        assert_eq!(parse_timestamp("2021-12-16 15:06:22.456268343"), Ok(nanos));
+        assert_eq!(parse_timestamp("2021-12-16T15:06:22.456268343"), Ok(nanos));
+        assert_eq!(parse_timestamp("2021-12-16T15:06:22.456268343Z"), Ok(nanos));
+        assert_eq!(
+            parse_timestamp("2021-12-16T16:06:22.456268343+01:00"),
+            Ok(nanos)
+        );
+        assert_eq!(parse_timestamp(" 1639667182456268343 "), Ok(nanos));
+        assert_eq!(parse_timestamp("1970-01-01 00:00:00"), Ok(0));
+        assert_eq!(format_timestamp(nanos), "2021-12-16T15:06:22.456268343Z");
+
+        assert!(parse_timestamp("yesterday").is_err());
+        assert!(parse_timestamp("2021-12-16").is_err());
+    }
+
+    #[test]
+    fn timestamps_out_of_range() {
+        assert!(parse_timestamp("9999-12-31 00:00:00").is_err());
+        assert!(parse_timestamp("9999-12-31T00:00:00Z").is_err());
+        assert!(parse_timestamp("1600-01-01T00:00:00").is_err());
+        assert!(parse_timestamp("2262-04-11 23:47:16").is_ok());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("500"), Ok(500));
+        assert_eq!(parse_duration("500ms"), Ok(500_000_000));
+        assert_eq!(parse_duration("1h30m"), Ok(5_400_000_000_000));
+        assert_eq!(parse_duration("-2h"), Ok(-7_200_000_000_000));
+        assert_eq!(parse_duration("1w"), parse_duration("7d"));
+        assert_eq!(parse_duration("1s10us5ns"), Ok(1_000_010_005));
+
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("-").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("3y").is_err());
+        assert!(parse_duration("1000000w").is_err());
+    }
+}
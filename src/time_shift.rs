@@ -0,0 +1,465 @@
+use structopt::StructOpt;
+
+use crate::{
+    query::{Query, StorageRpc},
+    sql::{tokenize, Token},
+    time::{format_timestamp, parse_duration, parse_timestamp},
+};
+
+pub type Result<T, E = String> = std::result::Result<T, E>;
+
+/// Comparison operators that may relate the `time` column to a literal
+const TIME_COMPARISONS: &[&str] = &["=", "!=", "<>", "<", "<=", ">", ">="];
+
+/// Options to move the time ranges of queries before replaying them, so
+/// that queries captured in the past select data that exists in the
+/// target database.
+///
+/// Storage RPC requests have their `range` moved. SQL queries have the
+/// timestamp (string) and integer literals compared with `time` moved,
+/// such as in `time > '2021-12-16T15:06:22Z'`, `1639667182000000000 <
+/// time` or `time BETWEEN ... AND ...`. Other literals are left alone.
+#[derive(Debug, StructOpt)]
+pub struct TimeShift {
+    /// Move the times selected by every query by this duration, such as
+    /// `30d`, `-2h` or `1h30m`
+    #[structopt(long, parse(try_from_str = parse_duration), allow_hyphen_values = true)]
+    time_shift: Option<i64>,
+
+    /// Move the times selected by every query by the same amount, so
+    /// that the latest time selected by any query is this timestamp
+    #[structopt(long, parse(try_from_str = parse_timestamp))]
+    align_to: Option<i64>,
+}
+
+impl TimeShift {
+    /// Return the number of nanoseconds to move the times of `queries`
+    /// by, if any
+    pub fn nanos(&self, queries: &[Query]) -> Result<Option<i64>> {
+        match (self.time_shift, self.align_to) {
+            (None, None) => Ok(None),
+            (Some(nanos), None) => Ok(Some(nanos)),
+            (None, Some(align_to)) => {
+                let latest = queries
+                    .iter()
+                    .filter_map(|q| q.latest_time())
+                    .max()
+                    .ok_or("--align-to given but no query selects a time range")?;
+                Ok(Some(align_to.saturating_sub(latest)))
+            }
+            (Some(_), Some(_)) => Err("Specify at most one of --time-shift or --align-to".into()),
+        }
+    }
+
+    /// Return `queries` with their times moved as requested
+    pub fn apply(&self, queries: Vec<Query>) -> Result<Vec<Query>> {
+        match self.nanos(&queries)? {
+            Some(nanos) => {
+                println!("Shifting the times of all queries by {}ns", nanos);
+                Ok(queries.into_iter().map(|q| q.shift_time(nanos)).collect())
+            }
+            None => Ok(queries),
+        }
+    }
+}
+
+impl Query {
+    /// Return this query with all the times it selects moved by `nanos`
+    pub fn shift_time(self, nanos: i64) -> Self {
+        match self {
+            Query::Sql(sql) => {
+                let mut tokens = tokenize(&sql);
+                for (position, time) in time_literals(&tokens) {
+                    let shifted = time.saturating_add(nanos);
+                    tokens[position] = match &tokens[position] {
+                        Token::Number(_) => Token::Number(shifted.to_string()),
+                        _ => Token::StringLiteral(format_timestamp(shifted)),
+                    };
+                }
+                Query::Sql(tokens.iter().map(|t| t.to_string()).collect())
+            }
+            Query::StorageRpc(StorageRpc::ReadFilter(mut request)) => {
+                if let Some(range) = request.range.as_mut() {
+                    // the extremes mean the range is unbounded
+                    if range.start != i64::MIN {
+                        range.start = range.start.saturating_add(nanos);
+                    }
+                    if range.end != i64::MAX {
+                        range.end = range.end.saturating_add(nanos);
+                    }
+                }
+                Query::StorageRpc(StorageRpc::ReadFilter(request))
+            }
+        }
+    }
+
+    /// Return the latest time this query explicitly selects, if any
+    pub fn latest_time(&self) -> Option<i64> {
+        match self {
+            Query::Sql(sql) => time_literals(&tokenize(sql))
+                .into_iter()
+                .map(|(_, time)| time)
+                .max(),
+            Query::StorageRpc(StorageRpc::ReadFilter(request)) => {
+                let range = request.range.as_ref()?;
+                if range.end != i64::MAX {
+                    Some(range.end)
+                } else if range.start != i64::MIN {
+                    Some(range.start)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// A literal that the `time` column is compared with in a SQL query
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeComparison {
+    /// The position of the literal in the tokens
+    pub position: usize,
+    /// The position of the comparison operator, if it is a binary
+    /// comparison rather than `BETWEEN`
+    pub operator: Option<usize>,
+    /// The comparison, as if written `time <op> literal`. The bounds of
+    /// `BETWEEN` are `>=` and `<=`, and those of `NOT BETWEEN` are
+    /// `not between`
+    pub op: &'static str,
+    /// Is the literal written before `time`, as in `'2021-12-16' < time`?
+    pub reversed: bool,
+}
+
+/// Return the comparison with `op` reversed, so that `a <op> b` is
+/// `b <reversed op> a`
+pub fn reverse_comparison(op: &'static str) -> &'static str {
+    match op {
+        "<" => ">",
+        "<=" => ">=",
+        ">" => "<",
+        ">=" => "<=",
+        other => other,
+    }
+}
+
+/// Return the literals in `tokens` that the `time` column is compared
+/// with, by `time <op> literal`, `literal <op> time` or
+/// `time [NOT] BETWEEN literal AND literal`. Literals may be written as
+/// `TIMESTAMP '...'` or `'...'::timestamp`
+pub fn time_comparisons(tokens: &[Token]) -> Vec<TimeComparison> {
+    let significant: Vec<usize> = (0..tokens.len())
+        .filter(|&i| !matches!(tokens[i], Token::Whitespace(_)))
+        .collect();
+    let at = |k: Option<usize>| k.and_then(|k| significant.get(k)).map(|&i| &tokens[i]);
+    let is_word = |k: Option<usize>, word: &str| matches!(at(k), Some(Token::Word(w)) if w.eq_ignore_ascii_case(word));
+    let is_time = |k: Option<usize>| match at(k) {
+        Some(Token::Word(w)) | Some(Token::QuotedIdent(w)) => w.eq_ignore_ascii_case("time"),
+        _ => false,
+    };
+    let operator = |k: Option<usize>| match at(k) {
+        Some(Token::Punct(p)) => TIME_COMPARISONS.iter().find(|op| **op == p).copied(),
+        _ => None,
+    };
+    let is_literal_part = |k: Option<usize>| match at(k) {
+        Some(Token::StringLiteral(_)) | Some(Token::Number(_)) => true,
+        Some(Token::Punct(p)) => p == "::",
+        Some(Token::Word(w)) => !w.eq_ignore_ascii_case("and") && !w.eq_ignore_ascii_case("or"),
+        _ => false,
+    };
+    // is the `BETWEEN` at `k` preceded by `time` or `time NOT`? Returns
+    // whether it is negated
+    let between_time = |k: usize| {
+        if is_time(k.checked_sub(1)) {
+            Some(false)
+        } else if is_word(k.checked_sub(1), "not") && is_time(k.checked_sub(2)) {
+            Some(true)
+        } else {
+            None
+        }
+    };
+
+    let mut comparisons = vec![];
+    for (k, &position) in significant.iter().enumerate() {
+        if !matches!(tokens[position], Token::Number(_) | Token::StringLiteral(_)) {
+            continue;
+        }
+        // the extent of the literal, including any type
+        let first = if is_word(k.checked_sub(1), "timestamp") {
+            k - 1
+        } else {
+            k
+        };
+        let last = if matches!(at(Some(k + 1)), Some(Token::Punct(p)) if p == "::")
+            && matches!(at(Some(k + 2)), Some(Token::Word(_)))
+        {
+            k + 2
+        } else {
+            k
+        };
+        let before = |n: usize| first.checked_sub(n);
+
+        let comparison = if let (Some(op), true) = (operator(before(1)), is_time(before(2))) {
+            Some((op, before(1), false))
+        } else if let (Some(op), true) = (operator(Some(last + 1)), is_time(Some(last + 2))) {
+            Some((reverse_comparison(op), Some(last + 1), true))
+        } else if is_word(before(1), "between") {
+            between_time(first - 1)
+                .map(|negated| (if negated { "not between" } else { ">=" }, None, false))
+        } else if is_word(before(1), "and") && is_literal_part(before(2)) {
+            // the upper bound of `BETWEEN`, after a lower bound of up to
+            // three tokens
+            (3..=5)
+                .take_while(|&n| n == 3 || is_literal_part(before(n - 1)))
+                .find(|&n| is_word(before(n), "between"))
+                .and_then(|n| between_time(first - n))
+                .map(|negated| (if negated { "not between" } else { "<=" }, None, false))
+        } else {
+            None
+        };
+
+        if let Some((op, operator, reversed)) = comparison {
+            comparisons.push(TimeComparison {
+                position,
+                operator: operator.map(|k| significant[k]),
+                op,
+                reversed,
+            });
+        }
+    }
+    comparisons
+}
+
+/// Return the value (in nanoseconds) of a literal that is compared with
+/// `time`, if it is a timestamp
+fn literal_time(token: &Token) -> Option<i64> {
+    match token {
+        Token::Number(n) => n.parse().ok(),
+        Token::StringLiteral(s) if s.trim().parse::<i64>().is_err() => parse_timestamp(s).ok(),
+        _ => None,
+    }
+}
+
+/// Return the position and value (in nanoseconds) of each literal in
+/// `tokens` that the `time` column is compared with
+fn time_literals(tokens: &[Token]) -> Vec<(usize, i64)> {
+    time_comparisons(tokens)
+        .into_iter()
+        .filter_map(|c| Some((c.position, literal_time(&tokens[c.position])?)))
+        .collect()
+}
+
+/// Return the `[start, end)` range of times selected by comparisons of
+/// `time` with literals in `sql`, `None` where it is unbounded
+pub fn sql_time_range(sql: &str) -> (Option<i64>, Option<i64>) {
+    let tokens = tokenize(sql);
+    let (mut start, mut end): (Option<i64>, Option<i64>) = (None, None);
+
+    // only comparisons that must all hold bound the range, not those
+    // that are alternatives or negated
+    let conjuncts = conjunct_tokens(&tokens);
+    for comparison in time_comparisons(&tokens) {
+        if !conjuncts[comparison.position] {
+            continue;
+        }
+        let time = match literal_time(&tokens[comparison.position]) {
+            Some(time) => time,
+            None => continue,
+        };
+        let (lower, upper) = match comparison.op {
+            ">" => (Some(time.saturating_add(1)), None),
+            ">=" => (Some(time), None),
+            "<" => (None, Some(time)),
+            "<=" => (None, Some(time.saturating_add(1))),
+            "=" => (Some(time), Some(time.saturating_add(1))),
+            _ => (None, None),
+        };
+        start = start.max(lower);
+        end = match (end, upper) {
+            (Some(end), Some(upper)) => Some(end.min(upper)),
+            (end, upper) => end.or(upper),
+        };
+    }
+    (start, end)
+}
+
+/// Return, for each of `tokens`, whether it is a conjunct of the whole
+/// text: neither the text nor any parenthesized group it is within
+/// contains `OR` or is negated by `NOT`
+fn conjunct_tokens(tokens: &[Token]) -> Vec<bool> {
+    // for each group, whether it contains alternatives or is negated,
+    // and the group it is within. Group 0 is the whole text
+    let mut groups = vec![(false, 0)];
+    let mut current = 0;
+    let mut token_groups = Vec::with_capacity(tokens.len());
+    let mut previous: Option<&Token> = None;
+    for token in tokens {
+        match token {
+            Token::Punct(p) if p == "(" => {
+                let negated =
+                    matches!(previous, Some(Token::Word(w)) if w.eq_ignore_ascii_case("not"));
+                groups.push((negated, current));
+                current = groups.len() - 1;
+            }
+            Token::Punct(p) if p == ")" => {
+                token_groups.push(current);
+                current = groups[current].1;
+                previous = Some(token);
+                continue;
+            }
+            Token::Word(w) if w.eq_ignore_ascii_case("or") => groups[current].0 = true,
+            _ => {}
+        }
+        token_groups.push(current);
+        if !matches!(token, Token::Whitespace(_)) {
+            previous = Some(token);
+        }
+    }
+
+    token_groups
+        .into_iter()
+        .map(|mut group| loop {
+            let (disjunctive, parent) = groups[group];
+            if disjunctive {
+                return false;
+            }
+            if group == 0 {
+                return true;
+            }
+            group = parent;
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shift(sql: &str, nanos: i64) -> String {
+        match Query::Sql(sql.to_string()).shift_time(nanos) {
+            Query::Sql(sql) => sql,
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    const HOUR: i64 = 3_600_000_000_000;
+
+    #[test]
+    fn shifts_time_comparisons() {
+        assert_eq!(
+            shift(
+                "SELECT * FROM cpu WHERE time >= '2021-12-16T15:00:00Z'",
+                HOUR
+            ),
+            "SELECT * FROM cpu WHERE time >= '2021-12-16T16:00:00Z'"
+        );
+        assert_eq!(
+            shift("SELECT * FROM cpu WHERE cpu.time < 1639667182000000000", 1),
+            "SELECT * FROM cpu WHERE cpu.time < 1639667182000000001"
+        );
+        assert_eq!(
+            shift(
+                "SELECT * FROM cpu WHERE time > TIMESTAMP '2021-12-16 15:00:00' \
+                 AND time < '2021-12-16 16:00:00'::timestamp",
+                HOUR
+            ),
+            "SELECT * FROM cpu WHERE time > TIMESTAMP '2021-12-16T16:00:00Z' \
+             AND time < '2021-12-16T17:00:00Z'::timestamp"
+        );
+    }
+
+    #[test]
+    fn shifts_reversed_and_between() {
+        assert_eq!(
+            shift(
+                "SELECT * FROM cpu WHERE '2021-12-16T15:00:00Z' <= time",
+                HOUR
+            ),
+            "SELECT * FROM cpu WHERE '2021-12-16T16:00:00Z' <= time"
+        );
+        assert_eq!(
+            shift(
+                "SELECT * FROM cpu WHERE time BETWEEN '2021-12-16T15:00:00Z' AND '2021-12-16T16:00:00Z'",
+                HOUR
+            ),
+            "SELECT * FROM cpu WHERE time BETWEEN '2021-12-16T16:00:00Z' AND '2021-12-16T17:00:00Z'"
+        );
+        assert_eq!(
+            shift("SELECT * FROM cpu WHERE time NOT BETWEEN 10 AND 20", 5),
+            "SELECT * FROM cpu WHERE time NOT BETWEEN 15 AND 25"
+        );
+    }
+
+    #[test]
+    fn leaves_other_literals() {
+        for sql in [
+            "SELECT * FROM cpu WHERE host = '2021-12-16 15:00:00'",
+            "SELECT date_bin(interval '1 hour', time, '1970-01-01T00:00:00Z') FROM cpu",
+            "SELECT '2021-12-16T15:00:00Z' AS t, 5 FROM cpu WHERE usage > 1639667182000000000",
+            "SELECT * FROM cpu WHERE time > now() - interval '1 hour'",
+            "SELECT * FROM cpu WHERE x BETWEEN 1 AND 2 AND time IS NOT NULL",
+        ] {
+            assert_eq!(shift(sql, HOUR), sql);
+        }
+    }
+
+    #[test]
+    fn out_of_range_timestamps_are_ignored() {
+        let sql = "SELECT * FROM cpu WHERE time < '9999-12-31'";
+        assert_eq!(shift(sql, HOUR), sql);
+        assert_eq!(Query::Sql(sql.to_string()).latest_time(), None);
+    }
+
+    #[test]
+    fn time_ranges() {
+        assert_eq!(
+            sql_time_range("SELECT * FROM cpu WHERE time >= 10 AND time < 20"),
+            (Some(10), Some(20))
+        );
+        assert_eq!(
+            sql_time_range("SELECT * FROM cpu WHERE 10 < time AND 20 >= time"),
+            (Some(11), Some(21))
+        );
+        assert_eq!(
+            sql_time_range("SELECT * FROM cpu WHERE time BETWEEN 10 AND 20"),
+            (Some(10), Some(21))
+        );
+        assert_eq!(
+            sql_time_range("SELECT * FROM cpu WHERE time NOT BETWEEN 10 AND 20"),
+            (None, None)
+        );
+        assert_eq!(
+            sql_time_range("SELECT * FROM cpu WHERE host = '2021-12-16T15:00:00Z'"),
+            (None, None)
+        );
+    }
+
+    #[test]
+    fn time_ranges_ignore_alternatives() {
+        assert_eq!(
+            sql_time_range("SELECT * FROM cpu WHERE time < 10 OR time > 20"),
+            (None, None)
+        );
+        assert_eq!(
+            sql_time_range("SELECT * FROM cpu WHERE time >= 10 AND time < 20 OR host = 'a'"),
+            (None, None)
+        );
+        assert_eq!(
+            sql_time_range(
+                "SELECT * FROM cpu WHERE time >= 10 AND (time < 20 OR time > 30) AND time < 40"
+            ),
+            (Some(10), Some(40))
+        );
+        assert_eq!(
+            sql_time_range("SELECT * FROM cpu WHERE (time >= 10 AND time < 20) AND host = 'a'"),
+            (Some(10), Some(20))
+        );
+        assert_eq!(
+            sql_time_range("SELECT * FROM cpu WHERE NOT (time >= 10) AND time < 20"),
+            (None, Some(20))
+        );
+        assert_eq!(
+            sql_time_range("SELECT * FROM cpu WHERE time >= 10 AND host = 'or'"),
+            (Some(10), None)
+        );
+    }
+}
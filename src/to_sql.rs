@@ -0,0 +1,353 @@
+use std::path::Path;
+
+use generated_types::influxdata::platform::storage::{
+    node::{Comparison, Logical, Type, Value},
+    Node, ReadFilterRequest,
+};
+use structopt::StructOpt;
+
+use crate::{
+    predicate::{
+        comparison_op, measurement_equality, split_conjuncts, tag_name, NodeDisplay, TAG_KEY_FIELD,
+        TAG_KEY_MEASUREMENT,
+    },
+    query::{Query, StorageRpc},
+    query_log::QueryLog,
+    show::parse_selection,
+    sql::Token,
+    time::format_timestamp,
+};
+
+pub type Result<T, E = String> = std::result::Result<T, E>;
+
+/// Print the SQL equivalent of the Storage RPC requests in a saved log
+#[derive(Debug, StructOpt)]
+pub struct ToSql {
+    /// The filename of the query log
+    filename: String,
+
+    /// Which queries to convert, by index, such as `3`, `2-5` or
+    /// `1,4,7-9`. Converts all queries if not specified
+    selection: Option<String>,
+}
+
+impl ToSql {
+    pub async fn execute(&self) -> Result<()> {
+        let log = QueryLog::new_from_file(Path::new(&self.filename)).await?;
+
+        let indices = match &self.selection {
+            Some(selection) => parse_selection(selection, log.queries.len())?,
+            None => (0..log.queries.len()).collect(),
+        };
+
+        for i in indices {
+            match log.queries[i].query() {
+                Query::Sql(_) => println!("query {}: already sql", i),
+                Query::StorageRpc(StorageRpc::ReadFilter(request)) => {
+                    match read_filter_to_sql(request) {
+                        Ok(sql) => println!("query {}: {}", i, sql),
+                        Err(e) => println!("query {}: can not convert: {}", i, e),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Convert a `ReadFilterRequest` into an IOx SQL query that selects the
+/// same data.
+///
+/// The predicate must restrict the request to a single measurement,
+/// which becomes the table. Field restrictions (`_field = 'f'`) become
+/// `"f" IS NOT NULL` as the columns of the table are not known.
+pub fn read_filter_to_sql(request: &ReadFilterRequest) -> Result<String> {
+    let mut conjuncts = vec![];
+    if let Some(root) = request.predicate.as_ref().and_then(|p| p.root.as_ref()) {
+        split_conjuncts(root, &mut conjuncts);
+    }
+
+    let mut measurement = None;
+    let mut rest = vec![];
+    for node in conjuncts {
+        match measurement_equality(node) {
+            Some(name) if measurement.is_none() || measurement == Some(name) => {
+                measurement = Some(name)
+            }
+            Some(name) => {
+                return Err(format!(
+                    "predicate restricts to more than one measurement ({} and {})",
+                    measurement.unwrap_or_default(),
+                    name
+                ))
+            }
+            None => rest.push(node),
+        }
+    }
+    let measurement = measurement.ok_or("predicate does not restrict to a single measurement")?;
+
+    let mut exprs = vec![];
+    if let Some(range) = &request.range {
+        if range.start != i64::MIN {
+            exprs.push(format!("time >= {}", timestamp_literal(range.start)));
+        }
+        if range.end != i64::MAX {
+            exprs.push(format!("time < {}", timestamp_literal(range.end)));
+        }
+    }
+    for node in rest {
+        exprs.push(to_expr(node, measurement)?);
+    }
+
+    let mut sql = format!("SELECT * FROM {}", identifier(measurement));
+    if !exprs.is_empty() {
+        sql.push_str(" WHERE ");
+        sql.push_str(&exprs.join(" AND "));
+    }
+    sql.push_str(" ORDER BY time");
+    Ok(sql)
+}
+
+/// Convert a predicate node into a SQL expression. `measurement` is the
+/// table being queried
+fn to_expr(node: &Node, measurement: &str) -> Result<String> {
+    match Type::from_i32(node.node_type) {
+        Some(Type::LogicalExpression) => {
+            let op = match node.value {
+                Some(Value::Logical(l)) if l == Logical::Or as i32 => " OR ",
+                _ => " AND ",
+            };
+            let children = node
+                .children
+                .iter()
+                .map(|child| to_expr(child, measurement))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(format!("({})", children.join(op)))
+        }
+        Some(Type::ParenExpression) => {
+            let children = node
+                .children
+                .iter()
+                .map(|child| to_expr(child, measurement))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(format!("({})", children.join(" ")))
+        }
+        Some(Type::ComparisonExpression) => comparison_to_expr(node, measurement),
+        Some(Type::TagRef) | Some(Type::FieldRef) | Some(Type::Literal) => operand(node),
+        None => Err(format!("unknown node type {}", node.node_type)),
+    }
+}
+
+fn comparison_to_expr(node: &Node, measurement: &str) -> Result<String> {
+    let comparison = match node.value {
+        Some(Value::Comparison(c)) => Comparison::from_i32(c),
+        _ => None,
+    }
+    .ok_or("comparison without a valid operator")?;
+
+    let (left, right) = match node.children.as_slice() {
+        [left, right] => (left, right),
+        _ => return Err("comparison without two operands".to_string()),
+    };
+
+    match &left.value {
+        Some(Value::TagRefValue(key)) if key.as_slice() == TAG_KEY_MEASUREMENT => {
+            // `_measurement = '<table>'` nested in an expression
+            match (comparison, &right.value) {
+                (Comparison::Equal, Some(Value::StringValue(name))) => {
+                    Ok((name == measurement).to_string())
+                }
+                _ => Err(format!(
+                    "unsupported measurement predicate {}",
+                    NodeDisplay::new(node)
+                )),
+            }
+        }
+        Some(Value::TagRefValue(key)) if key.as_slice() == TAG_KEY_FIELD => {
+            match (comparison, &right.value) {
+                (Comparison::Equal, Some(Value::StringValue(field))) => {
+                    Ok(format!("{} IS NOT NULL", identifier(field)))
+                }
+                _ => Err(format!(
+                    "unsupported field predicate {}",
+                    NodeDisplay::new(node)
+                )),
+            }
+        }
+        Some(Value::TagRefValue(key)) => {
+            let tag = identifier(&tag_name(key));
+            let value = match &right.value {
+                Some(Value::StringValue(value)) => value,
+                Some(Value::RegexValue(regex)) => regex,
+                _ => return Err(format!("unsupported value for tag {}", tag)),
+            };
+
+            // an empty tag value matches series without that tag
+            Ok(match comparison {
+                Comparison::Equal if value.is_empty() => format!("{} IS NULL", tag),
+                Comparison::NotEqual if value.is_empty() => format!("{} IS NOT NULL", tag),
+                Comparison::NotEqual => {
+                    format!("({} IS NULL OR {} != {})", tag, tag, string_literal(value))
+                }
+                Comparison::Regex => format!("{} ~ {}", tag, string_literal(value)),
+                Comparison::NotRegex => {
+                    format!("({} IS NULL OR {} !~ {})", tag, tag, string_literal(value))
+                }
+                Comparison::StartsWith => starts_with(&tag, value),
+                _ => format!(
+                    "{} {} {}",
+                    tag,
+                    comparison_op(comparison),
+                    string_literal(value)
+                ),
+            })
+        }
+        _ => {
+            let left = operand(left)?;
+            let right = operand(right)?;
+            let op = match comparison {
+                Comparison::Regex => "~",
+                Comparison::NotRegex => "!~",
+                Comparison::StartsWith => return Err("startsWith on a field".to_string()),
+                other => comparison_op(other),
+            };
+            Ok(format!("{} {} {}", left, op, right))
+        }
+    }
+}
+
+/// Convert a leaf node into SQL
+fn operand(node: &Node) -> Result<String> {
+    match &node.value {
+        Some(Value::TagRefValue(key)) => Ok(identifier(&tag_name(key))),
+        Some(Value::FieldRefValue(field)) if field == "_value" => {
+            Err("_value references require a known field".to_string())
+        }
+        Some(Value::FieldRefValue(field)) => Ok(identifier(field)),
+        Some(Value::StringValue(s)) | Some(Value::RegexValue(s)) => Ok(string_literal(s)),
+        Some(Value::BoolValue(b)) => Ok(b.to_string()),
+        Some(Value::IntValue(i)) => Ok(i.to_string()),
+        Some(Value::UintValue(u)) => Ok(u.to_string()),
+        Some(Value::FloatValue(v)) => Ok(v.to_string()),
+        other => Err(format!("unsupported operand {:?}", other)),
+    }
+}
+
+fn identifier(name: &str) -> String {
+    Token::QuotedIdent(name.to_string()).to_string()
+}
+
+/// Return a `LIKE` expression matching values of `tag` that start with
+/// `prefix`, escaping any wildcards (`%` and `_`) in `prefix`
+fn starts_with(tag: &str, prefix: &str) -> String {
+    let mut pattern = String::new();
+    for c in prefix.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    let escaped = pattern.len() > prefix.len();
+    pattern.push('%');
+
+    if escaped {
+        format!("{} LIKE {} ESCAPE '\\'", tag, string_literal(&pattern))
+    } else {
+        format!("{} LIKE {}", tag, string_literal(&pattern))
+    }
+}
+
+fn string_literal(value: &str) -> String {
+    Token::StringLiteral(value.to_string()).to_string()
+}
+
+fn timestamp_literal(nanos: i64) -> String {
+    string_literal(&format_timestamp(nanos))
+}
+
+#[cfg(test)]
+mod tests {
+    use generated_types::influxdata::platform::storage::{Predicate, TimestampRange};
+
+    use super::*;
+    use crate::predicate::{and, tag_comparison};
+
+    fn request(nodes: Vec<Node>, range: Option<TimestampRange>) -> ReadFilterRequest {
+        ReadFilterRequest {
+            range,
+            predicate: Some(Predicate { root: and(nodes) }),
+            ..Default::default()
+        }
+    }
+
+    fn string(s: &str) -> Value {
+        Value::StringValue(s.to_string())
+    }
+
+    #[test]
+    fn tags_fields_and_time_range() {
+        let request = request(
+            vec![
+                tag_comparison(TAG_KEY_MEASUREMENT, Comparison::Equal, string("cpu")),
+                tag_comparison(b"host", Comparison::Equal, string("server01")),
+                tag_comparison(TAG_KEY_FIELD, Comparison::Equal, string("usage_idle")),
+            ],
+            Some(TimestampRange {
+                start: 1_639_667_182_000_000_000,
+                end: i64::MAX,
+            }),
+        );
+        assert_eq!(
+            read_filter_to_sql(&request).unwrap(),
+            "SELECT * FROM \"cpu\" WHERE time >= '2021-12-16T15:06:22Z' \
+             AND \"host\" = 'server01' AND \"usage_idle\" IS NOT NULL ORDER BY time"
+        );
+    }
+
+    #[test]
+    fn empty_and_negated_tag_values() {
+        let request = request(
+            vec![
+                tag_comparison(TAG_KEY_MEASUREMENT, Comparison::Equal, string("cpu")),
+                tag_comparison(b"region", Comparison::Equal, string("")),
+                tag_comparison(b"host", Comparison::NotRegex, string("^a.*")),
+                tag_comparison(b"dc", Comparison::StartsWith, string("us")),
+            ],
+            None,
+        );
+        assert_eq!(
+            read_filter_to_sql(&request).unwrap(),
+            "SELECT * FROM \"cpu\" WHERE \"region\" IS NULL \
+             AND (\"host\" IS NULL OR \"host\" !~ '^a.*') AND \"dc\" LIKE 'us%' ORDER BY time"
+        );
+    }
+
+    #[test]
+    fn requires_a_single_measurement() {
+        let host = tag_comparison(b"host", Comparison::Equal, string("a"));
+        assert!(read_filter_to_sql(&request(vec![host.clone()], None)).is_err());
+
+        let two = request(
+            vec![
+                tag_comparison(TAG_KEY_MEASUREMENT, Comparison::Equal, string("cpu")),
+                tag_comparison(TAG_KEY_MEASUREMENT, Comparison::Equal, string("mem")),
+                host,
+            ],
+            None,
+        );
+        assert!(read_filter_to_sql(&two).is_err());
+    }
+
+    #[test]
+    fn starts_with_escapes_wildcards() {
+        assert_eq!(starts_with("\"dc\"", "us"), "\"dc\" LIKE 'us%'");
+        assert_eq!(
+            starts_with("\"dc\"", "us_west%"),
+            "\"dc\" LIKE 'us\\_west\\%%' ESCAPE '\\'"
+        );
+        assert_eq!(
+            starts_with("\"path\"", r"C:\tmp"),
+            r#""path" LIKE 'C:\\tmp%' ESCAPE '\'"#
+        );
+    }
+}
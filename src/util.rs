@@ -7,6 +7,49 @@ use crate::error::StringifyError;
 
 const MAX_OPERATION_WAIT_SECS: u64 = 10;
 
+/// The number of times `Connector::reconnect` tries to connect
+const RECONNECT_ATTEMPTS: u32 = 6;
+
+/// Connects to an IOx server on demand, so that subcommands which do
+/// not need a server can run without one
+#[derive(Debug, Clone)]
+pub struct Connector {
+    host: String,
+}
+
+impl Connector {
+    pub fn new(host: impl Into<String>) -> Self {
+        Self { host: host.into() }
+    }
+
+    /// Establish a new connection to the server
+    pub async fn connect(&self) -> Result<Connection> {
+        println!("Connecting to {}", self.host);
+        influxdb_iox_client::connection::Builder::default()
+            .build(&self.host)
+            .await
+            .context(&format!("Can not connect to {}", self.host))
+    }
+
+    /// Establish a new connection to a server that may be restarting,
+    /// for example after a query crashed it, retrying with exponential
+    /// backoff
+    pub async fn reconnect(&self) -> Result<Connection> {
+        let mut delay = Duration::from_secs(1);
+        for _ in 1..RECONNECT_ATTEMPTS {
+            match self.connect().await {
+                Ok(connection) => return Ok(connection),
+                Err(e) => {
+                    println!("{}, retrying in {:?}", e, delay);
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+            }
+        }
+        self.connect().await
+    }
+}
+
 /// Wait for all operations listed in `jobs` to complete, with status reporting
 pub async fn wait_for_jobs(connection: Connection, jobs: Vec<IoxOperation>) -> Result<()> {
     if jobs.is_empty() {
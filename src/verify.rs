@@ -0,0 +1,439 @@
+use std::{
+    cmp::Ordering,
+    collections::hash_map::DefaultHasher,
+    fmt::{Display, Formatter},
+    hash::{Hash, Hasher},
+};
+
+use arrow::{array::Array, datatypes::DataType, util::display::array_value_to_string};
+use generated_types::influxdata::platform::storage::read_response::{frame::Data, Frame};
+
+use crate::{
+    error::StringifyError,
+    predicate::tag_name,
+    query::{Query, QueryOutput},
+    sql::{has_order_by, order_by_keys},
+};
+
+pub type Result<T, E = String> = std::result::Result<T, E>;
+
+/// The maximum number of differing rows to report per query
+const MAX_REPORTED_DIFFERENCES: usize = 10;
+
+/// A single value in a `Table`
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum Cell {
+    Null,
+    Float(f64),
+    Other(String),
+}
+
+impl Display for Cell {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Cell::Null => write!(f, "NULL"),
+            Cell::Float(v) => write!(f, "{}", v),
+            Cell::Other(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl Cell {
+    /// Are these cells equal, allowing floats to differ by `tolerance`
+    /// (relative to their magnitude)?
+    fn approx_eq(&self, other: &Self, tolerance: f64) -> bool {
+        match (self, other) {
+            (Cell::Float(a), Cell::Float(b)) => {
+                (a.is_nan() && b.is_nan())
+                    || a == b
+                    || (a - b).abs() <= tolerance * a.abs().max(b.abs()).max(1.0)
+            }
+            _ => self == other,
+        }
+    }
+}
+
+/// The output of a query, normalized into rows of `Cell`s so that
+/// outputs of different runs can be compared.
+///
+/// Storage RPC frames are flattened into one row per point with
+/// columns `series`, `time` and `value`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Table {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Cell>>,
+}
+
+impl Table {
+    pub fn try_new(output: &QueryOutput) -> Result<Self> {
+        match output {
+            QueryOutput::Sql(batches) => {
+                let columns = batches
+                    .first()
+                    .map(|batch| {
+                        batch
+                            .schema()
+                            .fields()
+                            .iter()
+                            .map(|field| field.name().to_string())
+                            .collect()
+                    })
+                    .unwrap_or_default();
+
+                let mut rows = vec![];
+                for batch in batches {
+                    for row in 0..batch.num_rows() {
+                        let cells = batch
+                            .columns()
+                            .iter()
+                            .map(|column| {
+                                if column.is_null(row) {
+                                    return Ok(Cell::Null);
+                                }
+                                let value = array_value_to_string(column, row)
+                                    .context("Formatting value")?;
+                                Ok(match column.data_type() {
+                                    DataType::Float16 | DataType::Float32 | DataType::Float64 => {
+                                        value.parse().map(Cell::Float).unwrap_or(Cell::Other(value))
+                                    }
+                                    _ => Cell::Other(value),
+                                })
+                            })
+                            .collect::<Result<Vec<_>>>()?;
+                        rows.push(cells);
+                    }
+                }
+                Ok(Self { columns, rows })
+            }
+            QueryOutput::StorageRpc(frames) => Ok(Self::from_frames(frames)),
+        }
+    }
+
+    fn from_frames(frames: &[Frame]) -> Self {
+        let columns = vec![
+            "series".to_string(),
+            "time".to_string(),
+            "value".to_string(),
+        ];
+        let mut rows = vec![];
+        let mut series = String::new();
+
+        let mut push = |series: &str, timestamps: &[i64], values: Vec<Cell>| {
+            for (timestamp, value) in timestamps.iter().zip(values) {
+                rows.push(vec![
+                    Cell::Other(series.to_string()),
+                    Cell::Other(timestamp.to_string()),
+                    value,
+                ]);
+            }
+        };
+
+        for frame in frames {
+            match &frame.data {
+                Some(Data::Group(group)) => {
+                    series = format!(
+                        "group({})",
+                        group
+                            .partition_key_vals
+                            .iter()
+                            .map(|v| String::from_utf8_lossy(v).to_string())
+                            .collect::<Vec<_>>()
+                            .join(",")
+                    );
+                }
+                Some(Data::Series(s)) => {
+                    series = s
+                        .tags
+                        .iter()
+                        .map(|tag| {
+                            format!(
+                                "{}={}",
+                                tag_name(&tag.key),
+                                String::from_utf8_lossy(&tag.value)
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                        .join(",");
+                }
+                Some(Data::FloatPoints(p)) => push(
+                    &series,
+                    &p.timestamps,
+                    p.values.iter().map(|v| Cell::Float(*v)).collect(),
+                ),
+                Some(Data::IntegerPoints(p)) => push(
+                    &series,
+                    &p.timestamps,
+                    p.values
+                        .iter()
+                        .map(|v| Cell::Other(v.to_string()))
+                        .collect(),
+                ),
+                Some(Data::UnsignedPoints(p)) => push(
+                    &series,
+                    &p.timestamps,
+                    p.values
+                        .iter()
+                        .map(|v| Cell::Other(v.to_string()))
+                        .collect(),
+                ),
+                Some(Data::BooleanPoints(p)) => push(
+                    &series,
+                    &p.timestamps,
+                    p.values
+                        .iter()
+                        .map(|v| Cell::Other(v.to_string()))
+                        .collect(),
+                ),
+                Some(Data::StringPoints(p)) => push(
+                    &series,
+                    &p.timestamps,
+                    p.values.iter().map(|v| Cell::Other(v.clone())).collect(),
+                ),
+                None => {}
+            }
+        }
+
+        Self { columns, rows }
+    }
+
+    /// Sort the rows of this table, for comparisons where row order
+    /// does not matter
+    pub fn sort(&mut self) {
+        sort_rows(&mut self.rows);
+    }
+
+    /// Put the rows of this table, returned by a query whose rows are in
+    /// `order`, into a canonical order, so that tables differ only if
+    /// the order of their rows differs significantly
+    fn normalize(&mut self, order: &RowOrder) {
+        match order {
+            RowOrder::Ordered => {}
+            RowOrder::Unordered => self.sort(),
+            RowOrder::SortedBy(keys) => match self.key_columns(keys) {
+                Some(keys) => self.sort_ties(&keys),
+                // sorted by expressions that are not in the output
+                None => self.sort(),
+            },
+        }
+    }
+
+    /// Return the indices of the columns named (or numbered, from 1) by
+    /// `keys`, if they are all in this table
+    fn key_columns(&self, keys: &[String]) -> Option<Vec<usize>> {
+        keys.iter()
+            .map(|key| match key.parse::<usize>() {
+                Ok(position) if position >= 1 && position <= self.columns.len() => {
+                    Some(position - 1)
+                }
+                _ => self
+                    .columns
+                    .iter()
+                    .position(|column| column.eq_ignore_ascii_case(key)),
+            })
+            .collect()
+    }
+
+    /// Sort each run of rows with the same values in the `keys` columns,
+    /// as the database may return rows that tie in any order
+    fn sort_ties(&mut self, keys: &[usize]) {
+        let mut start = 0;
+        while start < self.rows.len() {
+            let first = &self.rows[start];
+            let run = self.rows[start..]
+                .iter()
+                .take_while(|row| keys.iter().all(|&k| row.get(k) == first.get(k)))
+                .count();
+            sort_rows(&mut self.rows[start..start + run]);
+            start += run;
+        }
+    }
+
+    /// Return a hash of the contents of this table, whose rows are in
+    /// `order`. Empty tables have the same hash whatever their columns
+    pub fn content_hash(&self, order: &RowOrder) -> u64 {
+        let mut table = self.clone();
+        table.normalize(order);
+
+        let mut hasher = DefaultHasher::new();
+        if !table.rows.is_empty() {
+            table.columns.hash(&mut hasher);
+        }
+        for row in &table.rows {
+            for cell in row {
+                cell.to_string().hash(&mut hasher);
+            }
+        }
+        hasher.finish()
+    }
+}
+
+fn sort_rows(rows: &mut [Vec<Cell>]) {
+    rows.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+}
+
+/// How significant the order of the rows returned by a query is
+#[derive(Debug, Clone, PartialEq)]
+pub enum RowOrder {
+    /// The order of the rows does not matter
+    Unordered,
+    /// The rows are in a significant order
+    Ordered,
+    /// The rows are sorted by these columns (names, or positions from
+    /// 1), so rows with equal values in them may be in any order
+    SortedBy(Vec<String>),
+}
+
+/// Return how the rows returned by `query` are ordered. SQL results are
+/// only ordered if the query requests it
+pub fn row_order(query: &Query) -> RowOrder {
+    match query {
+        Query::Sql(sql) if has_order_by(sql) => RowOrder::SortedBy(order_by_keys(sql)),
+        Query::Sql(_) => RowOrder::Unordered,
+        Query::StorageRpc(_) => RowOrder::Ordered,
+    }
+}
+
+/// Compare the `actual` output of a query against the `expected` output,
+/// returning a description of each difference found (empty if they
+/// match). Rows are compared allowing for the insignificant reordering
+/// that `order` permits. Floats may differ by the relative `tolerance`.
+/// Two empty outputs match, whatever their columns.
+pub fn compare(expected: &Table, actual: &Table, order: &RowOrder, tolerance: f64) -> Vec<String> {
+    if expected.rows.is_empty() && actual.rows.is_empty() {
+        return vec![];
+    }
+    if expected.columns != actual.columns {
+        return vec![format!(
+            "columns differ: expected [{}], got [{}]",
+            expected.columns.join(", "),
+            actual.columns.join(", ")
+        )];
+    }
+
+    let (mut expected, mut actual) = (expected.clone(), actual.clone());
+    expected.normalize(order);
+    actual.normalize(order);
+
+    let mut differences = vec![];
+    if expected.rows.len() != actual.rows.len() {
+        differences.push(format!(
+            "expected {} rows, got {}",
+            expected.rows.len(),
+            actual.rows.len()
+        ));
+    }
+
+    let num_rows = expected.rows.len().max(actual.rows.len());
+    for i in 0..num_rows {
+        let (e, a) = (expected.rows.get(i), actual.rows.get(i));
+        let matches = match (e, a) {
+            (Some(e), Some(a)) => {
+                e.len() == a.len() && e.iter().zip(a).all(|(e, a)| e.approx_eq(a, tolerance))
+            }
+            _ => false,
+        };
+        if matches {
+            continue;
+        }
+
+        if differences.len() >= MAX_REPORTED_DIFFERENCES {
+            differences.push("...".to_string());
+            break;
+        }
+        differences.push(format!(
+            "row {}:\n    - {}\n    + {}",
+            i,
+            format_row(e),
+            format_row(a)
+        ));
+    }
+    differences
+}
+
+fn format_row(row: Option<&Vec<Cell>>) -> String {
+    match row {
+        Some(row) => {
+            let cells: Vec<_> = row.iter().map(|c| c.to_string()).collect();
+            format!("[{}]", cells.join(", "))
+        }
+        None => "<missing>".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(columns: &[&str], rows: &[&[&str]]) -> Table {
+        Table {
+            columns: columns.iter().map(|c| c.to_string()).collect(),
+            rows: rows
+                .iter()
+                .map(|row| row.iter().map(|v| Cell::Other(v.to_string())).collect())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn empty_outputs_match_whatever_their_columns() {
+        let no_batches = table(&[], &[]);
+        let empty_batch = table(&["host", "usage"], &[]);
+        assert!(compare(&no_batches, &empty_batch, &RowOrder::Ordered, 0.0).is_empty());
+        assert_eq!(
+            no_batches.content_hash(&RowOrder::Ordered),
+            empty_batch.content_hash(&RowOrder::Ordered)
+        );
+        assert!(!compare(
+            &no_batches,
+            &table(&["host"], &[&["a"]]),
+            &RowOrder::Ordered,
+            0.0
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn ties_may_be_in_any_order() {
+        let expected = table(&["time", "host"], &[&["1", "a"], &["1", "b"], &["2", "c"]]);
+        let ties_swapped = table(&["time", "host"], &[&["1", "b"], &["1", "a"], &["2", "c"]]);
+        let misordered = table(&["time", "host"], &[&["2", "c"], &["1", "a"], &["1", "b"]]);
+
+        let by_time = RowOrder::SortedBy(vec!["TIME".to_string()]);
+        assert!(compare(&expected, &ties_swapped, &by_time, 0.0).is_empty());
+        assert!(!compare(&expected, &misordered, &by_time, 0.0).is_empty());
+        assert_eq!(
+            expected.content_hash(&by_time),
+            ties_swapped.content_hash(&by_time)
+        );
+
+        let by_position = RowOrder::SortedBy(vec!["1".to_string()]);
+        assert!(compare(&expected, &ties_swapped, &by_position, 0.0).is_empty());
+
+        assert!(!compare(&expected, &ties_swapped, &RowOrder::Ordered, 0.0).is_empty());
+        assert!(compare(&expected, &misordered, &RowOrder::Unordered, 0.0).is_empty());
+    }
+
+    #[test]
+    fn sorted_by_unknown_expressions_is_unordered() {
+        let expected = table(&["host"], &[&["a"], &["b"]]);
+        let actual = table(&["host"], &[&["b"], &["a"]]);
+        let order = RowOrder::SortedBy(vec!["lower ( host )".to_string()]);
+        assert!(compare(&expected, &actual, &order, 0.0).is_empty());
+    }
+
+    #[test]
+    fn order_of_queries() {
+        assert_eq!(
+            row_order(&Query::Sql(
+                "SELECT * FROM t ORDER BY time DESC".to_string()
+            )),
+            RowOrder::SortedBy(vec!["time".to_string()])
+        );
+        assert_eq!(
+            row_order(&Query::Sql(
+                "SELECT * FROM (SELECT * FROM t ORDER BY time) AS s".to_string()
+            )),
+            RowOrder::Unordered
+        );
+    }
+}